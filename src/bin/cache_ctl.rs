@@ -0,0 +1,345 @@
+extern crate clap;
+extern crate local_cache_proxy;
+extern crate pretty_env_logger;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+#[macro_use]
+extern crate log;
+
+use local_cache_proxy::config::AppConfig;
+use local_cache_proxy::net::ac_freshness::orphaned_validators;
+use local_cache_proxy::net::digest::verify_file;
+use local_cache_proxy::net::pins::{pinned_keys, PinSets};
+use local_cache_proxy::net::process_action_cache::{
+    gate_path, gated_digests, live_references, process_action_caches, read_action_result,
+};
+use local_cache_proxy::net::repo_entries;
+use local_cache_proxy::net::Downloader;
+
+fn entry_type_and_digest(path: &Path) -> (String, String) {
+    let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+    let mut parts = file_name.splitn(2, "__");
+    let tpe = parts.next().unwrap_or("").to_string();
+    let digest = parts.next().unwrap_or("").to_string();
+    (tpe, digest)
+}
+
+fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map(|e| e.len()).unwrap_or(0)
+}
+
+fn folder_size(path: &Path) -> u64 {
+    match fs::read_dir(path) {
+        Ok(paths) => paths
+            .filter_map(|e| e.ok())
+            .map(|e| {
+                let path = e.path();
+                if path.is_dir() {
+                    folder_size(&path)
+                } else {
+                    file_size(&path)
+                }
+            })
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+fn stats(config: &AppConfig) {
+    let pins = match PinSets::load(config) {
+        Ok(pin_sets) => pinned_keys(config, &pin_sets.digests()),
+        Err(e) => {
+            warn!("Failed to load pin sets: {}", e);
+            HashSet::new()
+        }
+    };
+    let mut by_type: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    // entries, bytes, bytes pinned
+    let mut by_partition: BTreeMap<String, (u64, u64, u64)> = BTreeMap::new();
+    for (partition, path) in config.cached_entries() {
+        let (tpe, _) = entry_type_and_digest(&path);
+        let size = file_size(&path);
+        {
+            let type_stats = by_type.entry(tpe).or_insert((0, 0));
+            type_stats.0 += 1;
+            type_stats.1 += size;
+        }
+        let partition_stats = by_partition.entry(partition).or_insert((0, 0, 0));
+        partition_stats.0 += 1;
+        partition_stats.1 += size;
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        if pins.contains(&file_name) {
+            partition_stats.2 += size;
+        }
+    }
+
+    let budgets: HashMap<String, u64> = config.partitions().into_iter().collect();
+
+    println!("{:<12} {:>10} {:>16}", "type", "entries", "bytes");
+    for (tpe, &(count, bytes)) in by_type.iter() {
+        println!("{:<12} {:>10} {:>16}", tpe, count, bytes);
+    }
+    println!(
+        "{:<12} {:>10} {:>16}",
+        "gates",
+        gated_digests(config).len(),
+        "-"
+    );
+    println!(
+        "{:<12} {:>10} {:>16}",
+        "tmp",
+        "-",
+        folder_size(&config.tmp_folder())
+    );
+    println!();
+    // Pinned bytes are included in bytes, but not held against the budget
+    println!(
+        "{:<24} {:>10} {:>16} {:>16} {:>16}",
+        "partition", "entries", "bytes", "pinned", "budget"
+    );
+    for (partition, &(count, bytes, pinned)) in by_partition.iter() {
+        println!(
+            "{:<24} {:>10} {:>16} {:>16} {:>16}",
+            partition,
+            count,
+            bytes,
+            pinned,
+            budgets.get(partition).cloned().unwrap_or(0)
+        );
+    }
+}
+
+// Re-hash every CAS blob and decode every action cache entry, returning how many were bad.
+fn verify(config: &AppConfig, remove: bool) -> usize {
+    let mut bad = 0;
+    for (_, path) in config.cached_entries() {
+        let (tpe, digest) = entry_type_and_digest(&path);
+        let problem = if tpe == "cas" {
+            match verify_file(&path, &digest) {
+                Ok(true) => None,
+                Ok(false) => Some("content does not match its digest".to_string()),
+                Err(e) => Some(e.to_string()),
+            }
+        } else {
+            read_action_result(&path).err()
+        };
+
+        if let Some(problem) = problem {
+            bad += 1;
+            println!("{:?}: {}", path, problem);
+            if remove {
+                fs::remove_file(&path)
+                    .unwrap_or_else(|e| warn!("Failed to remove {:?}: {}", path, e));
+            }
+        }
+    }
+    println!("{} bad entries found", bad);
+    bad
+}
+
+fn remove_path(path: &Path, dry_run: bool) {
+    println!("removing {:?}", path);
+    if dry_run {
+        return;
+    }
+    let removed = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    removed.unwrap_or_else(|e| warn!("Failed to remove {:?}: {}", path, e));
+}
+
+// Drop gates no cached action cache entry refers to anymore, they get recreated on startup
+// for any that are still referenced, along with validators and repo records of evicted
+// entries and downloads left behind by a crash.
+fn gc(config: &AppConfig, dry_run: bool) {
+    for digest in gated_digests(config) {
+        if live_references(config, &digest).is_empty() {
+            remove_path(&gate_path(config, &digest), dry_run);
+        }
+    }
+
+    for path in orphaned_validators(config) {
+        remove_path(&path, dry_run);
+    }
+
+    for path in repo_entries::orphaned(config) {
+        remove_path(&path, dry_run);
+    }
+
+    if let Ok(paths) = fs::read_dir(config.tmp_folder()) {
+        for path in paths.filter_map(|e| e.ok()) {
+            remove_path(&path.path(), dry_run);
+        }
+    }
+}
+
+fn evict(config: &AppConfig, matches: &ArgMatches) -> Result<usize, String> {
+    let downloader = Downloader::new(config).map_err(|e| e.to_string())?;
+
+    if matches.is_present("all") {
+        return downloader.clear(None);
+    }
+    if let Some(repo) = matches.value_of("repo") {
+        return downloader.evict_repo(repo);
+    }
+
+    let mut removed = 0;
+    for digest in matches.values_of("digest").into_iter().flat_map(|e| e) {
+        removed += downloader.evict_digest(digest)?;
+    }
+    Ok(removed)
+}
+
+// Throw away every gate and recreate them from the action cache entries on disk.
+fn rebuild_index(config: &AppConfig) {
+    let previous = gated_digests(config);
+    for digest in previous.iter() {
+        fs::remove_file(gate_path(config, digest))
+            .unwrap_or_else(|e| warn!("Failed to remove gate for {}: {}", digest, e));
+    }
+    process_action_caches(config);
+    println!(
+        "rebuilt gates, {} before, {} now",
+        previous.len(),
+        gated_digests(config).len()
+    );
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let matches = App::new("Local Bazel Cache Maintenance")
+        .version("0.1")
+        .about("Inspects and repairs a cache folder while the proxy is stopped")
+        .author("Ian O Connell <ianoc@ianoc.net>")
+        .arg(
+            Arg::with_name("cache_folder")
+                .long("cache-folder")
+                .value_name("CACHE_FOLDER")
+                .help("location for the cache")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache_folder_size")
+                .long("cache-folder-size")
+                .value_name("CACHE_FOLDER_SIZE")
+                .help("Max size in bytes for the cache folder, should match the proxy's")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("repo_quota")
+                .long("repo-quota")
+                .value_name("REPO=BYTES")
+                .help("Repo partitions as passed to the proxy")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pin_file")
+                .long("pin-file")
+                .value_name("PIN_FILE")
+                .help("Pin file as passed to the proxy")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("stats").about("Entry counts and sizes per type and partition"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Re-hash CAS entries and decode action cache entries")
+                .arg(
+                    Arg::with_name("remove")
+                        .long("remove")
+                        .help("Remove entries that fail verification")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .about("Remove orphaned CAS gates, AC validators and leftover temp files")
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Only print what would be removed")
+                        .takes_value(false),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("evict")
+                .about("Evict digests, a repo's entries or everything")
+                .arg(
+                    Arg::with_name("repo")
+                        .long("repo")
+                        .value_name("REPO")
+                        .help("Evict this repo's partition, or without one every entry it stored")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("all")
+                        .long("all")
+                        .help("Evict every entry")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("digest")
+                        .value_name("DIGEST")
+                        .help("Digests to evict, both ac and cas entries are removed")
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rebuild-index")
+                .about("Recreate the CAS gates from the cached action cache entries"),
+        )
+        .get_matches();
+
+    let cfg = AppConfig::from_matches(&matches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+    // Nothing here serves requests, only the cache folder settings matter
+    let cfg = AppConfig {
+        idle_time_terminate: None,
+        negative_cache_size: 0,
+        memory_cache_size: 0,
+        scrub_rate: 0,
+        upload_types: Vec::new(),
+        ..cfg
+    };
+
+    let has_partitions = PathBuf::from(&cfg.cache_folder)
+        .join("partitions")
+        .is_dir();
+    if cfg.repo_quotas.is_empty() && has_partitions {
+        warn!("Cache folder has repo partitions but no --repo-quota given, only using the root");
+    }
+
+    match matches.subcommand() {
+        ("stats", _) => stats(&cfg),
+        ("verify", Some(sub)) => {
+            if verify(&cfg, sub.is_present("remove")) > 0 {
+                process::exit(1);
+            }
+        }
+        ("gc", Some(sub)) => gc(&cfg, sub.is_present("dry_run")),
+        ("evict", Some(sub)) => match evict(&cfg, sub) {
+            Ok(removed) => println!("evicted {} entries", removed),
+            Err(e) => {
+                eprintln!("Failed to evict: {}", e);
+                process::exit(1);
+            }
+        },
+        ("rebuild-index", _) => rebuild_index(&cfg),
+        _ => {
+            eprintln!("{}", matches.usage());
+            process::exit(1);
+        }
+    }
+}
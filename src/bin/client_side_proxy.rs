@@ -2,31 +2,142 @@ extern crate clap;
 extern crate futures;
 extern crate hyper;
 extern crate hyper_proxy;
+extern crate libc;
 extern crate local_cache_proxy;
-extern crate lru_disk_cache;
 extern crate pretty_env_logger;
 extern crate tokio;
 extern crate tokio_core;
 extern crate tokio_uds;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use hyper::Body;
 use hyper::Client;
 use std::env;
+use std::ffi::OsString;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 #[macro_use]
 extern crate log;
 use local_cache_proxy::unix_socket::uri::Uri as HyperlocalUri;
 
 use hyper::client::HttpConnector;
 use local_cache_proxy::config::AppConfig;
+use local_cache_proxy::net::instance::{is_healthy, running_instance, InstanceLock};
 use local_cache_proxy::net::Downloader;
 use local_cache_proxy::net::ProxyConnector;
 use local_cache_proxy::unix_socket::unix_connector::UnixConnector;
 
-fn main() {
-    pretty_env_logger::init();
+// Where the `ensure` subcommand starts, a flag's value can be `ensure` too so we ask clap
+fn subcommand_index(args: &[OsString]) -> Option<usize> {
+    (1..args.len()).find(|&i| {
+        args[i] == "ensure"
+            && app()
+                .get_matches_from_safe(&args[..i + 1])
+                .map(|e| e.subcommand_name() == Some("ensure"))
+                .unwrap_or(false)
+    })
+}
+
+// Run ourselves again without the `ensure` subcommand, detached and logging to a file.
+fn spawn_daemon(log_path: &Path) -> io::Result<Child> {
+    let all_args: Vec<OsString> = env::args_os().collect();
+    let args = match subcommand_index(&all_args) {
+        Some(index) => all_args[1..index].to_vec(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Unable to find the ensure subcommand in our arguments",
+            ))
+        }
+    };
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    // A session of its own, so it outlives the wrapper script and its terminal
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    command.spawn()
+}
+
+// Make sure a healthy proxy is serving this config, starting one if needed, then print where.
+fn ensure(cfg: &AppConfig, matches: &ArgMatches) {
+    let log_path = matches
+        .value_of("log_file")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(&cfg.cache_folder).join("proxy.log"));
+    let deadline = Instant::now()
+        + AppConfig::str_to_ms(matches.value_of("startup_timeout").unwrap_or("30000")).unwrap();
+    let bind_target = cfg.bind_target.to_string();
+    let mut child: Option<Child> = None;
+
+    loop {
+        match running_instance(cfg) {
+            Some(ref running) if running.bind_target != bind_target => {
+                eprintln!(
+                    "Cache folder {} is in use by pid {} serving {}, not {}",
+                    cfg.cache_folder, running.pid, running.bind_target, bind_target
+                );
+                process::exit(1);
+            }
+            Some(_) => {
+                if is_healthy(&cfg.bind_target) {
+                    println!("{}", bind_target);
+                    return;
+                }
+            }
+            // Either nothing is running, or the one we started hasn't got going yet
+            None => match child {
+                Some(ref mut started) => {
+                    if let Ok(Some(status)) = started.try_wait() {
+                        eprintln!(
+                            "Proxy exited during startup with {}, see {:?}",
+                            status, log_path
+                        );
+                        process::exit(1);
+                    }
+                }
+                None => {
+                    child = Some(spawn_daemon(&log_path).unwrap_or_else(|e| {
+                        eprintln!("Failed to start proxy: {}", e);
+                        process::exit(1)
+                    }));
+                }
+            },
+        }
 
-    let matches = App::new("Local Bazel Cache And Proxy")
+        if Instant::now() > deadline {
+            eprintln!(
+                "Timed out waiting for a healthy proxy on {}, see {:?}",
+                bind_target, log_path
+            );
+            process::exit(1);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("Local Bazel Cache And Proxy")
         .version("0.1")
         .about("Handles managing a local disk cache while forwarding cache misses externally")
         .author("Ian O Connell <ianoc@ianoc.net>")
@@ -36,7 +147,16 @@ fn main() {
                 .long("upstream-uri")
                 .value_name("UPSTREAM_URI")
                 .required(true)
-                .help("Upstream URI to use after any proxies, http://...")
+                .help("Upstream URI to use after any proxies, http://...[,max_download=N][,max_upload=N][,upload=false][,retries=N]. Repeat to add fallback upstreams, tried in order")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("routes_file")
+                .long("routes-file")
+                .value_name("ROUTES_FILE")
+                .help("File of `<repo glob> <upstream>...` lines sending matching repos to their own upstreams")
                 .takes_value(true),
         )
         .arg(
@@ -62,6 +182,15 @@ fn main() {
                 .help("Max size in bytes for the cache folder")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("repo_quota")
+                .long("repo-quota")
+                .value_name("REPO=BYTES")
+                .help("Give a repo its own cache partition of this many bytes, taken out of the cache folder size")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("cache_folder")
                 .long("cache-folder")
@@ -73,14 +202,14 @@ fn main() {
             Arg::with_name("maximum_upload_size")
                 .long("maximum-upload-size")
                 .value_name("MAXIMUM_UPLOAD_SIZE")
-                .help("Max size allowed for remote uploads")
+                .help("Default max size allowed for remote uploads")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("maximum_download_size")
                 .long("maximum-download-size")
                 .value_name("MAXIMUM_DOWNLOAD_SIZE")
-                .help("Max size allowed for remote downloads")
+                .help("Default max size allowed for remote downloads")
                 .takes_value(true),
         )
         .arg(
@@ -90,54 +219,211 @@ fn main() {
                 .help("MS to keep the server active when idle")
                 .takes_value(true),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("upstream_credential")
+                .long("upstream-credential")
+                .value_name("TOKEN")
+                .help("Bearer token presented to the upstream when uploading")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("upload_types")
+                .long("upload-types")
+                .value_name("TYPES")
+                .help("Comma separated cache types we may upload, e.g. cas or ac,cas")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("peer")
+                .long("peer")
+                .value_name("PEER_URI")
+                .help("LAN peer proxy to try for CAS blobs before the upstream, http://<host>:<port>")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("discover_peers")
+                .long("discover-peers")
+                .help("Find LAN peers via mDNS")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("advertise_uri")
+                .long("advertise-uri")
+                .value_name("ADVERTISE_URI")
+                .help("Uri other LAN peers can reach us on, announced via mDNS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown_grace_period")
+                .long("shutdown-grace-period")
+                .value_name("GRACE_PERIOD_IN_MS")
+                .help("MS to let in-flight requests and queued uploads finish when shutting down")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("negative_cache_ttl")
+                .long("negative-cache-ttl")
+                .value_name("TTL_IN_MS")
+                .help("MS to remember an upstream miss for, answering repeat requests with a 404 straight away, 0 to disable")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("negative_cache_size")
+                .long("negative-cache-size")
+                .value_name("ENTRIES")
+                .help("How many upstream misses to remember at most")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ac_max_age")
+                .long("ac-max-age")
+                .value_name("MAX_AGE_IN_MS")
+                .help("MS before a cached action cache entry is checked with the upstream again, by default they're kept until evicted")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ac_revalidate")
+                .long("ac-revalidate")
+                .help("Check stale action cache entries using their ETag/Last-Modified instead of fetching them again, on every request without --ac-max-age")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("eviction_policy")
+                .long("eviction-policy")
+                .value_name("POLICY")
+                .help("How to pick cache entries to evict: lru, gdsf, arc or lru-admission. Defaults to lru")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eviction_stats")
+                .long("eviction-stats")
+                .help("Also simulate the other eviction policies against the same traffic, comparing their hit ratios on /admin/eviction")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("pin_file")
+                .long("pin-file")
+                .value_name("PIN_FILE")
+                .help("File of `<set> <digest>` lines pinning AC/CAS entries so they're never evicted, an AC digest also pins the blobs it references")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_pinned_size")
+                .long("max-pinned-size")
+                .value_name("BYTES")
+                .help("Most bytes of pinned entries to hold, on top of the cache folder size. Pin sets that would go over it are refused, defaults to a quarter of the cache folder size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scrub_rate")
+                .long("scrub-rate")
+                .value_name("BYTES_PER_SECOND")
+                .help("Bytes a second of cache entries to check for corruption in the background, quarantining any that are, 0 to disable. Defaults to 8MB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory_cache_size")
+                .long("memory-cache-size")
+                .value_name("BYTES")
+                .help("Bytes of small cache entries to also keep in memory, 0 to disable. Defaults to 64MB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memory_cache_max_entry_size")
+                .long("memory-cache-max-entry-size")
+                .value_name("BYTES")
+                .help("Largest entry kept in memory. Defaults to 64KB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tee_downloads")
+                .long("tee-downloads")
+                .help("Stream CAS blobs missing locally to Bazel while they download from the upstream, caching them once complete")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("denylist_file")
+                .long("denylist-file")
+                .value_name("DENYLIST_FILE")
+                .help("File of AC/CAS digests, one per line, to treat as missing, reloaded when it changes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denylist_uri")
+                .long("denylist-uri")
+                .value_name("DENYLIST_URI")
+                .help("Server proxy to refresh the denylist from, e.g. http://<host>:<port>/denylist")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denylist_refresh_interval")
+                .long("denylist-refresh-interval")
+                .value_name("INTERVAL_IN_MS")
+                .help("MS between checks for denylist changes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .value_name("TOKEN")
+                .help("Bearer token required for the /admin API, which is disabled without one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("access_log")
+                .long("access-log")
+                .value_name("ACCESS_LOG")
+                .help("File to append a JSON line per request to, or - for stdout")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("ensure")
+                .about("Start a proxy in the background unless one is already serving this config, printing its bind target once healthy")
+                .arg(
+                    Arg::with_name("log_file")
+                        .long("log-file")
+                        .value_name("LOG_FILE")
+                        .help("Where a started proxy logs to, defaults to proxy.log in the cache folder")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("startup_timeout")
+                        .long("startup-timeout")
+                        .value_name("TIMEOUT_IN_MS")
+                        .help("MS to wait for the proxy to become healthy")
+                        .takes_value(true),
+                ),
+        )
+}
+
+fn main() {
+    pretty_env_logger::init();
+
+    let matches = app().get_matches();
 
     let proxy: Option<&str> = matches.value_of("proxy");
 
-    let cfg = AppConfig {
-        proxy: proxy.map(|e| e.to_string()),
-        upstream: matches
-            .value_of("upstream")
-            .expect("Should never fail, expecting to see primary upstream arg")
-            .parse()
-            .expect("Failed to parse URI for primary upstream"),
-        bind_target: matches
-            .value_of("bind_target")
-            .unwrap_or("http://localhost:10487")
-            .parse()
-            .unwrap(),
-        cache_folder_size: matches
-            .value_of("cache_folder_size")
-            .unwrap_or("32212254720")
-            .parse()
-            .unwrap(),
-        cache_folder: matches
-            .value_of("cache_folder")
-            .unwrap_or(&format!(
-                "{}/bazel_download_cache",
-                env::home_dir().unwrap().display()
-            ))
-            .to_string(),
-        maximum_upload_size: matches
-            .value_of("maximum_upload_size")
-            .unwrap_or("10485760")
-            .parse()
-            .unwrap(),
-        maximum_download_size: matches
-            .value_of("maximum_download_size")
-            .unwrap_or("10485760")
-            .parse()
-            .unwrap(),
-        idle_time_terminate: Some(
-            AppConfig::str_to_ms(
-                matches.value_of("idle_time_terminate").unwrap_or("600000"), // 10 minute default
-            ).unwrap(),
-        ),
-    };
+    let cfg = AppConfig::from_matches(&matches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
+    if let Some(sub) = matches.subcommand_matches("ensure") {
+        ensure(&cfg, sub);
+        return;
+    }
 
     info!("setting up bazel cache folder in : {:?}", cfg.cache_folder);
     info!("Cache folder size in : {:?}", cfg.cache_folder_size);
 
+    // Two proxies sharing a cache folder would corrupt each other's LRU state
+    let _instance_lock = InstanceLock::acquire(&cfg).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
     match proxy {
         Some(e) => {
             let proxy_uri = HyperlocalUri::new(e, "/").into();
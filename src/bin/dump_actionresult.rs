@@ -1,35 +1,365 @@
 extern crate clap;
 extern crate futures;
 extern crate hyper;
-extern crate hyper_proxy;
 extern crate local_cache_proxy;
-extern crate lru_disk_cache;
 extern crate pretty_env_logger;
 extern crate protobuf;
-extern crate tokio;
-extern crate tokio_core;
-extern crate tokio_uds;
+#[macro_use]
+extern crate serde_json;
 
 use clap::{App, Arg};
 #[macro_use]
 extern crate log;
 
-// use std::{thread, time};
-
-use protobuf::error::ProtobufError;
-use protobuf::{CodedInputStream, Message}; //, ProtobufResult, RepeatedField};
-use std::fs::File;
-use std::io::BufReader;
-// use std::io::{self, stdin, BufRead, BufReader};
+use futures::{Future, Stream};
+use hyper::Client;
+use hyper::StatusCode;
+use hyper::Uri as HyperUri;
+use local_cache_proxy::action_result::action_result::Directory;
 use local_cache_proxy::action_result::ActionResult;
-use std::path::Path;
+use local_cache_proxy::net::digest::sha256_bytes;
+use local_cache_proxy::net::process_action_cache::read_action_result;
+use protobuf::{parse_from_bytes, CodedInputStream};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+// Where to find the CAS blobs holding output directory trees
+enum BlobSource {
+    CacheFolder(PathBuf),
+    Upstream(HyperUri),
+}
+
+#[derive(Debug)]
+struct Output {
+    path: String,
+    kind: &'static str,
+    digest: String,
+    size: i64,
+    executable: bool,
+}
+
+impl Output {
+    fn to_json(&self) -> Value {
+        json!({
+            "path": self.path,
+            "kind": self.kind,
+            "digest": self.digest,
+            "size": self.size,
+            "executable": self.executable,
+        })
+    }
+}
+
+fn read_cached_blob(cache_folder: &Path, digest: &str) -> Option<Vec<u8>> {
+    let file_name = format!("cas__{}", digest);
+    let mut folders = vec![cache_folder.to_path_buf()];
+    if let Ok(partitions) = fs::read_dir(cache_folder.join("partitions")) {
+        folders.extend(partitions.filter_map(|e| e.ok()).map(|e| e.path()));
+    }
+
+    folders
+        .iter()
+        .map(|folder| folder.join(&file_name))
+        .find(|path| path.is_file())
+        .and_then(|path| {
+            let mut data = Vec::new();
+            fs::File::open(path)
+                .and_then(|mut f| f.read_to_end(&mut data))
+                .ok()
+                .map(|_| data)
+        })
+}
+
+fn fetch_upstream_blobs(upstream: &HyperUri, digests: Vec<String>) -> HashMap<String, Vec<u8>> {
+    let client = Client::new();
+    let upstream_str = format!("{}", upstream).trim_right_matches('/').to_string();
+    let blobs = Arc::new(Mutex::new(HashMap::new()));
+    let blobs_copy = Arc::clone(&blobs);
+
+    let fetches: Vec<_> = digests
+        .into_iter()
+        .map(|digest| {
+            let uri: HyperUri = format!("{}/cas/{}", upstream_str, digest)
+                .parse()
+                .expect("Failed to build upstream uri");
+            let blobs = Arc::clone(&blobs_copy);
+            client
+                .get(uri.clone())
+                .and_then(move |res| {
+                    let status = res.status();
+                    res.into_body().concat2().map(move |body| {
+                        if status == StatusCode::OK {
+                            blobs.lock().unwrap().insert(digest, body.to_vec());
+                        } else {
+                            warn!("Upstream returned {} for {:?}", status, uri);
+                        }
+                    })
+                })
+                .or_else(|e| {
+                    warn!("Failed fetching tree from upstream: {:?}", e);
+                    Ok(())
+                })
+        })
+        .collect();
+
+    hyper::rt::run(futures::future::join_all(fetches).map(|_| ()));
+
+    let blobs = blobs.lock().unwrap();
+    blobs.clone()
+}
+
+fn load_blobs(source: &BlobSource, digests: Vec<String>) -> HashMap<String, Vec<u8>> {
+    match source {
+        &BlobSource::CacheFolder(ref cache_folder) => digests
+            .into_iter()
+            .filter_map(|digest| {
+                read_cached_blob(cache_folder, &digest).map(|data| (digest, data))
+            })
+            .collect(),
+        &BlobSource::Upstream(ref upstream) => fetch_upstream_blobs(upstream, digests),
+    }
+}
+
+// Our generated protos don't include `Tree`, it's just a root `Directory` (field 1) and its
+// `children` (field 2). Children are referenced by the digest of their serialized form.
+fn parse_tree(data: &[u8]) -> Result<(Directory, HashMap<String, Directory>), String> {
+    let mut is = CodedInputStream::from_bytes(data);
+    let mut root = Directory::new();
+    let mut children = HashMap::new();
+    while !is.eof().map_err(|e| e.to_string())? {
+        let (field_number, wire_type) = is.read_tag_unpack().map_err(|e| e.to_string())?;
+        match field_number {
+            1 | 2 => {
+                let bytes = is.read_bytes().map_err(|e| e.to_string())?;
+                let directory: Directory = parse_from_bytes(&bytes).map_err(|e| e.to_string())?;
+                if field_number == 1 {
+                    root = directory;
+                } else {
+                    children.insert(sha256_bytes(&bytes), directory);
+                }
+            }
+            _ => is.skip_field(wire_type).map_err(|e| e.to_string())?,
+        }
+    }
+    Ok((root, children))
+}
+
+fn expand_directory(
+    prefix: &str,
+    directory: &Directory,
+    children: &HashMap<String, Directory>,
+    outputs: &mut Vec<Output>,
+) {
+    for file in directory.get_files() {
+        outputs.push(Output {
+            path: format!("{}/{}", prefix, file.get_name()),
+            kind: "file",
+            digest: file.get_digest().get_hash().to_string(),
+            size: file.get_digest().get_size_bytes(),
+            executable: file.get_is_executable(),
+        });
+    }
+    for sub_directory in directory.get_directories() {
+        let path = format!("{}/{}", prefix, sub_directory.get_name());
+        match children.get(sub_directory.get_digest().get_hash()) {
+            Some(child) => expand_directory(&path, child, children, outputs),
+            None => warn!("Tree is missing directory {}", path),
+        }
+    }
+}
+
+// Flatten an action result into its outputs, expanding directories when we can find their trees.
+fn outputs(action_result: &ActionResult, source: Option<&BlobSource>) -> Vec<Output> {
+    let mut outputs: Vec<Output> = action_result
+        .get_output_files()
+        .iter()
+        .map(|f| Output {
+            path: f.get_path().to_string(),
+            kind: "file",
+            digest: f.get_digest().get_hash().to_string(),
+            size: f.get_digest().get_size_bytes(),
+            executable: f.get_is_executable(),
+        })
+        .collect();
+
+    let trees = match source {
+        Some(source) => load_blobs(
+            source,
+            action_result
+                .get_output_directories()
+                .iter()
+                .map(|d| d.get_tree_digest().get_hash().to_string())
+                .collect(),
+        ),
+        None => HashMap::new(),
+    };
+
+    for d in action_result.get_output_directories() {
+        let tree_digest = d.get_tree_digest();
+        let tree = trees.get(tree_digest.get_hash()).map(|data| parse_tree(data));
+        match tree {
+            Some(Ok((root, children))) => {
+                expand_directory(d.get_path(), &root, &children, &mut outputs)
+            }
+            other => {
+                if let Some(Err(e)) = other {
+                    warn!("Unable to decode tree for {}: {}", d.get_path(), e);
+                } else if source.is_some() {
+                    warn!("Tree for {} not found", d.get_path());
+                }
+                outputs.push(Output {
+                    path: d.get_path().to_string(),
+                    kind: "directory",
+                    digest: tree_digest.get_hash().to_string(),
+                    size: tree_digest.get_size_bytes(),
+                    executable: false,
+                });
+            }
+        }
+    }
+    outputs.sort_by(|a, b| a.path.cmp(&b.path));
+    outputs
+}
+
+fn print_table(outputs: &[Output]) {
+    println!(
+        "{:<4} {:<10} {:>12} {:<64} {}",
+        "exec", "kind", "size", "digest", "path"
+    );
+    for o in outputs {
+        println!(
+            "{:<4} {:<10} {:>12} {:<64} {}",
+            if o.executable { "x" } else { "-" },
+            o.kind,
+            o.size,
+            o.digest,
+            o.path
+        );
+    }
+}
+
+fn dump(action_result: &ActionResult, source: Option<&BlobSource>, json_format: bool) {
+    let outputs = outputs(action_result, source);
+    let stdout_digest = action_result.get_stdout_digest().get_hash();
+    let stderr_digest = action_result.get_stderr_digest().get_hash();
+    if json_format {
+        let outputs: Vec<Value> = outputs.iter().map(|o| o.to_json()).collect();
+        println!(
+            "{}",
+            json!({
+                "exit_code": action_result.get_exit_code(),
+                "stdout_digest": stdout_digest,
+                "stderr_digest": stderr_digest,
+                "outputs": outputs,
+            })
+        );
+    } else {
+        println!("exit code: {}", action_result.get_exit_code());
+        println!("stdout: {}", stdout_digest);
+        println!("stderr: {}", stderr_digest);
+        print_table(&outputs);
+    }
+}
+
+// Compare two action results output by output, returning whether they differ.
+fn diff(
+    left: &ActionResult,
+    right: &ActionResult,
+    source: Option<&BlobSource>,
+    json_format: bool,
+) -> bool {
+    let mut by_path: BTreeMap<String, (Option<Output>, Option<Output>)> = BTreeMap::new();
+    for o in outputs(left, source) {
+        let path = o.path.clone();
+        by_path.entry(path).or_insert((None, None)).0 = Some(o);
+    }
+    for o in outputs(right, source) {
+        let path = o.path.clone();
+        by_path.entry(path).or_insert((None, None)).1 = Some(o);
+    }
+
+    let mut differences = Vec::new();
+    for (path, (l, r)) in by_path {
+        let change = match (&l, &r) {
+            (&Some(_), &None) => "only_left",
+            (&None, &Some(_)) => "only_right",
+            (&Some(ref l), &Some(ref r)) if l.digest != r.digest => "content",
+            (&Some(ref l), &Some(ref r)) if l.executable != r.executable => "executable",
+            (&Some(ref l), &Some(ref r)) if l.kind != r.kind => "kind",
+            _ => continue,
+        };
+        differences.push((path, change, l, r));
+    }
+    let exit_codes_differ = left.get_exit_code() != right.get_exit_code();
+
+    if json_format {
+        let differences: Vec<Value> = differences
+            .iter()
+            .map(|&(ref path, change, ref l, ref r)| {
+                json!({
+                    "path": path,
+                    "change": change,
+                    "left": l.as_ref().map(|o| o.to_json()),
+                    "right": r.as_ref().map(|o| o.to_json()),
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "exit_code": [left.get_exit_code(), right.get_exit_code()],
+                "differences": differences,
+            })
+        );
+    } else {
+        if exit_codes_differ {
+            println!(
+                "exit code: {} != {}",
+                left.get_exit_code(),
+                right.get_exit_code()
+            );
+        }
+        for &(ref path, change, ref l, ref r) in differences.iter() {
+            let describe = |o: &Option<Output>| match o {
+                &Some(ref o) => format!(
+                    "{} {}{}",
+                    o.digest,
+                    o.size,
+                    if o.executable { " x" } else { "" }
+                ),
+                &None => "missing".to_string(),
+            };
+            println!("{:<10} {}", change, path);
+            println!("    < {}", describe(l));
+            println!("    > {}", describe(r));
+        }
+        println!("{} outputs differ", differences.len());
+    }
+    exit_codes_differ || !differences.is_empty()
+}
+
+fn load_action_result(path: &str) -> ActionResult {
+    info!("Reading file {:?}", path);
+    match read_action_result(Path::new(path)) {
+        Ok(action_result) => action_result,
+        Err(e) => {
+            eprintln!("Unable to read action result {:?}: {}", path, e);
+            process::exit(2);
+        }
+    }
+}
 
 fn main() {
     pretty_env_logger::init();
 
     let matches = App::new("Read a local protobuf file and show debug info.")
         .version("0.1")
-        .about("Testing using hte protobuf apis")
+        .about("Show the outputs recorded in an action cache entry")
         .author("Ian O Connell <ianoc@ianoc.net>")
         .arg(
             Arg::with_name("file")
@@ -40,39 +370,57 @@ fn main() {
                 .help("Use the file path to read")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("diff")
+                .long("diff")
+                .value_name("OTHER_FILE_PATH")
+                .help("Compare against another action cache entry, exits 1 if they differ")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .possible_values(&["table", "json"])
+                .default_value("table")
+                .help("Output format")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache_folder")
+                .long("cache-folder")
+                .value_name("CACHE_FOLDER")
+                .help("Expand output directories using trees from this cache folder")
+                .conflicts_with("upstream")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("upstream")
+                .long("upstream-uri")
+                .value_name("UPSTREAM_URI")
+                .help("Expand output directories using trees fetched from this http cache")
+                .takes_value(true),
+        )
         .get_matches();
 
-    let path = Path::new(matches.value_of("file").expect("Require a file path"));
-
-    let mut s = ActionResult::new();
-
-    info!("Reading file {:?}", path);
-
-    if path.exists() {
-        let file = File::open(&path).map_err(ProtobufError::IoError).unwrap();
-        let mut br = BufReader::new(file);
-        let mut cis = CodedInputStream::from_buffered_reader(&mut br);
-        s.merge_from(&mut cis).unwrap();
-    } else {
-        panic!("Path {:?} does not exist!", path);
-    }
+    let source = match (matches.value_of("cache_folder"), matches.value_of("upstream")) {
+        (Some(folder), _) => Some(BlobSource::CacheFolder(PathBuf::from(folder))),
+        (None, Some(upstream)) => Some(BlobSource::Upstream(
+            upstream.parse().expect("Failed to parse upstream URI"),
+        )),
+        (None, None) => None,
+    };
+    let json_format = matches.value_of("format") == Some("json");
 
-    let blacklist = ["external/io_bazel_rules_go"];
+    let action_result = load_action_result(matches.value_of("file").expect("Require a file path"));
 
-    let mut blacklisted = false;
-    for i in s.output_directories.iter() {
-        println!("{:?}", i);
-        for e in blacklist.iter() {
-            if i.path.contains(e) {
-                blacklisted = true;
+    match matches.value_of("diff") {
+        Some(other) => {
+            let other = load_action_result(other);
+            if diff(&action_result, &other, source.as_ref(), json_format) {
+                process::exit(1);
             }
         }
+        None => dump(&action_result, source.as_ref(), json_format),
     }
-
-    warn!("Blacklisted? : {:?}", blacklisted);
-
-    info!("Contents of s: {:?}", s.output_directories);
-    info!("Contents of s: {:?}", s);
-
-    // info!("Contents of s: {:?}", s.fields);
 }
@@ -3,7 +3,6 @@ extern crate futures;
 extern crate hyper;
 extern crate hyper_proxy;
 extern crate local_cache_proxy;
-extern crate lru_disk_cache;
 extern crate pretty_env_logger;
 extern crate rusoto_core;
 extern crate rusoto_s3;
@@ -13,11 +12,13 @@ extern crate tokio_uds;
 
 use clap::{App, Arg};
 use local_cache_proxy::config::S3Config;
-use std::env;
+use std::process;
+use std::time::Duration;
 #[macro_use]
 extern crate log;
 
 use local_cache_proxy::config::AppConfig;
+use local_cache_proxy::net::instance::InstanceLock;
 use rusoto_core::Region;
 use rusoto_s3::S3Client;
 
@@ -69,6 +70,68 @@ fn main() {
                 .help("location for the cache")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("eviction_policy")
+                .long("eviction-policy")
+                .value_name("POLICY")
+                .help("How to pick cache entries to evict: lru, gdsf, arc or lru-admission. Defaults to lru")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("eviction_stats")
+                .long("eviction-stats")
+                .help("Also simulate the other eviction policies against the same traffic, comparing their hit ratios on /admin/eviction")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("scrub_rate")
+                .long("scrub-rate")
+                .value_name("BYTES_PER_SECOND")
+                .help("Bytes a second of cache entries to check for corruption in the background, quarantining any that are, 0 to disable. Defaults to 8MB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("write_policy_file")
+                .long("write-policy-file")
+                .value_name("WRITE_POLICY_FILE")
+                .help("File of `<credential> <types>` lines restricting who may write ac/cas entries")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown_grace_period")
+                .long("shutdown-grace-period")
+                .value_name("GRACE_PERIOD_IN_MS")
+                .help("MS to let in-flight requests finish when shutting down")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin_token")
+                .long("admin-token")
+                .value_name("TOKEN")
+                .help("Bearer token required for the /admin API, which is disabled without one")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denylist_file")
+                .long("denylist-file")
+                .value_name("DENYLIST_FILE")
+                .help("File of AC/CAS digests, one per line, to treat as missing, reloaded when it changes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("denylist_refresh_interval")
+                .long("denylist-refresh-interval")
+                .value_name("INTERVAL_IN_MS")
+                .help("MS between checks for denylist changes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("access_log")
+                .long("access-log")
+                .value_name("ACCESS_LOG")
+                .help("File to append a JSON line per request to, or - for stdout")
+                .takes_value(true),
+        )
         .get_matches();
 
     let s3_config = S3Config {
@@ -83,11 +146,11 @@ fn main() {
             .to_string(),
     };
 
+    let cfg = AppConfig::from_matches(&matches).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
     let cfg = AppConfig {
-        proxy: None,
-        upstream: "http://local:123"
-            .parse()
-            .expect("Failed to parse URI for primary upstream"),
         bind_target: format!(
             "http://127.0.0.1:{}",
             matches
@@ -95,34 +158,24 @@ fn main() {
                 .expect("Expected port to be specified")
         ).parse()
             .unwrap(),
-        cache_folder_size: matches
-            .value_of("cache_folder_size")
-            .unwrap_or("32212254720")
-            .parse()
-            .unwrap(),
-        cache_folder: matches
-            .value_of("cache_folder")
-            .unwrap_or(&format!(
-                "{}/bazel_download_cache",
-                env::home_dir().unwrap().display()
-            ))
-            .to_string(),
-        maximum_upload_size: matches
-            .value_of("maximum_upload_size")
-            .unwrap_or("10485760")
-            .parse()
-            .unwrap(),
-        maximum_download_size: matches
-            .value_of("maximum_download_size")
-            .unwrap_or("10485760")
-            .parse()
-            .unwrap(),
         idle_time_terminate: None,
+        // Misses are only remembered on the client side, where they save a round trip
+        negative_cache_ttl: Duration::from_millis(0),
+        negative_cache_size: 0,
+        memory_cache_size: 0,
+        upload_types: Vec::new(),
+        ..cfg
     };
 
     info!("setting up bazel cache folder in : {:?}", cfg.cache_folder);
     info!("Cache folder size in : {:?}", cfg.cache_folder_size);
 
+    // Two proxies sharing a cache folder would corrupt each other's LRU state
+    let _instance_lock = InstanceLock::acquire(&cfg).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+
     let client = S3Client::simple(Region::UsWest2);
 
     local_cache_proxy::net::start_remote_cache_server(&cfg, &s3_config, client).unwrap();
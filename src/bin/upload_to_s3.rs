@@ -3,7 +3,6 @@ extern crate futures;
 extern crate hyper;
 extern crate hyper_proxy;
 extern crate local_cache_proxy;
-extern crate lru_disk_cache;
 extern crate pretty_env_logger;
 extern crate protobuf;
 extern crate rusoto_core;
@@ -1,7 +1,23 @@
+use clap::ArgMatches;
+use config::{EvictionPolicy, RepoRoute, UpstreamConfig, WritePolicy};
 use hyper::Uri as HyperUri;
 use std;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
+// Partition holding every repo without a quota of its own
+pub const DEFAULT_PARTITION: &str = "default";
+
+// Repo names end up as folder names, they mustn't reach outside the folder they're put in
+pub fn is_safe_folder_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && !name.contains('/') && !name.contains("..")
+}
+
 #[derive(Debug, Clone)]
 pub struct S3Config {
     pub bucket: String,
@@ -19,27 +35,312 @@ impl S3Config {
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
-    // This contains the upstream we fetch from
-    pub upstream: HyperUri,
+    // The upstreams we fetch from, in the order we try them
+    pub upstreams: Vec<UpstreamConfig>,
+    // Repos matching a route use its upstreams instead of the defaults above
+    pub routes: Vec<RepoRoute>,
     pub proxy: Option<String>,
     pub bind_target: HyperUri,
     pub cache_folder: String,
     pub cache_folder_size: u64,
+    // Repos with their own budget carved out of cache_folder_size
+    pub repo_quotas: HashMap<String, u64>,
     pub maximum_download_size: u64,
     pub maximum_upload_size: u64,
     pub idle_time_terminate: Option<Duration>,
+    // How long we give in-flight requests and queued uploads to finish when shutting down
+    pub shutdown_grace_period: Duration,
+    // How long, and how many, upstream misses we remember so we can 404 without asking again
+    pub negative_cache_ttl: Duration,
+    pub negative_cache_size: usize,
+    // Cached action cache entries older than this are checked with the upstream again
+    pub ac_max_age: Option<Duration>,
+    // Check stale action cache entries with a conditional request rather than refetching them
+    pub ac_revalidate: bool,
+    // Digests to treat as missing, from a file and/or a server proxy, checked this often
+    pub denylist_file: Option<String>,
+    pub denylist_uri: Option<HyperUri>,
+    pub denylist_refresh_interval: Duration,
+    // Send CAS blobs to the requester as they arrive from the upstream, not once they're cached
+    pub tee_downloads: bool,
+    // Bytes of small entries, up to the max entry size each, also kept in memory
+    pub memory_cache_size: u64,
+    pub memory_cache_max_entry_size: u64,
+    // How each partition picks what to evict, and whether to also simulate the other policies
+    // against the same traffic to compare them
+    pub eviction_policy: EvictionPolicy,
+    pub eviction_stats: bool,
+    // `<set> <digest>` lines pinning entries so they're never evicted, more sets can be added
+    // through the admin API
+    pub pin_file: Option<String>,
+    // Most bytes of pinned entries to hold, on top of the cache folder size
+    pub max_pinned_size: u64,
+    // Bytes a second of cache entries to re-hash/decode in the background looking for
+    // corruption, 0 to not scrub
+    pub scrub_rate: u64,
+    // Sent as a bearer token on uploads to the upstream
+    pub upstream_credential: Option<String>,
+    // Cache types (ac/cas) we will try to upload to the upstream
+    pub upload_types: Vec<String>,
+    // Who may write which cache types, only used when serving as the upstream
+    pub write_policy: WritePolicy,
+    // LAN peers we try for CAS blobs before going to the upstream
+    pub peers: Vec<HyperUri>,
+    pub discover_peers: bool,
+    // Uri we announce to other peers over mDNS, if we share our cache
+    pub advertise_uri: Option<HyperUri>,
+    // Bearer token required for the /admin API, which is disabled without one
+    pub admin_token: Option<String>,
+    // Where to write the JSON access log, `-` for stdout, off when unset
+    pub access_log: Option<String>,
 }
 
 impl AppConfig {
-    pub fn upstream(&self) -> HyperUri {
-        self.upstream.clone()
+    // Every binary shares the flag names, a flag a binary doesn't define takes its default
+    // here, binaries that want a different one override the field afterwards.
+    pub fn from_matches(matches: &ArgMatches) -> Result<AppConfig, String> {
+        let maximum_download_size: u64 = flag_or(matches, "maximum_download_size", "10485760")?;
+        let maximum_upload_size: u64 = flag_or(matches, "maximum_upload_size", "10485760")?;
+        let cache_folder_size: u64 = flag_or(matches, "cache_folder_size", "32212254720")?;
+
+        let cfg = AppConfig {
+            upstreams: matches
+                .values_of("upstream")
+                .map(|upstreams| {
+                    upstreams
+                        .map(|e| UpstreamConfig::parse(e, maximum_download_size, maximum_upload_size))
+                        .collect()
+                })
+                .unwrap_or(Ok(Vec::new()))?,
+            routes: match matches.value_of("routes_file") {
+                Some(path) => {
+                    RepoRoute::from_file(path, maximum_download_size, maximum_upload_size)?
+                }
+                None => Vec::new(),
+            },
+            proxy: matches.value_of("proxy").map(|e| e.to_string()),
+            bind_target: flag_or(matches, "bind_target", "http://localhost:10487")?,
+            cache_folder: match matches.value_of("cache_folder") {
+                Some(folder) => folder.to_string(),
+                None => format!(
+                    "{}/bazel_download_cache",
+                    env::home_dir()
+                        .ok_or("No home directory to put the cache folder in")?
+                        .display()
+                ),
+            },
+            cache_folder_size: cache_folder_size,
+            repo_quotas: matches
+                .values_of("repo_quota")
+                .map(|quotas| quotas.map(parse_repo_quota).collect())
+                .unwrap_or(Ok(HashMap::new()))?,
+            maximum_download_size: maximum_download_size,
+            maximum_upload_size: maximum_upload_size,
+            // 10 minute default
+            idle_time_terminate: Some(ms_flag_or(matches, "idle_time_terminate", "600000")?),
+            shutdown_grace_period: ms_flag_or(matches, "shutdown_grace_period", "30000")?,
+            negative_cache_ttl: ms_flag_or(matches, "negative_cache_ttl", "60000")?,
+            negative_cache_size: flag_or(matches, "negative_cache_size", "100000")?,
+            ac_max_age: match matches.value_of("ac_max_age") {
+                Some(_) => Some(ms_flag_or(matches, "ac_max_age", "")?),
+                None => None,
+            },
+            ac_revalidate: matches.is_present("ac_revalidate"),
+            denylist_file: matches.value_of("denylist_file").map(|e| e.to_string()),
+            denylist_uri: match matches.value_of("denylist_uri") {
+                Some(_) => Some(flag_or(matches, "denylist_uri", "")?),
+                None => None,
+            },
+            denylist_refresh_interval: ms_flag_or(matches, "denylist_refresh_interval", "60000")?,
+            tee_downloads: matches.is_present("tee_downloads"),
+            memory_cache_size: flag_or(matches, "memory_cache_size", "67108864")?,
+            memory_cache_max_entry_size: flag_or(matches, "memory_cache_max_entry_size", "65536")?,
+            eviction_policy: EvictionPolicy::parse(
+                matches.value_of("eviction_policy").unwrap_or("lru"),
+            )?,
+            eviction_stats: matches.is_present("eviction_stats"),
+            pin_file: matches.value_of("pin_file").map(|e| e.to_string()),
+            // A quarter of the cache folder size unless told otherwise
+            max_pinned_size: match matches.value_of("max_pinned_size") {
+                Some(_) => flag_or(matches, "max_pinned_size", "")?,
+                None => cache_folder_size / 4,
+            },
+            scrub_rate: flag_or(matches, "scrub_rate", "8388608")?,
+            upstream_credential: matches
+                .value_of("upstream_credential")
+                .map(|e| e.to_string()),
+            upload_types: matches
+                .value_of("upload_types")
+                .unwrap_or("ac,cas")
+                .split(',')
+                .map(|e| e.trim().to_string())
+                .collect(),
+            write_policy: match matches.value_of("write_policy_file") {
+                Some(path) => WritePolicy::from_file(path)?,
+                None => WritePolicy::allow_all(),
+            },
+            peers: matches
+                .values_of("peer")
+                .map(|peers| {
+                    peers
+                        .map(|e| {
+                            e.parse()
+                                .map_err(|err| format!("Invalid --peer {:?}: {:?}", e, err))
+                        })
+                        .collect()
+                })
+                .unwrap_or(Ok(Vec::new()))?,
+            discover_peers: matches.is_present("discover_peers"),
+            advertise_uri: match matches.value_of("advertise_uri") {
+                Some(_) => Some(flag_or(matches, "advertise_uri", "")?),
+                None => None,
+            },
+            admin_token: matches.value_of("admin_token").map(|e| e.to_string()),
+            access_log: matches.value_of("access_log").map(|e| e.to_string()),
+        };
+        cfg.check_repo_quotas()?;
+        Ok(cfg)
+    }
+
+    pub fn upstreams(&self) -> Vec<UpstreamConfig> {
+        self.upstreams.clone()
+    }
+
+    pub fn upstreams_for(&self, repo: &str) -> Vec<UpstreamConfig> {
+        match self.routes.iter().find(|route| route.matches(repo)) {
+            Some(route) => route.upstreams.clone(),
+            None => self.upstreams(),
+        }
     }
 
     pub fn proxy(&self) -> Option<String> {
         self.proxy.clone()
     }
 
+    pub fn upstream_credential(&self) -> Option<String> {
+        self.upstream_credential.clone()
+    }
+
+    pub fn should_upload_type(&self, tpe: &str) -> bool {
+        self.upload_types.iter().any(|t| t == tpe)
+    }
+
+    // Which LRU partition a repo's entries live in
+    pub fn partition_for(&self, repo: &str) -> String {
+        if self.repo_quotas.contains_key(repo) {
+            repo.to_string()
+        } else {
+            DEFAULT_PARTITION.to_string()
+        }
+    }
+
+    // Without any quotas everything lives directly in the cache folder as it always has,
+    // otherwise each partition gets a folder of its own.
+    pub fn partition_folder(&self, partition: &str) -> PathBuf {
+        if self.repo_quotas.is_empty() {
+            PathBuf::from(&self.cache_folder)
+        } else {
+            Path::new(&self.cache_folder)
+                .join("partitions")
+                .join(partition)
+        }
+    }
+
+    pub fn cache_path(&self, repo: &str, file_name: &str) -> PathBuf {
+        self.partition_folder(&self.partition_for(repo))
+            .join(file_name)
+    }
+
+    // Downloads land here before moving into the cache, keeping that rename on one filesystem
+    pub fn tmp_folder(&self) -> PathBuf {
+        Path::new(&self.cache_folder).join("tmp")
+    }
+
+    // Find an entry without knowing which repo it belongs to
+    pub fn find_cached(&self, file_name: &str) -> Option<PathBuf> {
+        self.partitions()
+            .iter()
+            .map(|&(ref partition, _)| self.partition_folder(partition).join(file_name))
+            .find(|path| path.is_file())
+    }
+
+    // Every ac/cas entry on disk along with the partition holding it
+    pub fn cached_entries(&self) -> Vec<(String, PathBuf)> {
+        let mut entries = Vec::new();
+        for (partition, _) in self.partitions() {
+            let paths = match fs::read_dir(self.partition_folder(&partition)) {
+                Ok(paths) => paths,
+                Err(_) => continue,
+            };
+            for path in paths.filter_map(|e| e.ok()) {
+                let file_name = path.file_name().to_string_lossy().to_string();
+                if file_name.starts_with("ac__") || file_name.starts_with("cas__") {
+                    entries.push((partition.clone(), path.path()));
+                }
+            }
+        }
+        entries
+    }
+
+    // The default partition needs some room of its own, with none it would drop every entry
+    pub fn check_repo_quotas(&self) -> Result<(), String> {
+        let allocated: u64 = self.repo_quotas.values().sum();
+        if allocated >= self.cache_folder_size {
+            return Err(format!(
+                "Repo quotas add up to {} bytes, leaving none of the {} byte cache folder for other repos",
+                allocated, self.cache_folder_size
+            ));
+        }
+        Ok(())
+    }
+
+    // Every partition along with its size budget, the default gets whatever isn't allocated
+    pub fn partitions(&self) -> Vec<(String, u64)> {
+        let allocated: u64 = self.repo_quotas.values().sum();
+        let mut partitions: Vec<(String, u64)> = self
+            .repo_quotas
+            .iter()
+            .map(|(repo, quota)| (repo.clone(), *quota))
+            .collect();
+        partitions.push((
+            DEFAULT_PARTITION.to_string(),
+            self.cache_folder_size.saturating_sub(allocated),
+        ));
+        partitions
+    }
+
     pub fn str_to_ms(s: &str) -> Result<Duration, std::num::ParseIntError> {
         s.parse::<u64>().map(|e| Duration::from_millis(e))
     }
 }
+
+fn flag_or<T>(matches: &ArgMatches, name: &str, default: &str) -> Result<T, String>
+where
+    T: FromStr,
+    T::Err: Debug,
+{
+    let value = matches.value_of(name).unwrap_or(default);
+    value
+        .parse()
+        .map_err(|e| format!("Invalid --{} {:?}: {:?}", name.replace('_', "-"), value, e))
+}
+
+fn ms_flag_or(matches: &ArgMatches, name: &str, default: &str) -> Result<Duration, String> {
+    flag_or(matches, name, default).map(Duration::from_millis)
+}
+
+fn parse_repo_quota(spec: &str) -> Result<(String, u64), String> {
+    let parts: Vec<&str> = spec.splitn(2, '=').collect();
+    match parts.get(1).and_then(|q| q.parse().ok()) {
+        // The repo names a partition folder, and the default partition is everyone else's
+        Some(_) if parts[0] == DEFAULT_PARTITION || !is_safe_folder_name(parts[0]) => Err(format!(
+            "Invalid --repo-quota {:?}, {:?} can't be used as a repo name",
+            spec, parts[0]
+        )),
+        Some(quota) => Ok((parts[0].to_string(), quota)),
+        None => Err(format!(
+            "Invalid --repo-quota {:?}, expected <repo>=<bytes>",
+            spec
+        )),
+    }
+}
@@ -0,0 +1,46 @@
+/// How a cache partition picks what to give up when it's over its budget.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    // Least recently used first
+    Lru,
+    // Greedy-Dual-Size-Frequency, large rarely used entries first
+    Gdsf,
+    // Adaptive Replacement Cache, balancing recently and frequently used entries
+    Arc,
+    // LRU, but entries we've not seen before are evicted ahead of everything else
+    LruAdmission,
+}
+
+impl EvictionPolicy {
+    pub fn all() -> Vec<EvictionPolicy> {
+        vec![
+            EvictionPolicy::Lru,
+            EvictionPolicy::Gdsf,
+            EvictionPolicy::Arc,
+            EvictionPolicy::LruAdmission,
+        ]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            EvictionPolicy::Lru => "lru",
+            EvictionPolicy::Gdsf => "gdsf",
+            EvictionPolicy::Arc => "arc",
+            EvictionPolicy::LruAdmission => "lru-admission",
+        }
+    }
+
+    pub fn parse(name: &str) -> Result<EvictionPolicy, String> {
+        EvictionPolicy::all()
+            .into_iter()
+            .find(|e| e.name() == name.trim())
+            .ok_or_else(|| {
+                let names: Vec<&str> = EvictionPolicy::all().iter().map(|e| e.name()).collect();
+                format!(
+                    "Unknown eviction policy {:?}, expected one of {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+}
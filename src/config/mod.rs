@@ -1,4 +1,14 @@
 mod app_config;
+mod eviction_policy;
+mod repo_route;
+mod upstream_config;
+mod write_policy;
 
 pub use self::app_config::AppConfig;
+pub use self::app_config::DEFAULT_PARTITION;
+pub use self::app_config::is_safe_folder_name;
 pub use self::app_config::S3Config;
+pub use self::eviction_policy::EvictionPolicy;
+pub use self::repo_route::RepoRoute;
+pub use self::upstream_config::UpstreamConfig;
+pub use self::write_policy::WritePolicy;
@@ -0,0 +1,66 @@
+use config::UpstreamConfig;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+/// Sends requests for repos matching `pattern` to their own set of upstreams.
+#[derive(Debug, Clone)]
+pub struct RepoRoute {
+    // Repo name, or a glob using `*` and `?`
+    pub pattern: String,
+    pub upstreams: Vec<UpstreamConfig>,
+}
+
+// Simple glob matching, `*` matches any run of characters and `?` any single one.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((&'*', rest)) => (0..text.len() + 1).any(|i| glob_matches(rest, &text[i..])),
+        Some((&'?', rest)) => !text.is_empty() && glob_matches(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_matches(rest, &text[1..]),
+    }
+}
+
+impl RepoRoute {
+    pub fn matches(&self, repo: &str) -> bool {
+        let pattern: Vec<char> = self.pattern.chars().collect();
+        let repo: Vec<char> = repo.chars().collect();
+        glob_matches(&pattern, &repo)
+    }
+
+    /// Load routes from a file with one route per line, `<repo glob> <upstream spec>...`,
+    /// the first matching line wins.
+    ///
+    /// ```text
+    /// monorepo http://mirror:8080 http://central:8080,max_download=104857600
+    /// side-* http://side-cache:8080,upload=false
+    /// ```
+    pub fn from_file(
+        path: &str,
+        default_download_size: u64,
+        default_upload_size: u64,
+    ) -> Result<Vec<RepoRoute>, String> {
+        let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+        let mut routes = Vec::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let pattern = parts.next().unwrap().to_string();
+            let upstreams = parts
+                .map(|spec| UpstreamConfig::parse(spec, default_download_size, default_upload_size))
+                .collect::<Result<Vec<UpstreamConfig>, String>>()?;
+            if upstreams.is_empty() {
+                return Err(format!("Route for {:?} has no upstreams", pattern));
+            }
+            routes.push(RepoRoute {
+                pattern: pattern,
+                upstreams: upstreams,
+            });
+        }
+        Ok(routes)
+    }
+}
@@ -0,0 +1,64 @@
+use hyper::Uri as HyperUri;
+
+/// A single upstream cache along with the limits we apply when talking to it.
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    pub uri: HyperUri,
+    pub maximum_download_size: u64,
+    pub maximum_upload_size: u64,
+    // Whether local writes should be forwarded to this upstream
+    pub upload: bool,
+    // How many times we retry a failing fetch before falling through to the next upstream
+    pub retries: i32,
+}
+
+impl UpstreamConfig {
+    /// Parse an upstream spec, `<uri>[,max_download=N][,max_upload=N][,upload=BOOL][,retries=N]`,
+    /// anything not specified takes the given defaults.
+    pub fn parse(
+        spec: &str,
+        default_download_size: u64,
+        default_upload_size: u64,
+    ) -> Result<UpstreamConfig, String> {
+        let mut parts = spec.split(',');
+        let uri: HyperUri = parts
+            .next()
+            .ok_or_else(|| format!("Empty upstream spec: {:?}", spec))?
+            .trim()
+            .parse()
+            .map_err(|e| format!("Invalid upstream uri in {:?}: {:?}", spec, e))?;
+
+        let mut upstream = UpstreamConfig {
+            uri: uri,
+            maximum_download_size: default_download_size,
+            maximum_upload_size: default_upload_size,
+            upload: true,
+            retries: 3,
+        };
+
+        for option in parts {
+            let kv: Vec<&str> = option.splitn(2, '=').map(|e| e.trim()).collect();
+            if kv.len() != 2 {
+                return Err(format!(
+                    "Invalid upstream option {:?} in {:?}",
+                    option, spec
+                ));
+            }
+            let bad_value = |_| format!("Invalid value for {} in {:?}", kv[0], spec);
+            match kv[0] {
+                "max_download" => {
+                    upstream.maximum_download_size = kv[1].parse().map_err(bad_value)?
+                }
+                "max_upload" => upstream.maximum_upload_size = kv[1].parse().map_err(bad_value)?,
+                "retries" => upstream.retries = kv[1].parse().map_err(bad_value)?,
+                "upload" => {
+                    upstream.upload = kv[1]
+                        .parse()
+                        .map_err(|_| format!("Invalid value for upload in {:?}", spec))?
+                }
+                o => return Err(format!("Unknown upstream option {:?} in {:?}", o, spec)),
+            }
+        }
+        Ok(upstream)
+    }
+}
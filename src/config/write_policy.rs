@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+// Entry used for requests that don't present a credential we know about.
+const ANONYMOUS: &str = "*";
+
+/// Which cache types (`ac`, `cas`) a credential is allowed to write.
+///
+/// Loaded from a file with one policy per line, `<credential> <types>`, e.g.
+///
+/// ```text
+/// # CI may populate everything, laptops only content addressed blobs
+/// ci-secret-token ac,cas
+/// * cas
+/// ```
+///
+/// The `*` line applies to requests without a credential or with one not listed.
+#[derive(Debug, Clone)]
+pub struct WritePolicy {
+    credentials: HashMap<String, Vec<String>>,
+}
+
+impl WritePolicy {
+    pub fn allow_all() -> WritePolicy {
+        let mut credentials = HashMap::new();
+        credentials.insert(
+            ANONYMOUS.to_string(),
+            vec!["ac".to_string(), "cas".to_string()],
+        );
+        WritePolicy {
+            credentials: credentials,
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<WritePolicy, String> {
+        let file = File::open(path).map_err(|e| format!("Unable to open {}: {}", path, e))?;
+        let mut credentials = HashMap::new();
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 2 {
+                return Err(format!("Invalid write policy line: {:?}", line));
+            }
+            let types: Vec<String> = parts[1]
+                .split(',')
+                .map(|e| e.trim().to_string())
+                .filter(|e| !e.is_empty())
+                .collect();
+            credentials.insert(parts[0].to_string(), types);
+        }
+
+        Ok(WritePolicy {
+            credentials: credentials,
+        })
+    }
+
+    pub fn allows(&self, credential: Option<&str>, tpe: &str) -> bool {
+        let policy = credential
+            .and_then(|c| self.credentials.get(c))
+            .or_else(|| self.credentials.get(ANONYMOUS));
+        match policy {
+            Some(types) => types.iter().any(|t| t == tpe),
+            None => false,
+        }
+    }
+}
@@ -7,20 +7,26 @@ extern crate hyper_proxy;
 extern crate tempdir;
 #[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
 extern crate bytes;
 extern crate clap;
 extern crate hex;
 extern crate http;
 extern crate iovec;
+#[macro_use]
+extern crate lazy_static;
 extern crate libc;
-extern crate lru_disk_cache;
 extern crate mio;
 extern crate mio_uds;
+extern crate net2;
 extern crate pretty_env_logger;
 extern crate protobuf;
 extern crate rand;
 extern crate rusoto_core;
 extern crate rusoto_s3;
+#[macro_use]
+extern crate serde_json;
+extern crate sha2;
 extern crate tokio;
 extern crate tokio_core;
 extern crate tokio_io;
@@ -0,0 +1,182 @@
+use config::AppConfig;
+use http::header::{self, HeaderMap, HeaderValue};
+use hyper::Uri;
+use net::disk_health::disk_error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What the upstream told us about an action cache entry, so we can ask if it's changed.
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn from_headers(headers: &HeaderMap) -> Validators {
+        let value = |name| {
+            headers
+                .get(name)
+                .and_then(|e: &HeaderValue| e.to_str().ok())
+                .map(|e| e.to_string())
+        };
+        Validators {
+            etag: value(header::ETAG),
+            last_modified: value(header::LAST_MODIFIED),
+        }
+    }
+
+    // Headers making a GET conditional on the entry having changed
+    pub fn conditional_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = self.etag.as_ref().and_then(|e| HeaderValue::from_str(e).ok()) {
+            headers.insert(header::IF_NONE_MATCH, value);
+        }
+        if let Some(value) = self.last_modified
+            .as_ref()
+            .and_then(|e| HeaderValue::from_str(e).ok())
+        {
+            headers.insert(header::IF_MODIFIED_SINCE, value);
+        }
+        headers
+    }
+}
+
+// Whether AC entries can go stale at all, CAS blobs never do
+pub fn enabled(config: &AppConfig) -> bool {
+    config.ac_max_age.is_some() || config.ac_revalidate
+}
+
+// Action cache entries we check for freshness need to go by their files on disk
+pub fn memory_servable(config: &AppConfig, file_name: &str) -> bool {
+    !(file_name.starts_with("ac__") && enabled(config))
+}
+
+fn validators_folder(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("ac_validators")
+}
+
+// Kept outside the partitions so the LRU doesn't mistake them for entries
+fn validators_path(config: &AppConfig, repo: &str, file_name: &str) -> PathBuf {
+    validators_folder(config)
+        .join(config.partition_for(repo))
+        .join(file_name)
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|e| e.as_secs()).unwrap_or(0)
+}
+
+// When we last confirmed the entry with the upstream, how, and which upstream it was (unknown
+// for entries recorded before we kept track), None if it was written locally.
+fn read_validated(
+    config: &AppConfig,
+    repo: &str,
+    file_name: &str,
+) -> Option<(u64, Validators, Option<String>)> {
+    let contents = fs::read_to_string(validators_path(config, repo, file_name)).ok()?;
+    let mut lines = contents.lines();
+    let validated_at = lines.next()?.parse().ok()?;
+    let mut next = || lines.next().filter(|e| !e.is_empty()).map(|e| e.to_string());
+    let etag = next();
+    let last_modified = next();
+    let upstream = next();
+    Some((
+        validated_at,
+        Validators {
+            etag: etag,
+            last_modified: last_modified,
+        },
+        upstream,
+    ))
+}
+
+pub fn validators(config: &AppConfig, repo: &str, file_name: &str) -> Option<Validators> {
+    read_validated(config, repo, file_name).map(|e| e.1)
+}
+
+// The upstream we last fetched or confirmed the entry from, the only one that can tell us it's gone
+pub fn validated_by(config: &AppConfig, repo: &str, file_name: &str) -> Option<String> {
+    read_validated(config, repo, file_name).and_then(|e| e.2)
+}
+
+pub fn is_fresh(config: &AppConfig, repo: &str, file_name: &str) -> bool {
+    if !file_name.starts_with("ac__") || !enabled(config) {
+        return true;
+    }
+    // Revalidating with no max age means checking on every request
+    let max_age = config.ac_max_age.unwrap_or(Duration::from_millis(0));
+    // Entries PUT locally may not have reached the upstream yet, it can't tell us they're stale
+    let validated_at = match read_validated(config, repo, file_name) {
+        Some((validated_at, _, _)) => validated_at,
+        None => return true,
+    };
+    unix_secs(SystemTime::now()).saturating_sub(validated_at) < max_age.as_secs()
+}
+
+pub fn record_validated(
+    config: &AppConfig,
+    repo: &str,
+    file_name: &str,
+    upstream: &Uri,
+    validators: &Validators,
+) {
+    if !file_name.starts_with("ac__") || !enabled(config) {
+        return;
+    }
+    let path = validators_path(config, repo, file_name);
+    let written = path.parent()
+        .map(|e| fs::create_dir_all(e))
+        .unwrap_or(Ok(()))
+        .and_then(|_| fs::File::create(&path))
+        .and_then(|mut f| {
+            writeln!(
+                f,
+                "{}\n{}\n{}\n{}",
+                unix_secs(SystemTime::now()),
+                validators.etag.as_ref().map(|e| e.as_str()).unwrap_or(""),
+                validators
+                    .last_modified
+                    .as_ref()
+                    .map(|e| e.as_str())
+                    .unwrap_or(""),
+                upstream
+            )
+        });
+    written
+        .map_err(disk_error)
+        .unwrap_or_else(|e| warn!("Failed to record validators for {}: {}", file_name, e));
+}
+
+// The entry was replaced locally or is gone, whatever we knew about it no longer applies
+pub fn forget(config: &AppConfig, repo: &str, file_name: &str) {
+    fs::remove_file(validators_path(config, repo, file_name)).unwrap_or(());
+}
+
+// Validator files whose entry has since been evicted.
+pub fn orphaned_validators(config: &AppConfig) -> Vec<PathBuf> {
+    let mut orphaned = Vec::new();
+    let partitions = match fs::read_dir(validators_folder(config)) {
+        Ok(e) => e,
+        Err(_) => return orphaned,
+    };
+    for partition in partitions.filter_map(|e| e.ok()) {
+        let partition_name = partition.file_name().to_string_lossy().to_string();
+        let entries = match fs::read_dir(partition.path()) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let cached = config
+                .partition_folder(&partition_name)
+                .join(entry.file_name())
+                .is_file();
+            if !cached {
+                orphaned.push(entry.path());
+            }
+        }
+    }
+    orphaned
+}
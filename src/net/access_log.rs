@@ -0,0 +1,281 @@
+use bytes::Buf;
+use futures::{Async, Poll};
+use http::header::{self, HeaderValue};
+use hyper::body::Payload;
+use hyper::{Body, Request, Response};
+use net::mapped_file::{BodyData, BodyError, ResponseBody};
+use net::proxy_request::ProxyRequest;
+use net::server_error::ServerError;
+use net::state::InFlight;
+use rand;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+// How a request was answered, recorded in the access log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    LocalHit,
+    Revalidated,
+    StaleHit,
+    PeerHit,
+    UpstreamHit,
+    Miss,
+    KnownMiss,
+    Gated,
+    Denied,
+    TooLarge,
+    Stored,
+    AlreadyPresent,
+    Rejected,
+    // Served or stored by the upstream alone, while the cache disk is failing
+    PassedThrough,
+    Error,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Outcome::LocalHit => "local_hit",
+            Outcome::Revalidated => "revalidated",
+            Outcome::StaleHit => "stale_hit",
+            Outcome::PeerHit => "peer_hit",
+            Outcome::UpstreamHit => "upstream_hit",
+            Outcome::Miss => "miss",
+            Outcome::KnownMiss => "known_miss_404",
+            Outcome::Gated => "gated_404",
+            Outcome::Denied => "denied_404",
+            Outcome::TooLarge => "too_large_404",
+            Outcome::Stored => "stored",
+            Outcome::AlreadyPresent => "already_present",
+            Outcome::Rejected => "rejected",
+            Outcome::PassedThrough => "passed_through",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+struct Entry {
+    request_id: String,
+    method: String,
+    path: String,
+    tpe: Option<String>,
+    repo: Option<String>,
+    digest: Option<String>,
+    status: Option<u16>,
+    outcome: Option<Outcome>,
+    // Request body bytes as the client declared them, response body bytes as we sent them
+    bytes_in: u64,
+    bytes_out: u64,
+    started: Instant,
+    first_byte: Option<Duration>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1000.0 + d.subsec_nanos() as f64 / 1_000_000.0
+}
+
+/// What we know about a single request, filled in as it's handled.
+#[derive(Clone)]
+pub struct RequestLog {
+    entry: Arc<Mutex<Entry>>,
+}
+
+impl RequestLog {
+    // Reuses the caller's request id if they sent one, so it can be correlated across proxies
+    pub fn new(req: &Request<Body>) -> RequestLog {
+        let request_id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|e| e.to_str().ok())
+            .filter(|e| !e.is_empty() && e.len() <= 128)
+            .map(|e| e.to_string())
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+        let content_length = req
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|e| e.to_str().ok())
+            .and_then(|e| e.parse().ok())
+            .unwrap_or(0);
+
+        RequestLog {
+            entry: Arc::new(Mutex::new(Entry {
+                request_id: request_id,
+                method: req.method().to_string(),
+                path: req.uri().path().to_string(),
+                tpe: None,
+                repo: None,
+                digest: None,
+                status: None,
+                outcome: None,
+                bytes_in: content_length,
+                bytes_out: 0,
+                started: Instant::now(),
+                first_byte: None,
+            })),
+        }
+    }
+
+    pub fn request_id(&self) -> String {
+        self.entry.lock().unwrap().request_id.clone()
+    }
+
+    pub fn set_request(&self, proxy_request: &ProxyRequest) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.tpe = Some(proxy_request.tpe.clone());
+        entry.repo = Some(proxy_request.repo.clone());
+        entry.digest = Some(proxy_request.digest.clone());
+    }
+
+    pub fn set_outcome(&self, outcome: Outcome) {
+        self.entry.lock().unwrap().outcome = Some(outcome);
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        self.entry.lock().unwrap().outcome
+    }
+
+    // The first bytes sent mark the time to first byte
+    fn add_bytes_out(&self, bytes: u64) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.bytes_out += bytes;
+        if bytes > 0 && entry.first_byte.is_none() {
+            entry.first_byte = Some(entry.started.elapsed());
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let entry = self.entry.lock().unwrap();
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(millis)
+            .unwrap_or(0.0);
+        json!({
+            "timestamp_ms": timestamp as u64,
+            "request_id": entry.request_id,
+            "method": entry.method,
+            "path": entry.path,
+            "tpe": entry.tpe,
+            "repo": entry.repo,
+            "digest": entry.digest,
+            "status": entry.status,
+            "outcome": entry.outcome.map(|e| e.as_str()),
+            "bytes_in": entry.bytes_in,
+            "bytes_out": entry.bytes_out,
+            "ttfb_ms": entry.first_byte.map(millis),
+            "total_ms": millis(entry.started.elapsed()),
+        }).to_string()
+    }
+}
+
+/// Where access log lines go, one JSON object per line.
+#[derive(Clone)]
+pub struct AccessLog {
+    out: Arc<Mutex<Box<Write + Send>>>,
+}
+
+impl AccessLog {
+    // `-` logs to stdout, anything else is a file we append to
+    pub fn open(path: &str) -> io::Result<AccessLog> {
+        let out: Box<Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(AccessLog {
+            out: Arc::new(Mutex::new(out)),
+        })
+    }
+
+    fn write(&self, request_log: &RequestLog) {
+        let line = request_log.to_json();
+        let mut out = self.out.lock().unwrap();
+        writeln!(out, "{}", line)
+            .and_then(|_| out.flush())
+            .unwrap_or_else(|e| warn!("Failed writing access log: {}", e));
+    }
+}
+
+// Counts what we send, writing the log line once the body is done with, sent in full or not
+pub struct LoggedBody {
+    inner: ResponseBody,
+    access_log: Option<AccessLog>,
+    request_log: RequestLog,
+    // Shutdown waits until the whole body is sent, not just the headers
+    _in_flight: InFlight,
+}
+
+impl Payload for LoggedBody {
+    type Data = BodyData;
+    type Error = BodyError;
+
+    fn poll_data(&mut self) -> Poll<Option<BodyData>, BodyError> {
+        let data = try_ready!(self.inner.poll_data());
+        if let Some(ref data) = data {
+            self.request_log.add_bytes_out(data.remaining() as u64);
+        }
+        Ok(Async::Ready(data))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        self.inner.content_length()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(ref access_log) = self.access_log {
+            access_log.write(&self.request_log);
+        }
+    }
+}
+
+// Tag the response with its request id and log it, if we're keeping an access log.
+pub fn log_response(
+    access_log: &Option<AccessLog>,
+    request_log: RequestLog,
+    in_flight: InFlight,
+    res: Result<Response<ResponseBody>, ServerError>,
+) -> Result<Response<LoggedBody>, ServerError> {
+    match res {
+        Ok(mut res) => {
+            if let Ok(value) = HeaderValue::from_str(&request_log.request_id()) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            let mut access_log = access_log.clone();
+            if access_log.is_some() {
+                request_log.entry.lock().unwrap().status = Some(res.status().as_u16());
+            }
+            if res.body().is_end_stream() {
+                // Without a body the headers are all there is to send
+                if let Some(log) = access_log.take() {
+                    {
+                        let mut entry = request_log.entry.lock().unwrap();
+                        entry.first_byte = Some(entry.started.elapsed());
+                    }
+                    log.write(&request_log);
+                }
+            }
+            Ok(res.map(|body| LoggedBody {
+                inner: body,
+                access_log: access_log,
+                request_log: request_log,
+                _in_flight: in_flight,
+            }))
+        }
+        Err(e) => {
+            if let Some(ref access_log) = *access_log {
+                request_log.set_outcome(Outcome::Error);
+                access_log.write(&request_log);
+            }
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use config::AppConfig;
+use futures::{future, Future, Stream};
+use http::header;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use net::blocking_io;
+use net::disk_health;
+use net::downloader::Downloader;
+use net::process_action_cache::{gate_path, gate_references};
+use net::scrubber;
+use net::server_error::ServerError;
+use net::server_io::{bearer_credential, constant_time_eq, empty_with_status_code_fut};
+use serde_json::Value;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type ResponseFuture = Box<Future<Item = Response<Body>, Error = ServerError> + Send>;
+
+fn json_response(status_code: StatusCode, body: Value) -> ResponseFuture {
+    Box::new(
+        future::result(
+            Response::builder()
+                .status(status_code)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+        ).map_err(From::from),
+    )
+}
+
+fn error_response(status_code: StatusCode, message: &str) -> ResponseFuture {
+    json_response(status_code, json!({ "error": message }))
+}
+
+// Walking or deleting from a big cache folder takes a while, so it happens on the blocking pool
+fn blocking_json<F>(f: F) -> ResponseFuture
+where
+    F: FnOnce() -> Value + Send + 'static,
+{
+    Box::new(
+        blocking_io::run(move || Ok(f()))
+            .map_err(|e: String| ServerError::from(e))
+            .and_then(|body| json_response(StatusCode::OK, body)),
+    )
+}
+
+fn epoch_seconds(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+}
+
+fn query_param(req: &Request<Body>, key: &str) -> Option<String> {
+    req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| {
+                let mut kv = pair.splitn(2, '=');
+                match (kv.next(), kv.next()) {
+                    (Some(k), Some(v)) if k == key && !v.is_empty() => Some(v.to_string()),
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+// Describe every ac/cas entry on disk, optionally restricted to a repo's partition or a digest.
+// Blocking, never holds the disk cache.
+fn list_entries(config: &AppConfig, repo: Option<&str>, digest: Option<&str>) -> Vec<Value> {
+    let repo_partition = repo.map(|repo| config.partition_for(repo));
+    let mut entries = Vec::new();
+    for (partition, path) in config.cached_entries() {
+        if repo_partition.as_ref().map(|p| *p != partition).unwrap_or(false) {
+            continue;
+        }
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        let (tpe, entry_digest) = if file_name.starts_with("ac__") {
+            ("ac", file_name.trim_left_matches("ac__").to_string())
+        } else {
+            ("cas", file_name.trim_left_matches("cas__").to_string())
+        };
+        if digest.map(|d| d != entry_digest).unwrap_or(false) {
+            continue;
+        }
+
+        let metadata = match path.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let mut entry = json!({
+            "type": tpe,
+            "digest": entry_digest,
+            "partition": partition,
+            "size": metadata.len(),
+            "accessed": epoch_seconds(metadata.accessed().ok()),
+            "modified": epoch_seconds(metadata.modified().ok()),
+        });
+        if tpe == "cas" {
+            entry["gated"] = json!(gate_path(config, &entry_digest).is_file());
+            entry["referenced_by"] = json!(gate_references(config, &entry_digest));
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+fn describe_digest(config: &AppConfig, digest: &str) -> Value {
+    let entries = list_entries(config, None, Some(digest));
+    let gated = gate_path(config, digest).is_file();
+    let has_cas = entries.iter().any(|e| e["type"] == "cas");
+
+    // Mirrors the checks get_request makes before serving a CAS blob
+    let cas_lookup = if has_cas {
+        "served from the local cache"
+    } else if gated {
+        "not cached locally, fetched from the upstream on demand"
+    } else {
+        "no action cache entry has referenced this digest, lookups return 404"
+    };
+
+    json!({
+        "digest": digest,
+        "entries": entries,
+        "gated": gated,
+        "referenced_by": gate_references(config, digest),
+        "cas_lookup": cas_lookup,
+    })
+}
+
+fn usage(downloader: &Downloader) -> Value {
+    let partitions: Vec<Value> = downloader
+        .partition_usage()
+        .into_iter()
+        .map(|(partition, size, capacity, pinned)| {
+            json!({
+                "partition": partition,
+                "size": size,
+                "capacity": capacity,
+                "pinned": pinned,
+            })
+        })
+        .collect();
+    let (memory_size, memory_capacity) = downloader.memory_cache.usage();
+    let degraded = disk_health::degraded().map(|e| {
+        json!({
+            "reason": e.reason,
+            "since": epoch_seconds(Some(e.since)),
+        })
+    });
+    json!({
+        "partitions": partitions,
+        "memory": { "size": memory_size, "capacity": memory_capacity },
+        "degraded": degraded,
+    })
+}
+
+// Hit ratios of the policy in use, and with --eviction-stats of the others had they been
+fn eviction(downloader: &Downloader) -> Value {
+    let partitions: Vec<Value> = downloader
+        .lru_cache
+        .lock()
+        .unwrap()
+        .eviction_stats()
+        .into_iter()
+        .map(|(partition, entries, policies)| {
+            let policies: Vec<Value> = policies
+                .into_iter()
+                .map(|(policy, active, stats)| {
+                    json!({
+                        "policy": policy.name(),
+                        "active": active,
+                        "hits": stats.hits,
+                        "misses": stats.misses,
+                        "hit_ratio": stats.hit_ratio(),
+                        "bytes_hit": stats.bytes_hit,
+                        "evictions": stats.evictions,
+                        "bytes_evicted": stats.bytes_evicted,
+                    })
+                })
+                .collect();
+            json!({ "partition": partition, "entries": entries, "policies": policies })
+        })
+        .collect();
+    json!({ "partitions": partitions })
+}
+
+fn scrub() -> Value {
+    let stats = scrubber::stats();
+    let quarantined: Vec<Value> = stats
+        .quarantined
+        .iter()
+        .map(|&(ref path, ref problem)| {
+            json!({ "path": path.to_string_lossy(), "problem": problem })
+        })
+        .collect();
+    json!({
+        "passes": stats.passes,
+        "entries": stats.entries,
+        "bytes": stats.bytes,
+        "corrupt": stats.corrupt,
+        "last_pass": epoch_seconds(stats.last_pass),
+        "quarantined": quarantined,
+    })
+}
+
+fn pin_sets(downloader: &Downloader) -> Value {
+    let sets: Vec<Value> = downloader
+        .lru_cache
+        .lock()
+        .unwrap()
+        .pin_sets()
+        .into_iter()
+        .map(|(name, digests, from_pin_file)| {
+            json!({ "name": name, "digests": digests, "from_pin_file": from_pin_file })
+        })
+        .collect();
+    json!({ "sets": sets })
+}
+
+// Replace a pin set with the digests in the request body, one per line
+fn put_pin_set(req: Request<Body>, downloader: &Downloader, name: &str) -> ResponseFuture {
+    let downloader = downloader.clone();
+    let name = name.to_string();
+    Box::new(
+        req.into_body()
+            .concat2()
+            .map_err(From::from)
+            .and_then(move |body| {
+                let pinned = downloader.lru_cache.lock().unwrap().put_pin_set(
+                    &downloader.config,
+                    &name,
+                    &String::from_utf8_lossy(&body),
+                );
+                match pinned {
+                    Ok(digests) => json_response(StatusCode::OK, json!({ "digests": digests })),
+                    Err(e) => {
+                        warn!("Failed to pin {}: {}", name, e);
+                        error_response(StatusCode::BAD_REQUEST, &e)
+                    }
+                }
+            }),
+    )
+}
+
+fn remove_pin_set(downloader: &Downloader, name: &str) -> ResponseFuture {
+    let removed = downloader
+        .lru_cache
+        .lock()
+        .unwrap()
+        .remove_pin_set(&downloader.config, name);
+    match removed {
+        Ok(true) => json_response(StatusCode::OK, json!({ "removed": name })),
+        Ok(false) => Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND)),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e),
+    }
+}
+
+// Run an eviction on the blocking pool, answering with how many entries went
+fn evict<F>(downloader: &Downloader, f: F) -> ResponseFuture
+where
+    F: FnOnce(&Downloader) -> Result<usize, String> + Send + 'static,
+{
+    let downloader = downloader.clone();
+    Box::new(blocking_io::run(move || Ok(f(&downloader))).then(
+        |res: Result<Result<usize, String>, String>| match res.and_then(|e| e) {
+            Ok(removed) => json_response(StatusCode::OK, json!({ "evicted": removed })),
+            Err(e) => {
+                warn!("Admin eviction failed: {}", e);
+                error_response(StatusCode::INTERNAL_SERVER_ERROR, &e)
+            }
+        },
+    ))
+}
+
+// Handle `/admin/...` requests, these are only served when an admin token is configured.
+pub fn admin_request(
+    req: Request<Body>,
+    config: &AppConfig,
+    downloader: &Downloader,
+) -> ResponseFuture {
+    let admin_token = match config.admin_token {
+        Some(ref token) => token,
+        None => return Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND)),
+    };
+    let authorized = bearer_credential(req.headers())
+        .map(|e| constant_time_eq(e.as_bytes(), admin_token.as_bytes()))
+        .unwrap_or(false);
+    if !authorized {
+        return error_response(StatusCode::UNAUTHORIZED, "invalid admin token");
+    }
+
+    let elements: Vec<String> = req.uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .skip(1)
+        .map(|e| e.to_string())
+        .collect();
+    let elements: Vec<&str> = elements.iter().map(|e| e.as_str()).collect();
+    info!("Admin {:?} {:?}", req.method(), req.uri().path());
+
+    let method = req.method().clone();
+    match (&method, elements.as_slice()) {
+        (&Method::GET, &["entries"]) => {
+            let config = config.clone();
+            let repo = query_param(&req, "repo");
+            let digest = query_param(&req, "digest");
+            blocking_json(move || {
+                let entries = list_entries(
+                    &config,
+                    repo.as_ref().map(|e| e.as_str()),
+                    digest.as_ref().map(|e| e.as_str()),
+                );
+                json!({ "entries": entries })
+            })
+        }
+        (&Method::GET, &["entries", digest]) => {
+            let config = config.clone();
+            let digest = digest.to_string();
+            blocking_json(move || describe_digest(&config, &digest))
+        }
+        (&Method::GET, &["usage"]) => json_response(StatusCode::OK, usage(downloader)),
+        (&Method::GET, &["eviction"]) => json_response(StatusCode::OK, eviction(downloader)),
+        (&Method::GET, &["scrub"]) => json_response(StatusCode::OK, scrub()),
+        (&Method::GET, &["pins"]) => json_response(StatusCode::OK, pin_sets(downloader)),
+        (&Method::PUT, &["pins", name]) => put_pin_set(req, downloader, name),
+        (&Method::DELETE, &["pins", name]) => remove_pin_set(downloader, name),
+        (&Method::DELETE, &["entries"]) => evict(downloader, |d| d.clear(None)),
+        (&Method::DELETE, &["entries", digest]) => {
+            let digest = digest.to_string();
+            evict(downloader, move |d| d.evict_digest(&digest))
+        }
+        (&Method::DELETE, &["repos", repo]) => {
+            let repo = repo.to_string();
+            evict(downloader, move |d| d.evict_repo(&repo))
+        }
+        (&Method::GET, _) | (&Method::PUT, _) | (&Method::DELETE, _) => {
+            Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND))
+        }
+        _ => Box::new(empty_with_status_code_fut(StatusCode::METHOD_NOT_ALLOWED)),
+    }
+}
@@ -5,13 +5,21 @@ use futures::stream::Stream;
 use futures::sync::mpsc;
 use futures::Future;
 use futures::Poll;
+use http::header;
 use http::Request;
+use http::StatusCode;
 use http::Uri;
 use hyper::client::connect::Connect;
-use hyper::Client;
+use hyper::{Body, Client};
+use futures::task;
 use net::buffered_send_stream;
+use net::process_action_cache::gate_path;
+use net::upstream_misses::UpstreamMisses;
 use net::State;
+use std::collections::HashMap;
 use std::io::ErrorKind as IoErrorKind;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Instant;
@@ -23,9 +31,70 @@ use std::time::Duration;
 use std::fmt;
 use std::fs;
 
+// How long to stop uploading a type to an upstream after it refused one
+const REFUSED_UPLOAD_BACKOFF_SECS: u64 = 600;
+
+lazy_static! {
+    // Upstream and type uploads were refused for, and when
+    static ref REFUSED_UPLOADS: Mutex<HashMap<String, Instant>> = Mutex::new(HashMap::new());
+    // How many uploads of each file are waiting in the queue, their gates have to stay until
+    // the uploader gets to them
+    static ref QUEUED_UPLOADS: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+fn upload_file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or(String::new())
+}
+
+/// Whether an upload of this cache entry is queued, and still to be checked against its gate.
+pub(super) fn upload_queued(file_name: &str) -> bool {
+    QUEUED_UPLOADS.lock().unwrap().contains_key(file_name)
+}
+
+fn dequeued(path: &str) {
+    let mut queued = QUEUED_UPLOADS.lock().unwrap();
+    let file_name = upload_file_name(path);
+    let remaining = match queued.get_mut(&file_name) {
+        Some(count) => {
+            *count -= 1;
+            *count
+        }
+        None => return,
+    };
+    if remaining == 0 {
+        queued.remove(&file_name);
+    }
+}
+
+// Upload uris look like <upstream>/<type>/repo=<repo>/<digest>
+fn upload_kind(uri: &Uri) -> String {
+    let segments: Vec<&str> = uri.path().split('/').collect();
+    let tpe = segments
+        .iter()
+        .position(|e| e.starts_with("repo="))
+        .and_then(|i| if i > 0 { segments.get(i - 1) } else { None })
+        .unwrap_or(&"");
+    format!(
+        "{}/{}",
+        uri.authority_part().map(|e| e.as_str()).unwrap_or(""),
+        tpe
+    )
+}
+
+/// Whether the upstream refused uploads of this type recently, so there's no point trying.
+pub(super) fn upload_refused(uri: &Uri) -> bool {
+    let mut refused = REFUSED_UPLOADS.lock().unwrap();
+    refused.retain(|_, since| since.elapsed() < Duration::from_secs(REFUSED_UPLOAD_BACKOFF_SECS));
+    refused.contains_key(&upload_kind(uri))
+}
+
 struct UploadRequest {
     uri: Uri,
     path: String,
+    maximum_upload_size: u64,
     should_upload: Option<Box<Fn() -> bool + Send>>,
 }
 
@@ -56,6 +125,7 @@ impl RequestUpload {
         self: &Self,
         uri: &Uri,
         path: &String,
+        maximum_upload_size: u64,
         should_upload: Box<Fn() -> bool + Send>,
     ) -> Result<(), String> {
         let uploader = self.0.lock().map_err(|e| e.to_string())?;
@@ -63,29 +133,106 @@ impl RequestUpload {
             .unbounded_send(UploadRequest {
                 uri: uri.clone(),
                 path: path.clone(),
+                maximum_upload_size: maximum_upload_size,
                 should_upload: Some(should_upload),
             })
-            .map_err(|e| e.to_string())
+            .map_err(|e| e.to_string())?;
+        *QUEUED_UPLOADS
+            .lock()
+            .unwrap()
+            .entry(upload_file_name(path))
+            .or_insert(0) += 1;
+        Ok(())
     }
 }
 
+// Uploads we didn't get to before shutting down, picked back up on the next start
+fn pending_uploads_path(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("pending_uploads")
+}
+
+// CAS uploads are only wanted once we've seen an action cache entry referencing them
+pub(super) fn gated_upload_check(config: &AppConfig, path: &str) -> Box<Fn() -> bool + Send> {
+    let file_name = upload_file_name(path);
+    if file_name.starts_with("cas__") {
+        let gate = gate_path(config, file_name.trim_left_matches("cas__"));
+        Box::new(move || gate.is_file())
+    } else {
+        Box::new(|| true)
+    }
+}
+
+fn persist_uploads(config: &AppConfig, uploads: &[(Uri, String, u64)]) -> Result<(), String> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(pending_uploads_path(config))
+        .map_err(|e| e.to_string())?;
+    for &(ref uri, ref path, maximum_upload_size) in uploads {
+        writeln!(file, "{} {} {}", maximum_upload_size, uri, path).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn restore_uploads(config: &AppConfig, request_upload: &RequestUpload) -> Result<usize, String> {
+    let pending_path = pending_uploads_path(config);
+    let file = match fs::File::open(&pending_path) {
+        Ok(file) => file,
+        Err(_) => return Ok(0),
+    };
+
+    let mut restored = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        let parts: Vec<&str> = line.splitn(3, ' ').collect();
+        if parts.len() != 3 {
+            continue;
+        }
+        match (parts[0].parse::<u64>(), parts[1].parse::<Uri>()) {
+            (Ok(maximum_upload_size), Ok(uri)) => {
+                let path = parts[2].to_string();
+                request_upload.upload(
+                    &uri,
+                    &path,
+                    maximum_upload_size,
+                    gated_upload_check(config, &path),
+                )?;
+                restored += 1;
+            }
+            _ => warn!("Skipping malformed pending upload: {:?}", line),
+        }
+    }
+    fs::remove_file(&pending_path).map_err(|e| e.to_string())?;
+    Ok(restored)
+}
+
 pub(super) fn start_uploader<C: Connect + 'static>(
     config: &AppConfig,
     http_client: &Client<C>,
     state: &Arc<Mutex<State>>,
+    upstream_misses: &UpstreamMisses,
 ) -> (Box<Future<Item = (), Error = ()> + Send>, RequestUpload) {
     // Create a channel for this peer
     let (tx, rx) = mpsc::unbounded();
+    let request_upload = RequestUpload(Arc::new(Mutex::new(tx)));
+
+    match restore_uploads(config, &request_upload) {
+        Ok(0) => (),
+        Ok(restored) => info!("Restored {} uploads pending from before shutdown", restored),
+        Err(e) => warn!("Failed to restore pending uploads: {}", e),
+    }
 
     (
         Box::new(Uploader {
             client: http_client.clone(),
             active_future: None,
+            active_upload: None,
             rx: rx,
             config: config.clone(),
             state: Arc::clone(state),
+            upstream_misses: upstream_misses.clone(),
         }),
-        RequestUpload(Arc::new(Mutex::new(tx))),
+        request_upload,
     )
 }
 
@@ -96,6 +243,9 @@ struct Uploader<C> {
     /// this is the active worker future that might be complete
     active_future: Option<Box<Future<Item = (), Error = String> + Send + 'static>>,
 
+    /// The upload `active_future` is running, if it isn't just waiting on a delay
+    active_upload: Option<(Uri, String, u64)>,
+
     /// Receive half of the message channel.
     ///
     /// This is used to receive messages from peers. When a message is received
@@ -107,19 +257,32 @@ struct Uploader<C> {
 
     /// Shared states between clients and services
     state: Arc<Mutex<State>>,
+
+    /// Forgotten once we've uploaded them, the upstream has them now
+    upstream_misses: UpstreamMisses,
 }
 
 impl<C> Uploader<C> {
-    fn should_upload(&self, path: &String) -> bool {
+    // Out of time while shutting down, save whatever is left for the next run
+    fn persist_remaining(&mut self) {
+        let mut remaining: Vec<(Uri, String, u64)> = self.active_upload.take().into_iter().collect();
+        while let Ok(Async::Ready(Some(u))) = self.rx.poll() {
+            remaining.push((u.uri, u.path, u.maximum_upload_size));
+        }
+        match persist_uploads(&self.config, &remaining) {
+            Ok(_) => info!("Persisted {} pending uploads", remaining.len()),
+            Err(e) => error!("Failed to persist {} pending uploads: {}", remaining.len(), e),
+        }
+    }
+
+    fn should_upload(&self, path: &String, maximum_upload_size: u64) -> bool {
         let metadata = match fs::metadata(&path) {
             Ok(meta) => meta,
             Err(e) => {
                 match e.kind() {
+                    // Evicted before we got to it
                     IoErrorKind::NotFound => {
-                        error!(
-                            "Should never reach here, file not found looking for {:?}",
-                            path
-                        );
+                        info!("{:?} is no longer cached, not uploading it", path);
                     }
                     IoErrorKind::PermissionDenied => {
                         error!("Permissions error accessing file for upload {:?}", path);
@@ -132,7 +295,7 @@ impl<C> Uploader<C> {
             }
         };
         // Build response headers.
-        metadata.len() <= self.config.maximum_upload_size
+        metadata.len() <= maximum_upload_size
     }
 }
 
@@ -144,10 +307,37 @@ where
     type Error = ();
 
     fn poll(&mut self) -> Poll<(), ()> {
+        let (time_since_last_req, shutdown_deadline, drain_uploads) = {
+            let mut s = self.state.lock().unwrap();
+            s.uploader_task = Some(task::current());
+            (
+                s.last_user_facing_request.elapsed(),
+                s.shutdown_deadline,
+                s.drain_uploads,
+            )
+        };
+
+        if let Some(deadline) = shutdown_deadline {
+            if Instant::now() >= deadline {
+                self.persist_remaining();
+                return Ok(Async::Ready(()));
+            }
+            // No need to keep waiting for activity to die down
+            if self.active_upload.is_none() {
+                self.active_future = None;
+            }
+        }
+
         // Receive all messages from peers.
         match &mut self.active_future {
             Some(fut) => match fut.poll() {
-                Ok(Async::Ready(_)) => (),
+                Ok(Async::Ready(_)) => {
+                    if let Some((_, ref path, _)) = self.active_upload {
+                        if let Some(file_name) = Path::new(path).file_name() {
+                            self.upstream_misses.forget(&file_name.to_string_lossy());
+                        }
+                    }
+                }
                 Err(e) => {
                     warn!("Failed to poll active future with {:?}", e);
                 }
@@ -156,12 +346,9 @@ where
             None => (),
         };
         self.active_future = None;
+        self.active_upload = None;
 
-        let time_since_last_req = {
-            let s = self.state.lock().unwrap();
-            s.last_user_facing_request.elapsed()
-        };
-        if time_since_last_req < Duration::from_millis(1000 * 10) {
+        if shutdown_deadline.is_none() && time_since_last_req < Duration::from_millis(1000 * 10) {
             info!("Uploader will not action requests, due to recency of client activity: {:?} seconds ago.", time_since_last_req.as_secs());
             self.active_future = Some(Box::new(
                 Delay::new(Instant::now() + Duration::from_millis(1000 * 5))
@@ -173,6 +360,10 @@ where
         }
 
         let mut upload_request: UploadRequest = match self.rx.poll().unwrap() {
+            Async::NotReady if drain_uploads => {
+                info!("Background uploader drained");
+                return Ok(Async::Ready(()));
+            }
             Async::NotReady => {
                 info!("Background uploader returning to idle");
                 return Ok(Async::NotReady);
@@ -185,7 +376,9 @@ where
         };
 
         let mut should_upload = true;
-        if !upload_request.should_upload.take().unwrap()() {
+        let passed_gate = upload_request.should_upload.take().unwrap()();
+        dequeued(&upload_request.path);
+        if !passed_gate {
             info!(
                 "Aborting upload of {:?} Unknown cas upload, didn't see action cache info",
                 upload_request.path
@@ -193,7 +386,7 @@ where
             should_upload = false;
         }
 
-        if !self.should_upload(&upload_request.path) {
+        if !self.should_upload(&upload_request.path, upload_request.maximum_upload_size) {
             info!(
                 "Aborting upload of {:?} accessibilty issues, too large",
                 upload_request.path
@@ -201,15 +394,29 @@ where
             should_upload = false;
         }
 
+        if should_upload && upload_refused(&upload_request.uri) {
+            debug!(
+                "Skipping upload of {:?}, upstream refused this type recently",
+                upload_request.path
+            );
+            should_upload = false;
+        }
+
         if should_upload {
             {
                 let mut locked = self.state.lock().unwrap();
                 locked.last_background_upload = Instant::now();
             }
+            self.active_upload = Some((
+                upload_request.uri.clone(),
+                upload_request.path.clone(),
+                upload_request.maximum_upload_size,
+            ));
             self.active_future = Some(run_upload_file(
                 self.client.clone(),
                 upload_request.uri,
                 upload_request.path,
+                self.config.upstream_credential(),
             ));
         }
 
@@ -222,6 +429,7 @@ pub(super) fn raw_upload_file<C: Connect + 'static>(
     http_client: Client<C>,
     uri: Uri,
     path: String,
+    credential: Option<String>,
 ) -> Box<Future<Item = (), Error = String> + Send + 'static> {
     info!("Uploading {} to {:?}", path, uri);
     let body = match buffered_send_stream::send_file(&path) {
@@ -230,16 +438,54 @@ pub(super) fn raw_upload_file<C: Connect + 'static>(
             return Box::new(futures::future::err(e).map_err(|e: ::std::io::Error| e.to_string()))
         }
     };
+    upload_body(http_client, uri, body, credential)
+}
 
-    let http_payload = http_client.request(Request::put(uri.clone()).body(body).unwrap());
+// PUT a body to an upstream, whether it's read from the cache or forwarded from a client
+pub(super) fn upload_body<C: Connect + 'static>(
+    http_client: Client<C>,
+    uri: Uri,
+    body: Body,
+    credential: Option<String>,
+) -> Box<Future<Item = (), Error = String> + Send + 'static> {
+    if upload_refused(&uri) {
+        return Box::new(futures::future::err(format!(
+            "Upstream refused this type of upload recently, skipping {:?}",
+            uri
+        )));
+    }
+    let mut request = Request::put(uri.clone());
+    if let Some(token) = credential {
+        request.header(header::AUTHORIZATION, format!("Bearer {}", token).as_str());
+    }
+    let http_payload = http_client.request(request.body(body).unwrap());
 
-    Box::new(http_payload.map_err(|e| e.to_string()).map(|_| ()))
+    Box::new(
+        http_payload
+            .map_err(|e| e.to_string())
+            .and_then(move |resp| match resp.status() {
+                status if status.is_success() => Ok(()),
+                StatusCode::FORBIDDEN => {
+                    // Callers log the error, so only the first refusal shows up
+                    REFUSED_UPLOADS
+                        .lock()
+                        .unwrap()
+                        .insert(upload_kind(&uri), Instant::now());
+                    Err(format!(
+                        "Upstream refused upload to {:?}, not permitted, not uploading this type there for {} seconds",
+                        uri, REFUSED_UPLOAD_BACKOFF_SECS
+                    ))
+                }
+                status => Err(format!("Upstream returned {} for upload to {:?}", status, uri)),
+            }),
+    )
 }
 
-fn run_upload_file<C: Connect + 'static>(
+pub(super) fn run_upload_file<C: Connect + 'static>(
     http_client: Client<C>,
     uri: Uri,
     path: String,
+    credential: Option<String>,
 ) -> Box<Future<Item = (), Error = String> + Send + 'static> {
     info!("Maybe uploading {} to {:?}", path, uri);
     let resp_uri = uri.clone();
@@ -263,7 +509,7 @@ fn run_upload_file<C: Connect + 'static>(
                     Either::A(futures::future::ok(()))
                 } else {
                     Either::B(
-                        raw_upload_file(http_client, uri, path)
+                        raw_upload_file(http_client, uri, path, credential)
                             .map_err(|e| {
                                 warn!("Error in upload: {:?}", e);
                             })
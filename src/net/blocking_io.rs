@@ -0,0 +1,162 @@
+use bytes::BytesMut;
+use futures::future::Either;
+use futures::{future, Async, Future, Poll, Stream};
+use futures_cpupool::{Builder, CpuFuture, CpuPool};
+use hex::ToHex;
+use hyper::Chunk;
+use net::disk_health::disk_error;
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+// Reads and writes are this large, so a big blob is a few hundred trips to the pool, not millions
+pub const BUFFER_SIZE: usize = 256 * 1024;
+
+// Disk IO isn't CPU bound, so this is about how many slow reads we'll wait on at once
+const POOL_SIZE: usize = 16;
+
+lazy_static! {
+    static ref POOL: CpuPool = Builder::new()
+        .pool_size(POOL_SIZE)
+        .name_prefix("blocking-io-")
+        .create();
+}
+
+/// Run blocking file work off the event loop, so a slow disk only stalls the request waiting on it.
+pub fn run<F, T, E>(f: F) -> CpuFuture<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    POOL.spawn_fn(f)
+}
+
+enum ReadState {
+    Idle(File, BytesMut),
+    Reading(CpuFuture<(File, BytesMut, Chunk), io::Error>),
+    Done,
+}
+
+/// A file's contents as chunks, each read on the pool.
+pub struct FileChunkStream {
+    state: ReadState,
+}
+
+impl FileChunkStream {
+    pub fn new(file: File) -> FileChunkStream {
+        FileChunkStream {
+            state: ReadState::Idle(file, BytesMut::with_capacity(BUFFER_SIZE)),
+        }
+    }
+}
+
+// Chunks share `buf`'s allocation, which is reused once hyper is done sending them
+fn read_chunk(mut file: File, mut buf: BytesMut) -> io::Result<(File, BytesMut, Chunk)> {
+    buf.reserve(BUFFER_SIZE);
+    buf.resize(BUFFER_SIZE, 0);
+    let size = file.read(&mut buf)?;
+    buf.truncate(size);
+    let chunk = Chunk::from(buf.take().freeze());
+    Ok((file, buf, chunk))
+}
+
+impl Stream for FileChunkStream {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        loop {
+            self.state = match ::std::mem::replace(&mut self.state, ReadState::Done) {
+                ReadState::Idle(file, buf) => {
+                    ReadState::Reading(run(move || read_chunk(file, buf)))
+                }
+                ReadState::Reading(mut reading) => match reading.poll()? {
+                    Async::NotReady => {
+                        self.state = ReadState::Reading(reading);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready((_, _, ref chunk)) if chunk.is_empty() => {
+                        return Ok(Async::Ready(None))
+                    }
+                    Async::Ready((file, buf, chunk)) => {
+                        self.state = ReadState::Idle(file, buf);
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+                },
+                ReadState::Done => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// What `write_stream` wrote.
+pub struct Written {
+    pub len: u64,
+    pub sha256: String,
+}
+
+/// Write a stream out to `path` on the pool, gathering chunks into large writes, and sync it to
+/// disk so it's safe to commit to the cache once this resolves.
+pub fn write_stream<S>(
+    path: PathBuf,
+    stream: S,
+) -> Box<Future<Item = Written, Error = String> + Send>
+where
+    S: Stream + Send + 'static,
+    S::Item: AsRef<[u8]>,
+    S::Error: ToString,
+{
+    let path2 = path.clone();
+    Box::new(
+        run(move || File::create(&path).map_err(disk_error))
+            .and_then(move |file| {
+                stream.map_err(|e| e.to_string()).fold(
+                    (file, Sha256::default(), Vec::with_capacity(BUFFER_SIZE), 0),
+                    |(file, hasher, mut buf, written), chunk| {
+                        buf.extend_from_slice(chunk.as_ref());
+                        let written = written + chunk.as_ref().len() as u64;
+                        if buf.len() < BUFFER_SIZE {
+                            Either::A(future::ok((file, hasher, buf, written)))
+                        } else {
+                            let write = write_buffer(file, hasher, buf);
+                            Either::B(write.map(move |(file, hasher, buf)| {
+                                (file, hasher, buf, written)
+                            }))
+                        }
+                    },
+                )
+            })
+            .and_then(|(file, hasher, buf, written)| {
+                write_buffer(file, hasher, buf).and_then(move |(file, hasher, _)| {
+                    run(move || {
+                        file.sync_all().map_err(disk_error)?;
+                        Ok(Written {
+                            len: written,
+                            sha256: hasher.result().as_slice().to_hex(),
+                        })
+                    })
+                })
+            })
+            .map_err(move |e| {
+                // Nothing should be left that looks like it could be used
+                fs::remove_file(&path2).unwrap_or(());
+                format!("Failed writing {:?}: {}", path2, e)
+            }),
+    )
+}
+
+// Hands back the emptied buffer to fill again
+fn write_buffer(
+    mut file: File,
+    mut hasher: Sha256,
+    mut buf: Vec<u8>,
+) -> CpuFuture<(File, Sha256, Vec<u8>), String> {
+    run(move || {
+        file.write_all(&buf).map_err(disk_error)?;
+        hasher.input(&buf);
+        buf.clear();
+        Ok((file, hasher, buf))
+    })
+}
@@ -0,0 +1,177 @@
+use net::disk_health::disk_error;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// Every entry committed as of the last checkpoint
+const MANIFEST: &str = ".manifest";
+// Entries committed and removed since
+const JOURNAL: &str = ".journal";
+
+// Checkpoint once the journal has this many records per entry we hold, so replaying it stays quick
+const RECORDS_PER_ENTRY: usize = 4;
+const MIN_RECORDS: usize = 10000;
+
+/// An entry as it was when committed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Committed {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// What a cache folder held when it last committed or removed anything.
+pub struct Recorded {
+    pub entries: HashMap<String, Committed>,
+    // Committed since the last checkpoint, so possibly in flight when we stopped
+    pub recent: HashSet<String>,
+    // Whether the folder had a manifest or journal at all
+    pub existed: bool,
+}
+
+/// An append only record of the entries committed to a cache folder, with a manifest of them
+/// all written at each checkpoint. An entry is only committed once its file is synced and in
+/// place, so anything on disk without a matching record is partial or unknown.
+pub struct Journal {
+    root: PathBuf,
+    file: File,
+    records: usize,
+}
+
+// `+ <key> <size> <sha256>` on commit, `- <key>` on removal
+fn replay(path: &Path, entries: &mut HashMap<String, Committed>, recent: &mut HashSet<String>) -> usize {
+    let file = match File::open(path) {
+        Ok(e) => e,
+        Err(_) => return 0,
+    };
+    let mut records = 0;
+    for line in BufReader::new(file).lines() {
+        let line = match line {
+            Ok(e) => e,
+            Err(_) => break,
+        };
+        let parts: Vec<&str> = line.split(' ').collect();
+        match parts.as_slice() {
+            &["+", key, size, sha256] => match size.parse() {
+                Ok(size) => {
+                    entries.insert(
+                        key.to_string(),
+                        Committed {
+                            size: size,
+                            sha256: sha256.to_string(),
+                        },
+                    );
+                    recent.insert(key.to_string());
+                }
+                // A record cut short by a crash, nothing after it was written either
+                Err(_) => break,
+            },
+            &["-", key] => {
+                entries.remove(key);
+                recent.remove(key);
+            }
+            _ => break,
+        }
+        records += 1;
+    }
+    records
+}
+
+fn sync_dir(path: &Path) -> Result<(), String> {
+    File::open(path)
+        .and_then(|e| e.sync_all())
+        .map_err(|e| format!("Failed to sync {:?}: {}", path, e))
+}
+
+impl Journal {
+    pub fn open(root: &Path) -> Result<(Journal, Recorded), String> {
+        let manifest_path = root.join(MANIFEST);
+        let journal_path = root.join(JOURNAL);
+        let existed = manifest_path.exists() || journal_path.exists();
+
+        let mut entries = HashMap::new();
+        let mut recent = HashSet::new();
+        replay(&manifest_path, &mut entries, &mut recent);
+        recent.clear();
+        let records = replay(&journal_path, &mut entries, &mut recent);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .map_err(|e| format!("Failed to open {:?}: {}", journal_path, e))?;
+        let journal = Journal {
+            root: root.to_path_buf(),
+            file: file,
+            records: records,
+        };
+        Ok((
+            journal,
+            Recorded {
+                entries: entries,
+                recent: recent,
+                existed: existed,
+            },
+        ))
+    }
+
+    // For a folder we no longer keep a cache in, so using it again later starts afresh
+    pub fn discard(root: &Path) {
+        for name in &[MANIFEST, JOURNAL] {
+            fs::remove_file(root.join(name)).unwrap_or(());
+        }
+    }
+
+    // Durable before we return, the entry can be served from then on
+    pub fn commit(&mut self, key: &str, committed: &Committed) -> Result<(), String> {
+        self.append(&format!("+ {} {} {}\n", key, committed.size, committed.sha256))?;
+        self.file
+            .sync_data()
+            .map_err(|e| format!("Failed to sync the cache journal: {}", disk_error(e)))
+    }
+
+    // Not synced, losing it only means finding the entry missing on recovery
+    pub fn remove(&mut self, key: &str) -> Result<(), String> {
+        self.append(&format!("- {}\n", key))
+    }
+
+    fn append(&mut self, record: &str) -> Result<(), String> {
+        self.records += 1;
+        self.file
+            .write_all(record.as_bytes())
+            .map_err(|e| format!("Failed to write the cache journal: {}", disk_error(e)))
+    }
+
+    pub fn needs_checkpoint(&self, entries: usize) -> bool {
+        self.records > MIN_RECORDS.max(entries * RECORDS_PER_ENTRY)
+    }
+
+    /// Replace the manifest with these entries and start an empty journal.
+    pub fn checkpoint(&mut self, entries: &HashMap<String, Committed>) -> Result<(), String> {
+        let manifest_path = self.root.join(MANIFEST);
+        let tmp_path = self.root.join(format!("{}.tmp", MANIFEST));
+        {
+            let mut manifest = File::create(&tmp_path)
+                .map_err(|e| format!("Failed to create {:?}: {}", tmp_path, disk_error(e)))?;
+            let mut contents = String::new();
+            for (key, committed) in entries.iter() {
+                contents.push_str(&format!("+ {} {} {}\n", key, committed.size, committed.sha256));
+            }
+            manifest
+                .write_all(contents.as_bytes())
+                .and_then(|_| manifest.sync_all())
+                .map_err(|e| format!("Failed to write {:?}: {}", tmp_path, disk_error(e)))?;
+        }
+        fs::rename(&tmp_path, &manifest_path)
+            .map_err(|e| format!("Failed to replace {:?}: {}", manifest_path, e))?;
+        sync_dir(&self.root)?;
+
+        // Only now the manifest has everything can the journal go
+        self.file
+            .set_len(0)
+            .and_then(|_| self.file.sync_all())
+            .map_err(|e| format!("Failed to truncate the cache journal: {}", e))?;
+        self.records = 0;
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use futures::Poll;
 use futures::{Future, Stream};
 use http::header;
+use http::HeaderMap;
 use http::Response;
 use hyper::client::connect::Connect;
 use hyper::client::Client;
@@ -17,14 +18,18 @@ use tokio::prelude::*;
 use std::time::{Duration, Instant};
 use tokio::timer::Delay;
 
+// `headers` are added to the GET, e.g. to make it conditional
 pub fn connect_for_file<C: Connect + 'static>(
     http_client: Client<C>,
     uri: Uri,
+    headers: HeaderMap,
     tries: i32,
     sleep_duration: Duration,
     multiplier: u32,
 ) -> Box<Future<Item = Response<Body>, Error = String> + Send + 'static> {
-    let http_response = http_client.request(Request::get(uri.clone()).body(Body::empty()).unwrap());
+    let mut request = Request::get(uri.clone()).body(Body::empty()).unwrap();
+    request.headers_mut().extend(headers.clone());
+    let http_response = http_client.request(request);
     let when = Instant::now() + Duration::from_millis(1000 * 5);
     let task = Delay::new(when);
 
@@ -53,6 +58,7 @@ pub fn connect_for_file<C: Connect + 'static>(
                     connect_for_file(
                         http_client,
                         uri,
+                        headers,
                         tries - 1,
                         sleep_duration * multiplier,
                         multiplier,
@@ -1,39 +1,67 @@
+use bytes::Bytes;
+use net::ac_freshness;
+use net::blocking_io;
+use net::memory_cache::MemoryCache;
+use net::denylist::{start_denylist_refresh, Denylist};
+use net::disk_health;
+use net::access_log::{log_response, AccessLog, Outcome, RequestLog};
 use net::proxy_request::ProxyRequest;
+use net::scrubber::start_scrubber;
 use net::server_error::ServerError;
 use net::server_io::empty_with_status_code;
 use net::server_io::empty_with_status_code_fut;
-use net::server_io::send_file;
+use net::server_io::health_request;
+use net::server_io::{into_response_body, open_cached, send_file, send_open_file};
+use net::server_start::activated_listener;
+use net::server_start::start_activated_server_impl;
 use net::server_start::start_http_server_impl;
 use net::server_start::start_unix_server_impl;
-use net::state::State;
+use net::state::{InFlight, State};
+use net::upstream_misses::UpstreamMisses;
 use std::time::Duration;
 
 use hyper::Client;
 
 use config::AppConfig;
+use config::UpstreamConfig;
 use hyper;
 use hyper::body::Payload;
 use hyper::service::service_fn;
-use std::io;
+use std::io::{self, Read};
 
 use futures;
+use futures::future::Either;
+use futures::sync::oneshot;
 use futures::Future;
+use futures::Stream;
 use hyper::client::connect::Connect;
+use hyper::client::HttpConnector;
 use hyper::service::NewService;
 use hyper::Server;
 use hyper::Uri as HyperUri;
+use http::header::{self, HeaderValue};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use net::background_uploader::RequestUpload;
-use net::downloader::Downloader;
-use net::process_action_cache::{process_action_cache_response, process_existing_action_caches};
+use net::admin::admin_request;
+use net::background_uploader::{
+    gated_upload_check, run_upload_file, upload_body, upload_refused, RequestUpload,
+};
+use net::downloader::{Downloader, Revalidation};
+use net::peer_discovery::{start_peer_discovery, Peers};
+use net::shutdown::{graceful_shutdown, shutdown_signal};
+use net::process_action_cache::{
+    parse_action_result, process_action_cache_response, process_existing_action_caches,
+    referenced_cas_digests,
+};
 use std::error::Error;
-use std::fs;
-use std::path::Path;
+use std::fs::{self, File};
 use std::result::Result;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Instant;
 
+// How many LAN peers we ask at once for a CAS blob we don't have
+const PEERS_PER_LOOKUP: usize = 3;
+
 type ResponseFuture = Box<Future<Item = Response<Body>, Error = ServerError> + Send>;
 
 fn current_file_size(path: &str) -> Option<u64> {
@@ -50,30 +78,262 @@ fn duration_to_float_seconds(d: Duration) -> f64 {
     f + d.as_secs() as f64
 }
 
+fn memory_response(contents: Bytes) -> Response<Body> {
+    let len = contents.len() as u64;
+    let mut res = Response::new(Body::from(contents));
+    res.headers_mut()
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(len));
+    res
+}
+
+// Small entries are read in one go, and kept in memory for next time
+fn send_from_memory(
+    memory_cache: MemoryCache,
+    partition: String,
+    file_name: String,
+    mut file: File,
+) -> ResponseFuture {
+    Box::new(
+        blocking_io::run(move || {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).map(|_| contents)
+        })
+            .map(move |contents| {
+                let contents = Bytes::from(contents);
+                memory_cache.insert(&partition, &file_name, contents.clone());
+                memory_response(contents)
+            })
+            .map_err(From::from),
+    )
+}
+
+// Where we found what a GET asked for
+enum Found {
+    // In our cache, this many bytes
+    Cached(u64),
+    // Still arriving from the upstream, sent on as it arrives and cached unless passing through
+    Streaming(u64, Body),
+}
+
+// Try each upstream in order, falling through to the next on a miss or failure. Without
+// caching, e.g. while the cache disk is failing, entries are passed straight through.
+fn fetch_from_upstreams<C: Connect + 'static>(
+    downloader: Downloader,
+    http_client: Client<C>,
+    mut upstream_queries: Vec<(UpstreamConfig, HyperUri)>,
+    repo: String,
+    file_name: String,
+    caching: bool,
+    request_log: RequestLog,
+) -> Box<Future<Item = Option<Found>, Error = String> + Send> {
+    if upstream_queries.is_empty() {
+        return Box::new(futures::future::ok(None));
+    }
+    let (upstream, query_uri) = upstream_queries.remove(0);
+    let is_last = upstream_queries.is_empty();
+    // Action cache entries are small, and need to be processed once cached before we answer
+    let fetch_fut: Box<Future<Item = Option<Found>, Error = String> + Send> = if !caching {
+        Box::new(
+            downloader
+                .pass_through_file(&http_client, &upstream, &query_uri, &request_log)
+                .map(|e| e.map(|(len, body)| Found::Streaming(len, body))),
+        )
+    } else if downloader.config.tee_downloads && file_name.starts_with("cas__") {
+        Box::new(
+            downloader
+                .tee_file(
+                    &http_client,
+                    &upstream,
+                    &query_uri,
+                    &repo,
+                    &file_name,
+                    &request_log,
+                )
+                .map(|e| e.map(|(len, body)| Found::Streaming(len, body))),
+        )
+    } else {
+        Box::new(
+            downloader
+                .fetch_file(
+                    &http_client,
+                    &upstream,
+                    &query_uri,
+                    &repo,
+                    &file_name,
+                    &request_log,
+                )
+                .map(|e| e.map(Found::Cached)),
+        )
+    };
+    Box::new(fetch_fut.then(move |res| {
+        let caching = match res {
+            Ok(Some(found)) => {
+                request_log.set_outcome(if caching {
+                    Outcome::UpstreamHit
+                } else {
+                    Outcome::PassedThrough
+                });
+                return Either::A(futures::future::ok(Some(found)));
+            }
+            Ok(None) => caching,
+            // The cache disk failed us part way, the upstream can still give it to the client
+            Err(ref e) if caching && disk_health::degraded().is_some() => {
+                warn!(
+                    "Failed caching from upstream {:?}, passing it through: {:?}",
+                    query_uri, e
+                );
+                upstream_queries.insert(0, (upstream, query_uri));
+                false
+            }
+            Err(e) => {
+                if is_last {
+                    return Either::A(futures::future::err(e));
+                }
+                warn!(
+                    "Failed fetching from upstream {:?}, trying next: {:?}",
+                    query_uri, e
+                );
+                // Unless a later upstream has it, we can't say it's missing
+                request_log.set_outcome(Outcome::Error);
+                caching
+            }
+        };
+        Either::B(fetch_from_upstreams(
+            downloader,
+            http_client,
+            upstream_queries,
+            repo,
+            file_name,
+            caching,
+            request_log,
+        ))
+    }))
+}
+
+// Ask a few LAN peers for a CAS blob at once, taking whichever has it first and dropping the
+// rest, resolving to None if none of them have it. Bounded so a slow or bogus peer costs a miss
+// one peer's timeout, not one per peer.
+fn fetch_from_peers(
+    downloader: Downloader,
+    peer_client: Client<HttpConnector>,
+    peer_uris: Vec<HyperUri>,
+    repo: String,
+    file_name: String,
+    digest: String,
+    request_log: RequestLog,
+) -> Box<Future<Item = Option<u64>, Error = String> + Send> {
+    if peer_uris.is_empty() {
+        return Box::new(futures::future::ok(None));
+    }
+    let attempts: Vec<Box<Future<Item = u64, Error = String> + Send>> = peer_uris
+        .into_iter()
+        .take(PEERS_PER_LOOKUP)
+        .map(|uri| {
+            let err_uri = uri.clone();
+            let attempt: Box<Future<Item = u64, Error = String> + Send> = Box::new(
+                downloader
+                    .fetch_peer_file(&peer_client, &uri, &repo, &file_name, &digest, &request_log)
+                    .then(move |res| match res {
+                        Ok(Some(len)) => Ok(len),
+                        Ok(None) => Err(format!("{:?} doesn't have it", err_uri)),
+                        Err(e) => {
+                            info!("Failed fetching from peer {:?}: {:?}", err_uri, e);
+                            Err(e)
+                        }
+                    }),
+            );
+            attempt
+        })
+        .collect();
+    Box::new(
+        futures::future::select_ok(attempts).then(move |res| match res {
+            Ok((len, _)) => {
+                request_log.set_outcome(Outcome::PeerHit);
+                Ok(Some(len))
+            }
+            Err(_) => Ok(None),
+        }),
+    )
+}
+
+// Serve a CAS blob to a LAN peer straight from our local cache, never going upstream.
+fn peer_request(req: Request<Body>, config: &AppConfig) -> ResponseFuture {
+    let elements: Vec<&str> = req.uri().path().trim_matches('/').split('/').collect();
+    if elements.len() != 3 || elements[1] != "cas" {
+        return Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND));
+    }
+
+    match config.find_cached(&format!("cas__{}", elements[2])) {
+        Some(data_source_path) => {
+            debug!("Serving {:?} to LAN peer", data_source_path);
+            send_file(data_source_path.to_str().unwrap().to_string())
+        }
+        None => Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND)),
+    }
+}
+
 fn get_request<C: Connect + 'static>(
     instant: Instant,
     req: Request<Body>,
     downloader: &Downloader,
     http_client: &Client<C>,
+    peer_client: &Client<HttpConnector>,
+    peers: &Peers,
+    upstream_misses: &UpstreamMisses,
+    denylist: &Denylist,
     config: &AppConfig,
+    request_log: RequestLog,
 ) -> ResponseFuture {
     info!("Start Get request to {:?}", req.uri());
     let proxy_request = ProxyRequest::new(req.uri());
+    request_log.set_request(&proxy_request);
 
     let file_name = proxy_request.file_name();
+
+    if denylist.contains(&proxy_request.digest) {
+        warn!("Refusing to serve denylisted {:?}", file_name);
+        request_log.set_outcome(Outcome::Denied);
+        return Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND));
+    }
+
+    let partition = config.partition_for(&proxy_request.repo);
+    let keep_in_memory = ac_freshness::memory_servable(config, &file_name);
+    if keep_in_memory {
+        if let Some(contents) = downloader.memory_cache.get(&partition, &file_name) {
+            downloader.record_lookup(&partition, &file_name, true);
+            request_log.set_outcome(Outcome::LocalHit);
+            return Box::new(futures::future::ok(memory_response(contents)));
+        }
+    }
+    let memory_cache = downloader.memory_cache.clone();
+    let memory_file_name = file_name.clone();
     let file_name2 = file_name.clone();
 
-    let data_source_path = Path::new(&config.cache_folder).join(&file_name);
+    let data_source_path = config.cache_path(&proxy_request.repo, &file_name);
     let data_source_path2 = data_source_path.clone();
+    let repo = proxy_request.repo.clone();
+    let repo2 = proxy_request.repo.clone();
     let downloader = downloader.clone();
     let http_client = http_client.clone();
+    let peer_client = peer_client.clone();
     let path = req.uri().path().to_string().clone();
 
     let cfg2 = config.clone();
 
+    let peer_uris: Vec<HyperUri> = if file_name.starts_with("cas__") {
+        peers
+            .current()
+            .iter()
+            .filter_map(|peer| proxy_request.build_peer_uri(peer).ok())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let digest = proxy_request.digest.clone();
+
     if file_name.starts_with("cas__") {
         let gate_file = current_file_size(&format!("{}/enable_{}", config.cache_folder, file_name));
-        let already_present = current_file_size(&format!("{}/{}", config.cache_folder, file_name));
+        let already_present = current_file_size(data_source_path.to_str().unwrap());
         match already_present.or(gate_file) {
             None => {
                 // file we never saw in an action cache message, pretend it doesn't exist.
@@ -81,6 +341,7 @@ fn get_request<C: Connect + 'static>(
                     "Pretending target doesn't exist {:?}, returning 404",
                     file_name
                 );
+                request_log.set_outcome(Outcome::Gated);
                 let mut res = Response::new(Body::empty());
                 *res.status_mut() = StatusCode::NOT_FOUND;
                 return Box::new(futures::future::ok(res));
@@ -89,45 +350,157 @@ fn get_request<C: Connect + 'static>(
         }
     }
 
+    let on_disk = current_file_size(data_source_path.to_str().unwrap()).is_some();
+    downloader.record_lookup(&partition, &file_name, on_disk);
+
+    if !on_disk && upstream_misses.contains(&proxy_request.repo, &file_name)
+    {
+        debug!("Upstream recently didn't have {:?}, returning 404", file_name);
+        request_log.set_outcome(Outcome::KnownMiss);
+        return Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND));
+    }
+    let fresh = ac_freshness::is_fresh(config, &proxy_request.repo, &file_name);
+    let upstream_misses = upstream_misses.clone();
+    let missing_repo = proxy_request.repo.clone();
+    let missing_file_name = file_name.clone();
+
+    let upstream_queries: Result<Vec<(UpstreamConfig, HyperUri)>, ServerError> = config
+        .upstreams_for(&proxy_request.repo)
+        .into_iter()
+        .map(|upstream| {
+            proxy_request
+                .build_query_uri(&upstream.uri)
+                .map(|query_uri| (upstream, query_uri))
+        })
+        .collect();
+
     Box::new(
-        futures::done(proxy_request.build_query_uri(&config.upstream())).and_then(
-            move |query_uri| {
+        futures::done(upstream_queries).and_then(
+            move |upstream_queries| {
+                let req_uri_string: Vec<HyperUri> =
+                    upstream_queries.iter().map(|e| e.1.clone()).collect();
+                let upstream_log = request_log.clone();
+                let error_log = request_log.clone();
                 let downloaded_file_future: Box<
-                    Future<Item = Option<u64>, Error = ServerError> + Send,
+                    Future<Item = Option<Found>, Error = ServerError> + Send,
                 > = match current_file_size(data_source_path.to_str().unwrap()) {
-                    None => Box::new(
-                        downloader
-                            .fetch_file(&http_client, &query_uri, &file_name)
-                            .map(move |len| {
-                                match len {
-                                    Some(_) => {
-                                        process_action_cache_response(&cfg2, &file_name2)
-                                            .map_err(|e| {
+                    None => {
+                        // Peer fetches are cached too, so there's no asking peers without a disk
+                        let caching = disk_health::caching();
+                        let peer_uris = if caching { peer_uris } else { Vec::new() };
+                        Box::new(
+                            fetch_from_peers(
+                                downloader.clone(),
+                                peer_client,
+                                peer_uris,
+                                repo.clone(),
+                                file_name.clone(),
+                                digest,
+                                request_log.clone(),
+                            ).and_then(move |found| match found {
+                                Some(len) => Either::A(futures::future::ok(Some(Found::Cached(len)))),
+                                None => Either::B(fetch_from_upstreams(
+                                    downloader,
+                                    http_client,
+                                    upstream_queries,
+                                    repo,
+                                    file_name,
+                                    caching,
+                                    upstream_log,
+                                )),
+                            })
+                                .map(move |found| {
+                                    match found {
+                                        Some(Found::Cached(_)) => {
+                                            process_action_cache_response(&cfg2, &repo2, &file_name2)
+                                                .map_err(|e| {
+                                                    warn!(
+                                                "[Get]Failed to process action cache with: {:?} -- {:?}",
+                                                e,
+                                                data_source_path2.to_str().unwrap().to_string()
+                                            );
+                                                    ()
+                                                })
+                                                .unwrap_or(());
+                                        }
+                                        _ => {}
+                                    }
+                                    found
+                                })
+                                .map_err(From::from),
+                        )
+                    }
+                    Some(len) if !fresh && !upstream_queries.is_empty() => {
+                        // Action cache entries can be replaced or purged upstream, check ours is current
+                        let validators = if cfg2.ac_revalidate {
+                            ac_freshness::validators(&cfg2, &repo, &file_name)
+                        } else {
+                            None
+                        };
+                        // Ask the upstream the entry came from, another not having it means nothing
+                        let validated_by = ac_freshness::validated_by(&cfg2, &repo, &file_name);
+                        let (upstream, query_uri) = upstream_queries
+                            .iter()
+                            .find(|e| Some(e.0.uri.to_string()) == validated_by)
+                            .unwrap_or(&upstream_queries[0])
+                            .clone();
+                        let revalidation_log = request_log.clone();
+                        Box::new(
+                            downloader
+                                .revalidate_file(
+                                    &http_client,
+                                    &upstream,
+                                    &query_uri,
+                                    &repo,
+                                    &file_name,
+                                    validators,
+                                    &request_log,
+                                )
+                                .then(move |res| match res {
+                                    Ok(Revalidation::NotModified) => {
+                                        revalidation_log.set_outcome(Outcome::Revalidated);
+                                        Ok(Some(Found::Cached(len)))
+                                    }
+                                    Ok(Revalidation::Replaced(new_len)) => {
+                                        revalidation_log.set_outcome(Outcome::UpstreamHit);
+                                        process_action_cache_response(&cfg2, &repo2, &file_name2)
+                                            .unwrap_or_else(|e| {
                                                 warn!(
-                                            "[Get]Failed to process action cache with: {:?} -- {:?}",
-                                            e,
-                                            data_source_path2.to_str().unwrap().to_string()
-                                        );
-                                                ()
-                                            })
-                                            .unwrap_or(());
+                                                    "[Get]Failed to process action cache with: {:?}",
+                                                    e
+                                                )
+                                            });
+                                        Ok(Some(Found::Cached(new_len)))
                                     }
-                                    None => {}
-                                }
-                                len
-                            })
-                            .map_err(From::from),
-                    ),
-                    Some(len) => Box::new(futures::future::ok(Some(len))),
+                                    // Only the upstream it came from was asked, don't remember it as
+                                    // missing from the others
+                                    Ok(Revalidation::Gone) => {
+                                        revalidation_log.set_outcome(Outcome::Miss);
+                                        Ok(None)
+                                    }
+                                    Ok(Revalidation::Unknown) => {
+                                        revalidation_log.set_outcome(Outcome::StaleHit);
+                                        Ok(Some(Found::Cached(len)))
+                                    }
+                                    Err(e) => {
+                                        warn!("Failed revalidating {:?}, serving it anyway: {:?}", file_name2, e);
+                                        revalidation_log.set_outcome(Outcome::StaleHit);
+                                        Ok(Some(Found::Cached(len)))
+                                    }
+                                }),
+                        )
+                    }
+                    Some(len) => {
+                        request_log.set_outcome(Outcome::LocalHit);
+                        Box::new(futures::future::ok(Some(Found::Cached(len))))
+                    }
                 };
 
-                let req_uri_string = query_uri.clone();
-
                 let downloaded_fut = downloaded_file_future
-                    .and_then(move |file_path| {
-                        info!("Get request issued to : {} --> {:?}", req.uri(), file_path);
-                        match file_path {
-                            Some(file_len) => {
+                    .and_then(move |found| {
+                        match found {
+                            Some(Found::Cached(file_len)) => {
+                                info!("Get request issued to : {} --> {:?}", req.uri(), file_len);
                                 if instant.elapsed().as_secs() >= 2 {
                                     info!(
                                         "[{:?}] took: {} seconds at {} MB/sec",
@@ -137,16 +510,62 @@ fn get_request<C: Connect + 'static>(
                                             / duration_to_float_seconds(instant.elapsed())
                                     );
                                 }
-                                send_file(data_source_path.to_str().unwrap().to_string())
+                                let file = match open_cached(&data_source_path) {
+                                    Ok(Some(file)) => file,
+                                    Ok(None) => {
+                                        info!(
+                                            "{:?} was evicted before we could send it, returning 404",
+                                            data_source_path
+                                        );
+                                        request_log.set_outcome(Outcome::Miss);
+                                        let res: ResponseFuture = Box::new(
+                                            empty_with_status_code_fut(StatusCode::NOT_FOUND),
+                                        );
+                                        return res;
+                                    }
+                                    Err(e) => {
+                                        let res: ResponseFuture =
+                                            Box::new(futures::future::err(From::from(e)));
+                                        return res;
+                                    }
+                                };
+                                if keep_in_memory && memory_cache.fits(file_len) {
+                                    send_from_memory(
+                                        memory_cache,
+                                        partition,
+                                        memory_file_name,
+                                        file,
+                                    )
+                                } else {
+                                    send_open_file(
+                                        data_source_path.to_str().unwrap().to_string(),
+                                        file,
+                                    )
+                                }
+                            }
+                            Some(Found::Streaming(file_len, body)) => {
+                                info!(
+                                    "Streaming {} bytes from upstream for {}",
+                                    file_len,
+                                    req.uri()
+                                );
+                                let mut res = Response::new(body);
+                                res.headers_mut()
+                                    .insert(header::CONTENT_LENGTH, HeaderValue::from(file_len));
+                                Box::new(futures::future::ok(res))
                             }
-
                             None => {
                                 // info!(
                                 //    "[{:?}] took: {} seconds at inf MB/sec",
                                 //    path,
                                 //    duration_to_float_seconds(instant.elapsed())
                                 // );
-                                // We decided not to download the file, so 404 it!
+                                // We decided not to download the file, so 404 it! Only every
+                                // upstream answering 404 leaves no outcome, and is worth remembering.
+                                if request_log.outcome().is_none() {
+                                    request_log.set_outcome(Outcome::Miss);
+                                    upstream_misses.insert(&missing_repo, &missing_file_name);
+                                }
                                 let mut res = Response::new(Body::empty());
                                 *res.status_mut() = StatusCode::NOT_FOUND;
                                 Box::new(futures::future::ok(res))
@@ -159,6 +578,7 @@ fn get_request<C: Connect + 'static>(
                             o,
                             instant.elapsed().as_secs()
                         );
+                        error_log.set_outcome(Outcome::Error);
                         let e: ResponseFuture = Box::new(futures::future::ok({
                             let mut res = Response::new(
                                 format!("Requested uri: {:?}\n{:?}", req_uri_string, o).into(),
@@ -177,28 +597,19 @@ fn get_request<C: Connect + 'static>(
 
 fn upstream_upload(
     uploader: &RequestUpload,
-    upstream_uri: &HyperUri,
+    upstream: &UpstreamConfig,
     request: &ProxyRequest,
     upload_path: &String,
-    cache_folder: String,
-    file_name: String,
+    config: &AppConfig,
 ) {
-    let uploader_uri = request.build_query_uri(upstream_uri).unwrap();
+    let uploader_uri = request.build_query_uri(&upstream.uri).unwrap();
 
     uploader
         .upload(
             &uploader_uri,
             upload_path,
-            Box::new(move || {
-                if file_name.starts_with("cas__") {
-                    match current_file_size(&format!("{}/enable_{}", cache_folder, file_name)) {
-                        None => false,
-                        Some(_) => true,
-                    }
-                } else {
-                    true
-                }
-            }),
+            upstream.maximum_upload_size,
+            gated_upload_check(config, upload_path),
         )
         .map_err(|e| {
             warn!("Failed to trigger uploader!: {:?}", e);
@@ -207,39 +618,185 @@ fn upstream_upload(
         .unwrap();
 }
 
-fn put_request(
+// The CAS blobs an action cache entry references that we have cached, with their sizes
+fn cached_outputs(config: &AppConfig, repo: &str, contents: &[u8]) -> Vec<(ProxyRequest, String, u64)> {
+    let action_result = match parse_action_result(contents) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("Failed to parse forwarded action cache entry: {}", e);
+            return Vec::new();
+        }
+    };
+    referenced_cas_digests(&action_result)
+        .into_iter()
+        .filter_map(|digest| ProxyRequest::from_file_name(repo, &format!("cas__{}", digest)))
+        .filter_map(|request| {
+            let path = config.cache_path(repo, &request.file_name());
+            let len = fs::metadata(&path).ok()?.len();
+            Some((request, path.to_string_lossy().to_string(), len))
+        })
+        .collect()
+}
+
+// Without a cache disk to keep an upload on, send it straight to the upstreams we'd have
+// uploaded it to. There's nowhere to hold CAS blobs until an action cache entry references them
+// either, so those go up ungated, and an action cache entry's cached outputs go up before it.
+fn forward_put<C: Connect + 'static>(
+    req: Request<Body>,
+    http_client: &Client<C>,
+    config: &AppConfig,
+    proxy_request: &ProxyRequest,
+    request_log: RequestLog,
+) -> ResponseFuture {
+    let upstreams: Vec<UpstreamConfig> = if config.should_upload_type(&proxy_request.tpe) {
+        config
+            .upstreams_for(&proxy_request.repo)
+            .into_iter()
+            .filter(|e| e.upload)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    request_log.set_outcome(Outcome::PassedThrough);
+    if upstreams.is_empty() {
+        return Box::new(empty_with_status_code_fut(StatusCode::CREATED));
+    }
+    // Everything forwarded is held in memory, so refuse what no upstream would take up front
+    let maximum_upload_size = upstreams
+        .iter()
+        .map(|e| e.maximum_upload_size)
+        .max()
+        .unwrap_or(0);
+    if let Some(len) = req.body().content_length() {
+        if len > maximum_upload_size {
+            info!(
+                "Not forwarding {} byte upload, over the maximum upload size of {}",
+                len, maximum_upload_size
+            );
+            return Box::new(empty_with_status_code_fut(StatusCode::PAYLOAD_TOO_LARGE));
+        }
+    }
+
+    let http_client = http_client.clone();
+    let credential = config.upstream_credential();
+    let config = config.clone();
+    let proxy_request = proxy_request.clone();
+
+    Box::new(
+        req.into_body()
+            .map_err(ServerError::from)
+            .fold(Vec::new(), move |mut contents, chunk| {
+                if (contents.len() + chunk.len()) as u64 > maximum_upload_size {
+                    return Err(ServerError::from(format!(
+                        "Upload is over the maximum upload size of {}",
+                        maximum_upload_size
+                    )));
+                }
+                contents.extend_from_slice(&chunk);
+                Ok(contents)
+            })
+            .and_then(move |contents| {
+                let contents = Bytes::from(contents);
+                let outputs = if proxy_request.tpe == "ac" && config.should_upload_type("cas") {
+                    cached_outputs(&config, &proxy_request.repo, &contents)
+                } else {
+                    Vec::new()
+                };
+                let forwards: Vec<_> = upstreams
+                    .into_iter()
+                    .filter(|e| contents.len() as u64 <= e.maximum_upload_size)
+                    .filter_map(|upstream| {
+                        let uri = proxy_request.build_query_uri(&upstream.uri).ok()?;
+                        if upload_refused(&uri) {
+                            return None;
+                        }
+                        let output_uploads: Vec<_> = outputs
+                            .iter()
+                            .filter(|&&(_, _, len)| len <= upstream.maximum_upload_size)
+                            .filter_map(|&(ref request, ref path, _)| {
+                                let uri = request.build_query_uri(&upstream.uri).ok()?;
+                                if upload_refused(&uri) {
+                                    return None;
+                                }
+                                Some(run_upload_file(
+                                    http_client.clone(),
+                                    uri,
+                                    path.clone(),
+                                    credential.clone(),
+                                ))
+                            })
+                            .collect();
+                        let http_client = http_client.clone();
+                        let credential = credential.clone();
+                        let contents = contents.clone();
+                        let failed_uri = uri.clone();
+                        Some(
+                            futures::future::join_all(output_uploads)
+                                .and_then(move |_| {
+                                    info!("Forwarding upload to {:?}", uri);
+                                    upload_body(http_client, uri, Body::from(contents), credential)
+                                })
+                                .or_else(move |e| {
+                                    warn!("Failed forwarding upload to {:?}: {}", failed_uri, e);
+                                    Ok(())
+                                }),
+                        )
+                    })
+                    .collect();
+                futures::future::join_all(forwards)
+                    .map(|_| empty_with_status_code(StatusCode::CREATED))
+            }),
+    )
+}
+
+fn put_request<C: Connect + 'static>(
     instant: Instant,
     req: Request<Body>,
     downloader: &Downloader,
+    http_client: &Client<C>,
     config: &AppConfig,
     request_upload: &RequestUpload,
+    upstream_misses: &UpstreamMisses,
+    request_log: RequestLog,
 ) -> ResponseFuture {
     let proxy_request = ProxyRequest::new(req.uri());
     let file_name = proxy_request.file_name();
+    request_log.set_request(&proxy_request);
+    upstream_misses.forget(&file_name);
 
     info!("Put request: {:?}", req.uri().path());
 
-    let upstream_uri = config.upstream();
+    if !disk_health::caching() {
+        return forward_put(req, http_client, config, &proxy_request, request_log);
+    }
+
+    let upstreams = config.upstreams_for(&proxy_request.repo);
 
     let path = req.uri().path().to_string().clone();
 
-    let upload_path = Path::new(&config.cache_folder)
-        .join(&file_name)
+    let upload_path = config
+        .cache_path(&proxy_request.repo, &file_name)
         .to_str()
         .unwrap()
         .to_string();
 
     let uploader = request_upload.clone();
-    let cache_folder = config.cache_folder.clone();
 
     let processor_config = config.clone();
     Box::new(
         downloader
-            .save_file(&file_name, req)
+            .save_file(&proxy_request.repo, &file_name, req)
             .map(move |_file| {
+                request_log.set_outcome(if _file.is_some() {
+                    Outcome::Stored
+                } else {
+                    Outcome::AlreadyPresent
+                });
                 match _file {
                     Some(_f) => {
-                        process_action_cache_response(&processor_config, &_f)
+                        // Written locally, so whatever the upstream said about it no longer applies
+                        ac_freshness::forget(&processor_config, &proxy_request.repo, &_f);
+                        process_action_cache_response(&processor_config, &proxy_request.repo, &_f)
                             .map_err(|e| {
                                 warn!(
                                     "[Put]Failed to process action cache with: {:?} for {:?}",
@@ -249,14 +806,22 @@ fn put_request(
                             })
                             .unwrap_or(());
 
-                        upstream_upload(
-                            &uploader,
-                            &upstream_uri,
-                            &proxy_request,
-                            &upload_path,
-                            cache_folder.clone(),
-                            file_name.clone(),
-                        );
+                        if processor_config.should_upload_type(&proxy_request.tpe) {
+                            for upstream in upstreams.iter().filter(|e| e.upload) {
+                                upstream_upload(
+                                    &uploader,
+                                    upstream,
+                                    &proxy_request,
+                                    &upload_path,
+                                    &processor_config,
+                                );
+                            }
+                        } else {
+                            debug!(
+                                "Not uploading {:?}, {} uploads are disabled",
+                                file_name, proxy_request.tpe
+                            );
+                        }
                         ()
                     }
                     None => (),
@@ -286,46 +851,98 @@ pub fn start_server<C: Connect + 'static>(
 where
     C: Connect,
 {
-    let s = Arc::new(Mutex::new(State {
-        last_user_facing_request: Instant::now(),
-        last_background_upload: Instant::now(),
-    }));
+    let s = Arc::new(Mutex::new(State::new()));
 
     process_existing_action_caches(config.clone());
+    start_scrubber(config, &downloader);
+
+    let peers = Peers::new(config);
+    if config.discover_peers {
+        start_peer_discovery(&peers)?;
+    }
+    let peer_client = Client::builder().build::<_, Body>(HttpConnector::new(1));
 
-    let (uploader, channel) = ::net::background_uploader::start_uploader(config, &http_client, &s);
+    let upstream_misses = UpstreamMisses::new(config);
+    let denylist = Denylist::new(config);
+    let denylist_refresh = if config.denylist_file.is_some() || config.denylist_uri.is_some() {
+        Some(start_denylist_refresh(
+            &denylist,
+            &http_client,
+            config.denylist_refresh_interval,
+        ))
+    } else {
+        None
+    };
+
+    let (uploader, channel) = ::net::background_uploader::start_uploader(
+        config,
+        &http_client,
+        &s,
+        &upstream_misses,
+    );
     let terminator = ::net::terminator::start_terminator(config, &s);
 
     let cfg = config.clone();
 
+    let shutdown_state = Arc::clone(&s);
+    let shutdown_downloader = downloader.clone();
+
+    let access_log = match config.access_log {
+        Some(ref path) => Some(AccessLog::open(path)?),
+        None => None,
+    };
+
     let new_service = move || {
         // Move a clone of `client` into the `service_fn`.
         let downloader = downloader.clone();
         let http_client = http_client.clone();
+        let peer_client = peer_client.clone();
+        let peers = peers.clone();
+        let upstream_misses = upstream_misses.clone();
+        let denylist = denylist.clone();
         let request_upload = channel.clone();
         let state = Arc::clone(&s);
+        let access_log = access_log.clone();
 
         let inner_cfg = cfg.clone();
         service_fn(move |req| {
-            {
-                let mut locked = state.lock().unwrap();
-                locked.last_user_facing_request = Instant::now();
+            let is_peer_request = req.uri().path().starts_with("/peer/");
+            let is_admin_request = req.uri().path().starts_with("/admin/");
+            let is_health_request = req.uri().path() == "/health";
+            // Peers fetching from us, admin tooling and health checks shouldn't hold off our uploads/idle shutdown
+            if !is_peer_request && !is_admin_request && !is_health_request {
+                state.lock().unwrap().last_user_facing_request = Instant::now();
             }
+            let in_flight = InFlight::new(&state);
+            let request_log = RequestLog::new(&req);
+            let finished_log = request_log.clone();
+            let access_log = access_log.clone();
             Box::new(
                 match req.method() {
+                    _ if is_health_request => health_request(&state.lock().unwrap()),
+                    _ if is_admin_request => admin_request(req, &inner_cfg, &downloader),
+                    &Method::GET if is_peer_request => peer_request(req, &inner_cfg),
                     &Method::GET => get_request(
                         Instant::now(),
                         req,
                         &downloader,
                         &http_client,
+                        &peer_client,
+                        &peers,
+                        &upstream_misses,
+                        &denylist,
                         &inner_cfg.clone(),
+                        request_log,
                     ),
                     &Method::PUT => put_request(
                         Instant::now(),
                         req,
                         &downloader,
+                        &http_client,
                         &inner_cfg.clone(),
                         &request_upload,
+                        &upstream_misses,
+                        request_log,
                     ),
                     _ => {
                         info!(
@@ -340,60 +957,89 @@ where
                 }.map_err(|e| {
                     error!("Ran into error: {}", e.description());
                     e
-                }),
+                })
+                    .map(into_response_body)
+                    .then(move |res| {
+                        log_response(&access_log, finished_log, in_flight, res)
+                    }),
             )
         })
     };
 
-    let server_engine = match config.bind_target.scheme() {
-        Some("unix") => {
-            info!(
-                "Going to remove existing socket path: {}",
-                config.bind_target.authority().unwrap()
-            );
+    // When socket activated we serve on the socket we were handed, ignoring the bind target
+    let server_engine = match activated_listener()? {
+        Some(listener) => start_activated_server_impl(listener, new_service).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Error configuring socket activated server: {:?}", e),
+            )
+        })?,
+        None => match config.bind_target.scheme() {
+            Some("unix") => {
+                info!(
+                    "Going to remove existing socket path: {}",
+                    config.bind_target.authority().unwrap()
+                );
 
-            // remove the socket we wish to bind to before binding to it.
-            fs::remove_file(config.bind_target.authority().unwrap())?;
-            info!(
-                "Going to bind/start server on unix socket for: {}",
-                config.bind_target
-            );
-            start_unix_server_impl(&config.bind_target, new_service).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Error configuring unix server: {:?}", e),
-                )
-            })?
-        }
-        Some("http") => {
-            info!(
-                "Going to bind/start server on http path for: {}",
-                config.bind_target
-            );
-            start_http_server_impl(&config.bind_target, new_service).map_err(|e| {
-                io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("Error configuring unix server: {:?}", e),
-                )
-            })?
-        }
-        _o => {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!(
-                    "Invalid bind target {}, didn't understand the scheme",
+                // remove the socket we wish to bind to before binding to it.
+                match fs::remove_file(config.bind_target.authority().unwrap()) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    other => other?,
+                }
+                info!(
+                    "Going to bind/start server on unix socket for: {}",
                     config.bind_target
-                ),
-            ))
-        }
+                );
+                start_unix_server_impl(&config.bind_target, new_service).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Error configuring unix server: {:?}", e),
+                    )
+                })?
+            }
+            Some("http") => {
+                info!(
+                    "Going to bind/start server on http path for: {}",
+                    config.bind_target
+                );
+                start_http_server_impl(&config.bind_target, new_service).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("Error configuring unix server: {:?}", e),
+                    )
+                })?
+            }
+            _o => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Invalid bind target {}, didn't understand the scheme",
+                        config.bind_target
+                    ),
+                ))
+            }
+        },
     };
 
-    hyper::rt::run(
+    // Stop accepting connections once asked to shut down or idle for too long, then give
+    // in-flight requests and queued uploads the grace period to finish.
+    let (uploads_done_tx, uploads_done) = oneshot::channel();
+    let grace_period = config.shutdown_grace_period;
+    hyper::rt::run(futures::lazy(move || {
+        hyper::rt::spawn(uploader.then(move |_| uploads_done_tx.send(()).map_err(|_| ())));
+        if let Some(refresh) = denylist_refresh {
+            hyper::rt::spawn(refresh);
+        }
         server_engine
-            .join(uploader)
-            .join(terminator)
-            .map(|_| ())
-            .map_err(|_| ()),
-    );
+            .select(shutdown_signal().select(terminator).map(|_| ()).map_err(|_| ()))
+            .then(move |_| {
+                graceful_shutdown(
+                    shutdown_state,
+                    shutdown_downloader,
+                    grace_period,
+                    Box::new(uploads_done.map_err(|_| ())),
+                )
+            })
+    }));
     return Ok(());
 }
@@ -0,0 +1,166 @@
+use config::AppConfig;
+use futures;
+use futures::future::{loop_fn, Either, Loop};
+use futures::{Future, Stream};
+use http::HeaderMap;
+use hyper::client::connect::Connect;
+use hyper::{Client, StatusCode, Uri as HyperUri};
+use net::client::connect_for_file;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::timer::Delay;
+
+struct Denied {
+    from_file: HashSet<String>,
+    file_modified: Option<SystemTime>,
+    from_uri: HashSet<String>,
+}
+
+/// AC and CAS digests we treat as missing wherever they're cached, e.g. poisoned results.
+#[derive(Clone)]
+pub struct Denylist {
+    file: Option<PathBuf>,
+    uri: Option<HyperUri>,
+    denied: Arc<Mutex<Denied>>,
+}
+
+// One digest per line, `#` starts a comment
+fn parse(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
+
+impl Denylist {
+    pub fn new(config: &AppConfig) -> Denylist {
+        let denylist = Denylist {
+            file: config.denylist_file.as_ref().map(PathBuf::from),
+            uri: config.denylist_uri.clone(),
+            denied: Arc::new(Mutex::new(Denied {
+                from_file: HashSet::new(),
+                file_modified: None,
+                from_uri: HashSet::new(),
+            })),
+        };
+        denylist.reload_file();
+        denylist
+    }
+
+    pub fn contains(&self, digest: &str) -> bool {
+        // Entries are lowercased as they're loaded, requests can be in either case
+        let digest = digest.to_lowercase();
+        let denied = self.denied.lock().unwrap();
+        denied.from_file.contains(&digest) || denied.from_uri.contains(&digest)
+    }
+
+    // Everything we deny, for proxies refreshing from us
+    pub fn digests(&self) -> Vec<String> {
+        let denied = self.denied.lock().unwrap();
+        let mut digests: Vec<String> = denied
+            .from_file
+            .union(&denied.from_uri)
+            .cloned()
+            .collect();
+        digests.sort();
+        digests
+    }
+
+    // Re-read the file if it changed since we last loaded it, keeping what we had on failure
+    fn reload_file(&self) {
+        let path = match self.file {
+            Some(ref e) => e,
+            None => return,
+        };
+        let modified = match fs::metadata(path).and_then(|e| e.modified()) {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("Failed reading denylist {:?}: {}", path, e);
+                return;
+            }
+        };
+        if self.denied.lock().unwrap().file_modified == Some(modified) {
+            return;
+        }
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let from_file = parse(&contents);
+                info!("Loaded {} denied digests from {:?}", from_file.len(), path);
+                let mut denied = self.denied.lock().unwrap();
+                denied.from_file = from_file;
+                denied.file_modified = Some(modified);
+            }
+            Err(e) => warn!("Failed reading denylist {:?}: {}", path, e),
+        }
+    }
+}
+
+fn fetch_denylist<C: Connect + 'static>(
+    http_client: &Client<C>,
+    uri: &HyperUri,
+) -> Box<Future<Item = HashSet<String>, Error = String> + Send> {
+    let uri2 = uri.clone();
+    Box::new(
+        connect_for_file(
+            http_client.clone(),
+            uri.clone(),
+            HeaderMap::new(),
+            0,
+            Duration::from_millis(500),
+            1,
+        ).and_then(move |res| match res.status() {
+            StatusCode::OK => Either::A(
+                res.into_body()
+                    .concat2()
+                    .map(|body| parse(&String::from_utf8_lossy(&body)))
+                    .map_err(|e| e.to_string()),
+            ),
+            status => Either::B(futures::future::err(format!(
+                "{} answered {}",
+                uri2, status
+            ))),
+        }),
+    )
+}
+
+// Keep the denylist current, from its file and the proxy we refresh it from.
+pub(super) fn start_denylist_refresh<C: Connect + 'static>(
+    denylist: &Denylist,
+    http_client: &Client<C>,
+    interval: Duration,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let denylist = denylist.clone();
+    let http_client = http_client.clone();
+    Box::new(loop_fn((), move |_| {
+        denylist.reload_file();
+        let fetched: Box<Future<Item = (), Error = ()> + Send> = match denylist.uri {
+            Some(ref uri) => {
+                let refreshed = denylist.clone();
+                let uri2 = uri.clone();
+                Box::new(fetch_denylist(&http_client, uri).then(move |res| {
+                    match res {
+                        Ok(from_uri) => {
+                            let mut denied = refreshed.denied.lock().unwrap();
+                            if denied.from_uri != from_uri {
+                                info!("Refreshed {} denied digests from {}", from_uri.len(), uri2);
+                                denied.from_uri = from_uri;
+                            }
+                        }
+                        // Keep using what we last got until it's back
+                        Err(e) => warn!("Failed refreshing denylist: {}", e),
+                    }
+                    Ok(())
+                }))
+            }
+            None => Box::new(futures::future::ok(())),
+        };
+        fetched.and_then(move |_| {
+            Delay::new(Instant::now() + interval)
+                .then(|_| Ok(Loop::Continue(())))
+        })
+    }))
+}
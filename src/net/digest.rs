@@ -0,0 +1,32 @@
+use hex::ToHex;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+// Hex encoded sha256 of a file's contents, matching bazel's CAS digests.
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let size = file.read(&mut buf)?;
+        if size == 0 {
+            break;
+        }
+        hasher.input(&buf[0..size]);
+    }
+    Ok(hasher.result().as_slice().to_hex())
+}
+
+// Check a file we were handed matches the digest it was requested as.
+pub fn verify_file(path: &Path, expected_digest: &str) -> io::Result<bool> {
+    sha256_file(path).map(|actual| actual.eq_ignore_ascii_case(expected_digest))
+}
+
+pub fn sha256_bytes(data: &[u8]) -> String {
+    let mut hasher = Sha256::default();
+    hasher.input(data);
+    hasher.result().as_slice().to_hex()
+}
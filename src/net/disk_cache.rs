@@ -0,0 +1,484 @@
+use config::EvictionPolicy;
+use net::cache_journal::{Committed, Journal, Recorded};
+use net::digest::sha256_file;
+use net::disk_health::{disk_error, disk_ok};
+use net::eviction::{new_policy, Policy};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// How well a policy has done since we started.
+#[derive(Debug, Clone, Default)]
+pub struct EvictionStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub bytes_hit: u64,
+    pub evictions: u64,
+    pub bytes_evicted: u64,
+}
+
+impl EvictionStats {
+    pub fn hit_ratio(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+// What a policy would hold given the traffic we've seen, without any files behind it
+struct PolicyTracker {
+    kind: EvictionPolicy,
+    policy: Box<Policy>,
+    capacity: u64,
+    size: u64,
+    entries: HashMap<String, u64>,
+    stats: EvictionStats,
+}
+
+impl PolicyTracker {
+    fn new(kind: EvictionPolicy, capacity: u64) -> PolicyTracker {
+        PolicyTracker {
+            kind: kind,
+            policy: new_policy(kind, capacity),
+            capacity: capacity,
+            size: 0,
+            entries: HashMap::new(),
+            stats: EvictionStats::default(),
+        }
+    }
+
+    // Returns the entries evicted to make room, which never include the new one
+    fn insert(&mut self, key: &str, size: u64) -> Result<Vec<String>, String> {
+        if size > self.capacity {
+            return Err(format!(
+                "{} is {} bytes, more than the {} the cache can hold",
+                key, size, self.capacity
+            ));
+        }
+        self.remove(key);
+        let mut evicted = Vec::new();
+        while self.size + size > self.capacity {
+            let victim = match self.policy.evict() {
+                Some(e) => e,
+                None => break,
+            };
+            if let Some(victim_size) = self.entries.remove(&victim) {
+                self.size -= victim_size;
+                self.stats.evictions += 1;
+                self.stats.bytes_evicted += victim_size;
+                evicted.push(victim);
+            }
+        }
+        self.policy.insert(key, size);
+        self.entries.insert(key.to_string(), size);
+        self.size += size;
+        Ok(evicted)
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(size) => {
+                self.policy.remove(key);
+                self.size -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn hit(&mut self, key: &str) {
+        if let Some(&size) = self.entries.get(key) {
+            self.policy.touch(key);
+            self.stats.hits += 1;
+            self.stats.bytes_hit += size;
+        }
+    }
+}
+
+// Take on an entry we have no record of without reading blobs through, a cache from before we
+// kept a journal can be too big to hash before we start serving. What a CAS entry should hash to
+// is in its name, action cache entries are small enough to hash. The scrubber catches any
+// that are corrupt.
+fn adopt(path: &Path, file_name: &str) -> Result<String, String> {
+    if file_name.starts_with("cas__") {
+        Ok(file_name.trim_left_matches("cas__").to_lowercase())
+    } else {
+        sha256_file(path).map_err(|e| e.to_string())
+    }
+}
+
+// Whether an entry on disk is the one we committed
+fn recover(recorded: &Recorded, path: &Path, file_name: &str, size: u64) -> Result<Committed, String> {
+    match recorded.entries.get(file_name) {
+        Some(committed) if committed.size != size => Err(format!(
+            "only {} of the {} bytes committed",
+            size, committed.size
+        )),
+        // Possibly being replaced when we stopped
+        Some(committed) if recorded.recent.contains(file_name) => {
+            match sha256_file(path) {
+                Ok(ref sha256) if *sha256 == committed.sha256 => Ok(committed.clone()),
+                Ok(_) => Err("content doesn't match what was committed".to_string()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Some(committed) => Ok(committed.clone()),
+        None if recorded.existed => Err("never committed".to_string()),
+        // From before we kept a journal
+        None => adopt(path, file_name).map(|sha256| Committed {
+            size: size,
+            sha256: sha256,
+        }),
+    }
+}
+
+/// The ac/cas entries in one folder, kept within a byte budget by an eviction policy.
+/// With stats on, the other policies are simulated against the same traffic to compare them.
+/// Pinned entries are left out of both, and don't count towards the budget.
+pub struct DiskCache {
+    root: PathBuf,
+    active: PolicyTracker,
+    shadows: Vec<PolicyTracker>,
+    // Entries to pin, whether or not we hold them, and the sizes of those we do
+    pins: HashSet<String>,
+    pinned: HashMap<String, u64>,
+    journal: Journal,
+    committed: HashMap<String, Committed>,
+}
+
+impl DiskCache {
+    pub fn new(
+        root: PathBuf,
+        capacity: u64,
+        policy: EvictionPolicy,
+        stats: bool,
+        pins: HashSet<String>,
+    ) -> Result<DiskCache, String> {
+        let shadows = if stats {
+            EvictionPolicy::all()
+                .into_iter()
+                .filter(|e| *e != policy)
+                .map(|e| PolicyTracker::new(e, capacity))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let (journal, recorded) = Journal::open(&root)?;
+        if !recorded.existed {
+            info!("Adopting the entries in {:?}, which has no cache journal yet", root);
+        }
+        let mut cache = DiskCache {
+            root: root,
+            active: PolicyTracker::new(policy, capacity),
+            shadows: shadows,
+            pins: pins,
+            pinned: HashMap::new(),
+            journal: journal,
+            committed: HashMap::new(),
+        };
+
+        // Oldest first, so what we wrote last is what we keep
+        let mut existing = Vec::new();
+        for entry in fs::read_dir(&cache.root).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !(file_name.starts_with("ac__") || file_name.starts_with("cas__")) {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(ref e) if e.is_file() => e.clone(),
+                _ => continue,
+            };
+            let path = cache.root.join(&file_name);
+            match recover(&recorded, &path, &file_name, metadata.len()) {
+                Ok(committed) => {
+                    cache.committed.insert(file_name.clone(), committed);
+                }
+                Err(e) => {
+                    warn!("Dropping {:?} from the cache: {}", path, e);
+                    fs::remove_file(&path).map_err(|e| e.to_string())?;
+                    continue;
+                }
+            }
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|e| e.duration_since(UNIX_EPOCH).ok());
+            existing.push((modified, file_name, metadata.len()));
+        }
+        existing.sort();
+        for (_, file_name, size) in existing {
+            if cache.pins.contains(&file_name) {
+                cache.pinned.insert(file_name, size);
+                continue;
+            }
+            let path = cache.root.join(&file_name);
+            if let Err(e) = cache.track(&file_name, size) {
+                warn!("Dropping {:?} from the cache: {}", path, e);
+                fs::remove_file(&path).map_err(|e| e.to_string())?;
+                cache.forget(&file_name);
+            }
+        }
+        // Start from exactly what survived, so the next recovery has little to check
+        cache.journal.checkpoint(&cache.committed)?;
+
+        // Loading what's on disk isn't traffic, don't count it against any policy
+        for tracker in cache.trackers_mut() {
+            tracker.stats = EvictionStats::default();
+        }
+        Ok(cache)
+    }
+
+    fn trackers_mut(&mut self) -> Vec<&mut PolicyTracker> {
+        let mut trackers = vec![&mut self.active];
+        trackers.extend(self.shadows.iter_mut());
+        trackers
+    }
+
+    // Account for a new entry everywhere, deleting whatever the active policy evicts for it
+    fn track(&mut self, key: &str, size: u64) -> Result<Vec<String>, String> {
+        let evicted = self.active.insert(key, size)?;
+        for shadow in self.shadows.iter_mut() {
+            shadow.insert(key, size)?;
+        }
+        for victim in evicted.iter() {
+            let path = self.root.join(victim);
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Failed to evict {:?}: {}", path, e);
+            }
+            self.forget(victim);
+        }
+        Ok(evicted)
+    }
+
+    fn untrack(&mut self, key: &str) {
+        self.pinned.remove(key);
+        for tracker in self.trackers_mut() {
+            tracker.remove(key);
+        }
+    }
+
+    // Once its file is gone
+    fn forget(&mut self, key: &str) {
+        self.untrack(key);
+        if self.committed.remove(key).is_some() {
+            self.journal.remove(key).unwrap_or_else(|e| warn!("{}", e));
+        }
+    }
+
+    /// Move the file at `path`, synced to disk, into the cache as `key`, returning the entries
+    /// evicted for it. Once this returns it's committed, and will survive a crash.
+    pub fn insert_file(
+        &mut self,
+        key: &str,
+        path: String,
+        sha256: String,
+    ) -> Result<Vec<String>, String> {
+        let size = fs::metadata(&path).map_err(disk_error)?.len();
+        if let Err(e) = fs::rename(&path, self.root.join(key)) {
+            fs::remove_file(&path).unwrap_or(());
+            return Err(format!("Failed to move {:?} into the cache: {}", path, disk_error(e)));
+        }
+        // Only once it's in place, a move that fails mustn't cost other entries theirs
+        let evicted = if self.pins.contains(key) {
+            self.untrack(key);
+            self.pinned.insert(key.to_string(), size);
+            Vec::new()
+        } else {
+            match self.track(key, size) {
+                Ok(evicted) => evicted,
+                Err(e) => {
+                    fs::remove_file(self.root.join(key)).unwrap_or(());
+                    self.forget(key);
+                    return Err(e);
+                }
+            }
+        };
+
+        let committed = Committed {
+            size: size,
+            sha256: sha256,
+        };
+        if let Err(e) = self.journal.commit(key, &committed) {
+            // Without a record we couldn't trust it after a crash, so don't serve it now either
+            fs::remove_file(self.root.join(key)).unwrap_or(());
+            self.forget(key);
+            return Err(e);
+        }
+        self.committed.insert(key.to_string(), committed);
+        disk_ok();
+
+        if self.journal.needs_checkpoint(self.committed.len()) {
+            self.checkpoint();
+        }
+        Ok(evicted)
+    }
+
+    /// Move in an entry from a folder we no longer keep a cache in, taking it on as we would on
+    /// startup. It's only recorded by the next checkpoint, so moving many in only syncs once.
+    pub fn adopt_file(&mut self, key: &str, path: &Path) -> Result<(), String> {
+        let size = fs::metadata(path).map_err(|e| e.to_string())?.len();
+        let sha256 = adopt(path, key)?;
+        let pinned = self.pins.contains(key);
+        if pinned {
+            self.untrack(key);
+        } else {
+            self.track(key, size)?;
+        }
+        if let Err(e) = fs::rename(path, self.root.join(key)) {
+            self.untrack(key);
+            return Err(format!("Failed to move {:?} into the cache: {}", path, disk_error(e)));
+        }
+        if pinned {
+            self.pinned.insert(key.to_string(), size);
+        }
+        self.committed.insert(
+            key.to_string(),
+            Committed {
+                size: size,
+                sha256: sha256,
+            },
+        );
+        Ok(())
+    }
+
+    /// Record everything we hold in the manifest, so a restart only has the journal since to check.
+    pub fn checkpoint(&mut self) {
+        if let Err(e) = self.journal.checkpoint(&self.committed) {
+            warn!("Failed to checkpoint cache {:?}: {}", self.root, e);
+        }
+    }
+
+    pub fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.active.entries.contains_key(key) || self.pinned.contains_key(key)
+    }
+
+    /// Pin exactly these entries, returning any evicted to make room for those no longer pinned.
+    pub fn set_pins(&mut self, pins: HashSet<String>) -> Vec<String> {
+        let newly_pinned: Vec<(String, u64)> = self
+            .active
+            .entries
+            .iter()
+            .filter(|&(key, _)| pins.contains(key))
+            .map(|(key, &size)| (key.clone(), size))
+            .collect();
+        for (key, size) in newly_pinned {
+            for tracker in self.trackers_mut() {
+                tracker.remove(&key);
+            }
+            self.pinned.insert(key, size);
+        }
+
+        let unpinned: Vec<(String, u64)> = self
+            .pinned
+            .iter()
+            .filter(|&(key, _)| !pins.contains(key))
+            .map(|(key, &size)| (key.clone(), size))
+            .collect();
+        self.pins = pins;
+        let mut evicted = Vec::new();
+        for (key, size) in unpinned {
+            self.pinned.remove(&key);
+            match self.track(&key, size) {
+                Ok(e) => evicted.extend(e),
+                Err(e) => {
+                    warn!("Evicting {} now it's unpinned: {}", key, e);
+                    fs::remove_file(self.root.join(&key)).unwrap_or(());
+                    self.forget(&key);
+                    evicted.push(key);
+                }
+            }
+        }
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), String> {
+        let path = self.root.join(key);
+        if path.is_file() {
+            fs::remove_file(&path).map_err(|e| e.to_string())?;
+        }
+        self.forget(key);
+        Ok(())
+    }
+
+    /// Move an entry out of the cache to `dest`, e.g. to keep a corrupt one for inspection.
+    pub fn quarantine(&mut self, key: &str, dest: &Path) -> Result<(), String> {
+        fs::rename(self.root.join(key), dest).map_err(|e| e.to_string())?;
+        self.forget(key);
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.active.size
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.active.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.entries.len()
+    }
+
+    /// Bytes and entries held outside the budget because they're pinned.
+    pub fn pinned_usage(&self) -> (u64, usize) {
+        (self.pinned.values().sum(), self.pinned.len())
+    }
+
+    /// A request we answered from the cache.
+    pub fn record_hit(&mut self, key: &str) {
+        // Every policy holds pinned entries
+        if let Some(&size) = self.pinned.get(key) {
+            for tracker in self.trackers_mut() {
+                tracker.stats.hits += 1;
+                tracker.stats.bytes_hit += size;
+            }
+            return;
+        }
+        let size = match self.active.entries.get(key) {
+            Some(&e) => e,
+            None => return,
+        };
+        self.active.hit(key);
+        // A policy that had let it go would have fetched it again
+        for shadow in self.shadows.iter_mut() {
+            if shadow.entries.contains_key(key) {
+                shadow.hit(key);
+            } else {
+                shadow.stats.misses += 1;
+                shadow.insert(key, size).ok();
+            }
+        }
+    }
+
+    /// A request for something we don't hold, we'll see it again in `insert_file` if fetched.
+    pub fn record_miss(&mut self, key: &str) {
+        self.active.stats.misses += 1;
+        // A policy that held on to it would have answered
+        for shadow in self.shadows.iter_mut() {
+            if shadow.entries.contains_key(key) {
+                shadow.hit(key);
+            } else {
+                shadow.stats.misses += 1;
+            }
+        }
+    }
+
+    /// Stats for the policy in use, then any we're simulating.
+    pub fn stats(&self) -> Vec<(EvictionPolicy, bool, EvictionStats)> {
+        let mut stats = vec![(self.active.kind, true, self.active.stats.clone())];
+        stats.extend(
+            self.shadows
+                .iter()
+                .map(|e| (e.kind, false, e.stats.clone())),
+        );
+        stats
+    }
+}
@@ -0,0 +1,84 @@
+use libc;
+use std::io;
+use std::io::ErrorKind as IoErrorKind;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// How long to pass requests through before letting one try the disk again
+const RETRY_INTERVAL_SECS: u64 = 30;
+
+/// Why we stopped caching, and when.
+#[derive(Debug, Clone)]
+pub struct Degraded {
+    pub reason: String,
+    pub since: SystemTime,
+    last_attempt: Instant,
+}
+
+lazy_static! {
+    // One cache folder per process, so one disk to keep track of
+    static ref DEGRADED: Mutex<Option<Degraded>> = Mutex::new(None);
+}
+
+// Errors that'll keep happening until someone frees space or fixes the mount, rather than
+// something wrong with one entry
+fn is_disk_failure(e: &io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EROFS) => true,
+        _ => e.kind() == IoErrorKind::PermissionDenied,
+    }
+}
+
+/// Note an error writing to the cache disk, switching to pass through if it's one caching
+/// can't carry on from. Returns the error as a string, to use with `map_err`.
+pub fn disk_error(e: io::Error) -> String {
+    if is_disk_failure(&e) {
+        let mut degraded = DEGRADED.lock().unwrap();
+        if degraded.is_none() {
+            error!(
+                "Cache disk failed with {}, passing requests through without caching",
+                e
+            );
+        }
+        *degraded = Some(Degraded {
+            reason: e.to_string(),
+            since: degraded
+                .as_ref()
+                .map(|e| e.since)
+                .unwrap_or_else(SystemTime::now),
+            last_attempt: Instant::now(),
+        });
+    }
+    e.to_string()
+}
+
+/// Note a write to the cache disk went through, leaving pass through if we were in it.
+pub fn disk_ok() {
+    let mut degraded = DEGRADED.lock().unwrap();
+    if let Some(ref e) = *degraded {
+        info!(
+            "Cache disk is writable again after failing with {}, caching again",
+            e.reason
+        );
+    }
+    *degraded = None;
+}
+
+/// Whether a request should go through the cache. While degraded this lets one request
+/// through every so often, to find out if the disk has recovered.
+pub fn caching() -> bool {
+    let retry_interval = Duration::from_secs(RETRY_INTERVAL_SECS);
+    let mut degraded = DEGRADED.lock().unwrap();
+    match *degraded {
+        Some(ref mut e) if e.last_attempt.elapsed() >= retry_interval => {
+            e.last_attempt = Instant::now();
+            true
+        }
+        Some(_) => false,
+        None => true,
+    }
+}
+
+pub fn degraded() -> Option<Degraded> {
+    DEGRADED.lock().unwrap().clone()
+}
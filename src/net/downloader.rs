@@ -1,31 +1,355 @@
 use config::AppConfig;
+use bytes::Bytes;
+use config::DEFAULT_PARTITION;
+use config::EvictionPolicy;
+use config::UpstreamConfig;
 use futures;
 use futures::future::Either;
-use futures::{Future, Stream};
+use futures::Future;
 use http::header;
+use http::HeaderMap;
+use hyper;
 use hyper::client::connect::Connect;
 use hyper::client::Client;
 use hyper::Request;
 use hyper::Uri;
-use hyper::{Body, StatusCode};
-use lru_disk_cache::LruDiskCache;
+use hyper::{Body, Response, StatusCode};
+use net::ac_freshness::{self, Validators};
+use net::access_log::{Outcome, RequestLog};
+use net::blocking_io::{self, Written};
 use net::client::connect_for_file;
 use net::client::path_exists;
 use net::client::BodyStreamer;
+use net::disk_health;
+use net::cache_journal::Journal;
+use net::disk_cache::{DiskCache, EvictionStats};
+use net::memory_cache::MemoryCache;
+use net::pins::{pinned_keys, referenced_keys, PinSets};
+use net::repo_entries;
+use net::tee_stream::TeeStream;
 use rand;
+use std::collections::{HashMap, HashSet};
 use std::error::Error as StdError;
 use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tempdir::TempDir;
 use tokio::timer::Delay;
 
+// Entries left where an earlier config kept them, e.g. in the cache folder itself from before
+// repo quotas were turned on, go into the default partition rather than sit there untracked
+fn move_stray_entries(config: &AppConfig, default_cache: &mut DiskCache) -> Result<(), String> {
+    let current: HashSet<PathBuf> = config
+        .partitions()
+        .into_iter()
+        .map(|(partition, _)| config.partition_folder(&partition))
+        .collect();
+    let mut folders = vec![PathBuf::from(&config.cache_folder)];
+    if let Ok(entries) = fs::read_dir(Path::new(&config.cache_folder).join("partitions")) {
+        folders.extend(entries.filter_map(|e| e.ok()).map(|e| e.path()));
+    }
+
+    for folder in folders.into_iter().filter(|e| !current.contains(e)) {
+        let mut moved = 0;
+        for entry in fs::read_dir(&folder).map_err(|e| e.to_string())? {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if !(file_name.starts_with("ac__") || file_name.starts_with("cas__"))
+                || !entry.file_type().map(|e| e.is_file()).unwrap_or(false)
+            {
+                continue;
+            }
+            if let Err(e) = default_cache.adopt_file(&file_name, &entry.path()) {
+                warn!("Dropping {:?} from the cache: {}", entry.path(), e);
+                fs::remove_file(entry.path()).unwrap_or(());
+                continue;
+            }
+            moved += 1;
+        }
+        Journal::discard(&folder);
+        if folder != Path::new(&config.cache_folder) {
+            fs::remove_dir(&folder).unwrap_or(());
+        }
+        if moved > 0 {
+            info!(
+                "Moved {} entries from {:?} into the default partition",
+                moved, folder
+            );
+            default_cache.checkpoint();
+        }
+    }
+    if config.repo_quotas.is_empty() {
+        fs::remove_dir(Path::new(&config.cache_folder).join("partitions")).unwrap_or(());
+    }
+    Ok(())
+}
+
+/// One `DiskCache` per partition, so a single large repo can't evict everything else.
+pub struct PartitionedCache {
+    config: AppConfig,
+    partitions: HashMap<String, DiskCache>,
+    pin_sets: PinSets,
+    // Every entry the pin sets cover, wherever it's cached
+    pins: HashSet<String>,
+    // Small entries we also hold in memory, kept in step with what's on disk
+    memory: MemoryCache,
+    // So we only warn once each time pinned entries outgrow --max-pinned-size
+    over_pinned_size: bool,
+}
+
+impl PartitionedCache {
+    fn new(config: &AppConfig) -> Result<Self, Box<StdError>> {
+        config.check_repo_quotas()?;
+        let pin_sets = PinSets::load(config)?;
+        let pins = pinned_keys(config, &pin_sets.digests());
+        let mut partitions = HashMap::new();
+        for (partition, budget) in config.partitions() {
+            let folder = config.partition_folder(&partition);
+            fs::create_dir_all(&folder)?;
+            let cache = DiskCache::new(
+                folder,
+                budget,
+                config.eviction_policy,
+                config.eviction_stats,
+                pins.clone(),
+            )?;
+            partitions.insert(partition, cache);
+        }
+        if let Some(cache) = partitions.get_mut(DEFAULT_PARTITION) {
+            move_stray_entries(config, cache)?;
+        }
+        let mut cache = PartitionedCache {
+            config: config.clone(),
+            partitions: partitions,
+            pin_sets: pin_sets,
+            pins: pins,
+            memory: MemoryCache::new(config),
+            over_pinned_size: false,
+        };
+        cache.check_pinned_size();
+        Ok(cache)
+    }
+
+    fn pinned_size(&self) -> u64 {
+        self.partitions.values().map(|e| e.pinned_usage().0).sum()
+    }
+
+    // Pins only grow past the limit as the entries they cover arrive, those we keep but say so
+    fn check_pinned_size(&mut self) {
+        let pinned_size = self.pinned_size();
+        if pinned_size <= self.config.max_pinned_size {
+            self.over_pinned_size = false;
+        } else if !self.over_pinned_size {
+            warn!(
+                "Pinned entries hold {} bytes, more than the {} allowed by --max-pinned-size",
+                pinned_size, self.config.max_pinned_size
+            );
+            self.over_pinned_size = true;
+        }
+    }
+
+    // Commit an entry a repo stored into that repo's partition
+    pub fn insert_file(
+        &mut self,
+        repo: &str,
+        file_name: &str,
+        file_path: String,
+        sha256: String,
+    ) -> Result<(), String> {
+        let partition = &self.config.partition_for(repo);
+        let cache = self.partitions
+            .get_mut(partition)
+            .ok_or_else(|| format!("Unknown cache partition {}", partition))?;
+        self.memory.remove(partition, file_name);
+
+        let evicted = cache.insert_file(file_name, file_path, sha256)?;
+        repo_entries::record(&self.config, repo, file_name);
+
+        // A pinned action cache entry pins the blobs it references too
+        if file_name.starts_with("ac__") && self.pins.contains(file_name) {
+            let referenced = referenced_keys(&cache.path(file_name));
+            if referenced.iter().any(|e| !self.pins.contains(e)) {
+                self.pins.extend(referenced);
+                let pins = self.pins.clone();
+                for cache in self.partitions.values_mut() {
+                    cache.set_pins(pins.clone());
+                }
+            }
+            self.check_pinned_size();
+            return Ok(());
+        }
+
+        if !evicted.is_empty() {
+            self.memory
+                .remove_where(|p, f| p == partition && evicted.iter().any(|e| e == f));
+            info!(
+                "Cache partition {} evicted {} entries, now using {} of {} bytes",
+                partition,
+                evicted.len(),
+                cache.size(),
+                cache.capacity()
+            );
+        }
+        if self.pins.contains(file_name) {
+            self.check_pinned_size();
+        }
+        Ok(())
+    }
+
+    pub fn remove_from(&mut self, partition: &str, file_name: &str) -> Result<(), String> {
+        self.memory.remove(partition, file_name);
+        match self.partitions.get_mut(partition) {
+            Some(ref mut cache) if cache.contains_key(file_name) => cache.remove(file_name),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn quarantine(&mut self, partition: &str, file_name: &str, dest: &Path) -> Result<(), String> {
+        self.memory.remove(partition, file_name);
+        let cache = self.partitions
+            .get_mut(partition)
+            .ok_or_else(|| format!("Unknown cache partition {}", partition))?;
+        cache.quarantine(file_name, dest)
+    }
+
+    // Evict an entry from whichever partitions hold it, returning how many did.
+    pub fn remove(&mut self, file_name: &str) -> Result<usize, String> {
+        self.memory.remove_where(|_, f| f == file_name);
+        let mut removed = 0;
+        for cache in self.partitions.values_mut() {
+            if cache.contains_key(file_name) {
+                cache.remove(file_name)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    pub fn checkpoint(&mut self) {
+        for cache in self.partitions.values_mut() {
+            cache.checkpoint();
+        }
+    }
+
+    // Evict every ac/cas entry in a partition, or in all of them.
+    // Evict an entry from a partition, deleting it even if we weren't tracking it. Returns
+    // whether there was one.
+    pub fn evict_from(&mut self, partition: &str, file_name: &str) -> Result<bool, String> {
+        self.memory.remove(partition, file_name);
+        match self.partitions.get_mut(partition) {
+            Some(ref mut cache) if cache.contains_key(file_name) => {
+                cache.remove(file_name).map(|_| true)
+            }
+            _ => {
+                let path = self.config.partition_folder(partition).join(file_name);
+                if !path.is_file() {
+                    return Ok(false);
+                }
+                fs::remove_file(path).map(|_| true).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    // Feed lookups to the eviction policies, hits are what most of them rank entries by
+    pub fn record_hit(&mut self, partition: &str, file_name: &str) {
+        if let Some(cache) = self.partitions.get_mut(partition) {
+            cache.record_hit(file_name);
+        }
+    }
+
+    pub fn record_miss(&mut self, partition: &str, file_name: &str) {
+        if let Some(cache) = self.partitions.get_mut(partition) {
+            cache.record_miss(file_name);
+        }
+    }
+
+    /// Bytes used and allowed for each partition, and bytes pinned on top of that.
+    pub fn usage(&self) -> Vec<(String, u64, u64, u64)> {
+        let mut usage: Vec<(String, u64, u64, u64)> = self.partitions
+            .iter()
+            .map(|(partition, cache)| {
+                (
+                    partition.clone(),
+                    cache.size(),
+                    cache.capacity(),
+                    cache.pinned_usage().0,
+                )
+            })
+            .collect();
+        usage.sort();
+        usage
+    }
+
+    pub fn pin_sets(&self) -> Vec<(String, Vec<String>, bool)> {
+        self.pin_sets.sets()
+    }
+
+    // Create or replace a pin set from a list of digests, returning how many it has
+    pub fn put_pin_set(
+        &mut self,
+        config: &AppConfig,
+        name: &str,
+        contents: &str,
+    ) -> Result<usize, String> {
+        // Only what's cached counts, entries arriving later are warned about instead
+        let pinned_size: u64 = pinned_keys(config, &self.pin_sets.digests_with(name, contents))
+            .iter()
+            .filter_map(|key| config.find_cached(key))
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|e| e.len())
+            .sum();
+        if pinned_size > config.max_pinned_size {
+            return Err(format!(
+                "Pin set {} would pin {} bytes, more than the {} allowed by --max-pinned-size",
+                name, pinned_size, config.max_pinned_size
+            ));
+        }
+        let len = self.pin_sets.put(name, contents)?;
+        self.refresh_pins(config);
+        Ok(len)
+    }
+
+    // Unpin a set's entries, they're evicted like any other from then on
+    pub fn remove_pin_set(&mut self, config: &AppConfig, name: &str) -> Result<bool, String> {
+        let removed = self.pin_sets.remove(name)?;
+        if removed {
+            self.refresh_pins(config);
+        }
+        Ok(removed)
+    }
+
+    fn refresh_pins(&mut self, config: &AppConfig) {
+        self.pins = pinned_keys(config, &self.pin_sets.digests());
+        for (partition, cache) in self.partitions.iter_mut() {
+            let evicted = cache.set_pins(self.pins.clone());
+            if !evicted.is_empty() {
+                self.memory
+                    .remove_where(|p, f| p == partition && evicted.iter().any(|e| e == f));
+            }
+        }
+        self.check_pinned_size();
+    }
+
+    /// Entries held and how each eviction policy has fared, for each partition.
+    pub fn eviction_stats(&self) -> Vec<(String, usize, Vec<(EvictionPolicy, bool, EvictionStats)>)> {
+        let mut stats: Vec<(String, usize, Vec<(EvictionPolicy, bool, EvictionStats)>)> = self
+            .partitions
+            .iter()
+            .map(|(partition, cache)| (partition.clone(), cache.len(), cache.stats()))
+            .collect();
+        stats.sort_by(|a, b| a.0.cmp(&b.0));
+        stats
+    }
+}
+
 pub struct Downloader {
     pub config: AppConfig,
     pub tmp_download_root: Arc<Mutex<TempDir>>,
-    pub lru_cache: Arc<Mutex<LruDiskCache>>,
+    pub lru_cache: Arc<Mutex<PartitionedCache>>,
+    // Shares its entries with `lru_cache`, so hits don't wait on the disk cache's lock
+    pub memory_cache: MemoryCache,
 }
 
 impl fmt::Debug for Downloader {
@@ -39,6 +363,7 @@ impl Clone for Downloader {
         Downloader {
             tmp_download_root: Arc::clone(&self.tmp_download_root),
             lru_cache: Arc::clone(&self.lru_cache),
+            memory_cache: self.memory_cache.clone(),
             config: self.config.clone(),
         }
     }
@@ -63,24 +388,79 @@ fn get_content_length(headers: &header::HeaderMap) -> Option<u64> {
     Some(header_size)
 }
 
+// The length of an upstream's answer we can stream to the client, None if there's nothing to stream
+fn streamable_length(
+    res: &Response<Body>,
+    maximum_download_size: u64,
+    req_uri: &Uri,
+    request_log: &RequestLog,
+) -> Option<u64> {
+    match (res.status(), get_content_length(res.headers())) {
+        (StatusCode::OK, Some(len)) if len <= maximum_download_size => Some(len),
+        (StatusCode::OK, Some(len)) => {
+            info!("Skipping download for {} since too large: {}", req_uri, len);
+            request_log.set_outcome(Outcome::TooLarge);
+            None
+        }
+        // Without a length we can't tell a truncated download from a complete one
+        (StatusCode::OK, None) => {
+            warn!("Content length not found for query");
+            request_log.set_outcome(Outcome::Error);
+            None
+        }
+        (StatusCode::NOT_FOUND, _) => None,
+        (status, _) => {
+            info!("Upstream returned {} for {}", status, req_uri);
+            request_log.set_outcome(Outcome::Error);
+            None
+        }
+    }
+}
+
+// What an upstream answered a GET with
+enum Fetched {
+    Downloaded(Written, String, Validators),
+    NotModified,
+    Missing,
+    // Too large, or an answer that doesn't tell us either way
+    Skipped,
+}
+
+/// How an upstream answered when asked about an entry we already have.
+pub enum Revalidation {
+    NotModified,
+    Replaced(u64),
+    Gone,
+    // We couldn't find out, so keep using what we have
+    Unknown,
+}
+
+// With `validators` the GET is conditional, and may come back as `NotModified`
 fn internal_fetch_file_with_retries<C: Connect + 'static>(
     maximum_download_size: u64,
     download_root: String,
     http_client: Client<C>,
     uri: Uri,
+    validators: Option<Validators>,
     tries: i32,
     sleep_duration: Duration,
     multiplier: u32,
-) -> Box<Future<Item = Option<(u64, String)>, Error = String> + Send + 'static> {
+    request_log: RequestLog,
+) -> Box<Future<Item = Fetched, Error = String> + Send + 'static> {
     let req_uri = uri.clone();
     let req_uri3 = uri.clone();
     let req_uri4 = uri.clone();
 
     let next_download_root = download_root.clone();
+    let next_validators = validators.clone();
+    let next_request_log = request_log.clone();
 
     let initial_file_response = connect_for_file(
         http_client.clone(),
         uri.clone(),
+        validators
+            .map(|e| e.conditional_headers())
+            .unwrap_or_default(),
         1,
         Duration::from_millis(500),
         1,
@@ -105,58 +485,83 @@ fn internal_fetch_file_with_retries<C: Connect + 'static>(
                         "Skipping download for {} since too large: {}",
                         req_uri4, siz
                     );
+                    request_log.set_outcome(Outcome::TooLarge);
                 }
                 ok_size
             }
         };
         match (should_download, res.status()) {
+            (_, StatusCode::NOT_MODIFIED) => Either::A(futures::future::ok(Fetched::NotModified)),
             (true, StatusCode::OK) => {
+                let validators = Validators::from_headers(res.headers());
                 let temp_file_name: u64 = rand::random();
 
                 let file_path =
                     ::std::path::Path::new(&download_root).join(temp_file_name.to_string());
 
-                let mut file = fs::File::create(&file_path).unwrap();
                 Either::B(
-                    BodyStreamer::new(res.into_body())
-                        .for_each(move |chunk| {
-                            file.write_all(&chunk).map_err(|e| {
-                                warn!("example expects stdout is open, error={}", e);
-                                panic!("example expects stdout is open, error={}", e)
-                            })
-                        })
-                        .map(move |_| {
-                            Some((
-                                content_length.unwrap(),
+                    blocking_io::write_stream(file_path.clone(), BodyStreamer::new(res.into_body()))
+                        .and_then(move |written| {
+                            let expected = content_length.unwrap();
+                            if written.len != expected {
+                                fs::remove_file(&file_path).unwrap_or(());
+                                return Err(format!(
+                                    "Download ended after {} of {} bytes",
+                                    written.len, expected
+                                ));
+                            }
+                            Ok(Fetched::Downloaded(
+                                written,
                                 file_path.to_string_lossy().to_string(),
+                                validators,
                             ))
-                        })
-                        .map_err(|e| e.to_string()),
+                        }),
                 )
             }
-            _ => Either::A(futures::future::ok(None)),
+            (_, StatusCode::NOT_FOUND) => Either::A(futures::future::ok(Fetched::Missing)),
+            (false, StatusCode::OK) => {
+                // Too large has its own outcome, this isn't the upstream not having it either
+                if content_length.is_none() {
+                    request_log.set_outcome(Outcome::Error);
+                }
+                Either::A(futures::future::ok(Fetched::Skipped))
+            }
+            // Not something we can say the upstream doesn't have
+            (_, status) => {
+                info!("Upstream returned {} for {}", status, req_uri4);
+                request_log.set_outcome(Outcome::Error);
+                Either::A(futures::future::ok(Fetched::Skipped))
+            }
         }
     }));
 
     if tries > 0 {
-        Box::new(fetch_fut.or_else(move |_| {
-            Delay::new(Instant::now() + sleep_duration)
-                .map_err(|_| "timeout error".to_string())
-                .and_then(move |_| {
-                    info!(
-                        "Going to preform retry fetching {} from remote cache, {} tries left",
-                        req_uri3, tries
-                    );
-                    internal_fetch_file_with_retries(
-                        maximum_download_size,
-                        next_download_root,
-                        http_client,
-                        uri,
-                        tries - 1,
-                        sleep_duration * multiplier,
-                        multiplier,
-                    )
-                })
+        Box::new(fetch_fut.or_else(move |e| {
+            // Retrying won't free up the cache disk, leave it to the caller to pass through
+            if disk_health::degraded().is_some() {
+                return Either::A(futures::future::err(e));
+            }
+            Either::B(
+                Delay::new(Instant::now() + sleep_duration)
+                    .map_err(|_| "timeout error".to_string())
+                    .and_then(move |_| {
+                        info!(
+                            "Going to preform retry fetching {} from remote cache, {} tries left",
+                            req_uri3, tries
+                        );
+                        internal_fetch_file_with_retries(
+                            maximum_download_size,
+                            next_download_root,
+                            http_client,
+                            uri,
+                            next_validators,
+                            tries - 1,
+                            sleep_duration * multiplier,
+                            multiplier,
+                            next_request_log,
+                        )
+                    }),
+            )
         }))
     } else {
         fetch_fut
@@ -166,65 +571,199 @@ fn internal_fetch_file_with_retries<C: Connect + 'static>(
 impl Downloader {
     /// Create a new, empty, instance of `Shared`.
     pub fn new(app_config: &AppConfig) -> Result<Self, Box<StdError>> {
-        let dir = TempDir::new("local_cache_proxy")?;
-        let cache = LruDiskCache::new(
-            app_config.cache_folder.clone(),
-            app_config.cache_folder_size,
-        )?;
+        fs::create_dir_all(app_config.tmp_folder())?;
+        let dir = TempDir::new_in(app_config.tmp_folder(), "local_cache_proxy")?;
+        let cache = PartitionedCache::new(app_config)?;
 
+        for (partition, size, capacity, pinned) in cache.usage() {
+            info!(
+                "Cache partition {} using {} of {} bytes, with {} bytes pinned",
+                partition, size, capacity, pinned
+            );
+        }
+
+        let memory_cache = cache.memory.clone();
+        let (_, memory_capacity) = memory_cache.usage();
+        if memory_capacity > 0 {
+            info!(
+                "Keeping up to {} bytes of entries no larger than {} bytes in memory",
+                memory_capacity, app_config.memory_cache_max_entry_size
+            );
+        }
         Ok(Downloader {
             tmp_download_root: Arc::new(Mutex::new(dir)),
+            memory_cache: memory_cache,
             lru_cache: Arc::new(Mutex::new(cache)),
             config: app_config.clone(),
         })
     }
 
+    pub fn partition_usage(self: &Self) -> Vec<(String, u64, u64, u64)> {
+        self.lru_cache.lock().unwrap().usage()
+    }
+
+    pub fn checkpoint(self: &Self) {
+        self.lru_cache.lock().unwrap().checkpoint();
+    }
+
+    pub fn evict_digest(self: &Self, digest: &str) -> Result<usize, String> {
+        let mut lru_cache = self.lru_cache.lock().unwrap();
+        let ac = lru_cache.remove(&format!("ac__{}", digest))?;
+        let cas = lru_cache.remove(&format!("cas__{}", digest))?;
+        Ok(ac + cas)
+    }
+
+    // Evict every entry in a partition, or in all of them. The folders are listed without
+    // holding the disk cache, which is only taken to remove each entry. Blocking, so best
+    // called on the pool.
+    pub fn clear(self: &Self, partition: Option<&str>) -> Result<usize, String> {
+        let mut removed = 0;
+        for (entry_partition, path) in self.config.cached_entries() {
+            if partition.map(|e| e != entry_partition).unwrap_or(false) {
+                continue;
+            }
+            let file_name = match path.file_name() {
+                Some(e) => e.to_string_lossy().to_string(),
+                None => continue,
+            };
+            if self.lru_cache
+                .lock()
+                .unwrap()
+                .evict_from(&entry_partition, &file_name)?
+            {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // Everything in a repo's own partition, or without one whatever the repo stored, which
+    // other repos may have used too. Blocking, so best called on the pool.
+    pub fn evict_repo(self: &Self, repo: &str) -> Result<usize, String> {
+        let removed = if self.config.repo_quotas.contains_key(repo) {
+            self.clear(Some(repo))?
+        } else {
+            let partition = self.config.partition_for(repo);
+            let mut removed = 0;
+            for file_name in repo_entries::entries(&self.config, repo) {
+                if self.lru_cache
+                    .lock()
+                    .unwrap()
+                    .evict_from(&partition, &file_name)?
+                {
+                    removed += 1;
+                }
+            }
+            removed
+        };
+        repo_entries::forget_repo(&self.config, repo);
+        Ok(removed)
+    }
+
+    // Somewhere to download to before handing the file to `insert_file`
+    pub fn temp_path(self: &Self) -> PathBuf {
+        self.tmp_download_root
+            .lock()
+            .unwrap()
+            .path()
+            .join(rand::random::<u64>().to_string())
+    }
+
+    // Commit a downloaded file to the cache, blocking so best called on the pool
+    pub fn insert_file(
+        self: &Self,
+        repo: &str,
+        file_name: &str,
+        file_path: &Path,
+        sha256: String,
+    ) -> Result<(), String> {
+        self.lru_cache.lock().unwrap().insert_file(
+            repo,
+            file_name,
+            file_path.to_string_lossy().to_string(),
+            sha256,
+        )
+    }
+
+    // Called on the event loop, where the disk cache may be held across disk IO on the pool
+    pub fn record_lookup(self: &Self, partition: &str, file_name: &str, hit: bool) {
+        if hit {
+            // Skipped when the disk cache is busy, e.g. moving a download in, rather than wait
+            if let Ok(mut lru_cache) = self.lru_cache.try_lock() {
+                lru_cache.record_hit(partition, file_name);
+            }
+        } else {
+            let lru_cache = Arc::clone(&self.lru_cache);
+            let partition = partition.to_string();
+            let file_name = file_name.to_string();
+            blocking_io::run(move || {
+                lru_cache
+                    .lock()
+                    .unwrap()
+                    .record_miss(&partition, &file_name);
+                Ok::<(), ()>(())
+            }).forget();
+        }
+    }
+
     pub fn save_file(
         self: &Self,
+        repo: &String,
         file_name: &String,
         req: Request<Body>,
     ) -> Box<Future<Item = Option<String>, Error = String> + Send> {
-        let tmp_download_root = &self.tmp_download_root;
         let file_name = file_name.clone();
-        let file_path = tmp_download_root
-            .lock()
-            .unwrap()
-            .path()
-            .join(file_name.clone());
+        let partition = self.config.partition_for(repo);
+        let repo = repo.clone();
+        // Not named after the entry, concurrent puts of it mustn't write to the same file
+        let file_path = self.temp_path();
 
-        let upload_path = ::std::path::Path::new(&self.config.cache_folder).join(&file_name);
+        let upload_path = self.config.cache_path(&repo, &file_name);
 
         let lru_cache_copy = Arc::clone(&self.lru_cache);
-
-        let mut file = fs::File::create(&file_path).unwrap();
+        let memory_cache = self.memory_cache.clone();
+        let keep_in_memory = ac_freshness::memory_servable(&self.config, &file_name);
+        let failed_path = file_path.clone();
 
         Box::new(
-            req.into_body()
-                .for_each(move |chunk| {
-                    file.write_all(&chunk)
-                        .map_err(|e| panic!("example expects stdout is open, error={}", e))
-                })
-                .map(move |_e| {
-                    let mut lru_cache = lru_cache_copy
-                        .lock()
-                        .map_err(|e| {
-                            error!(
-                                "Fail access lru cache for name: {}, path: {:?}, error: {:?}",
-                                file_name, file_path, e
-                            );
-                            e
-                        })
-                        .unwrap();
-                    if !path_exists(&upload_path) {
-                        lru_cache.insert_file(&file_name, file_path).unwrap();
-                        Some(file_name)
-                    } else {
-                        None
-                    }
+            blocking_io::write_stream(file_path.clone(), req.into_body())
+                .and_then(move |written| {
+                    blocking_io::run(move || {
+                        let mut lru_cache = lru_cache_copy
+                            .lock()
+                            .map_err(|e| {
+                                error!(
+                                    "Fail access lru cache for name: {}, path: {:?}, error: {:?}",
+                                    file_name, file_path, e
+                                );
+                                e
+                            })
+                            .unwrap();
+                        if !path_exists(&upload_path) {
+                            lru_cache.insert_file(
+                                &repo,
+                                &file_name,
+                                file_path.to_string_lossy().to_string(),
+                                written.sha256,
+                            )?;
+                            // Likely to be asked for again soon, e.g. an action cache entry
+                            if keep_in_memory && memory_cache.fits(written.len) {
+                                if let Ok(contents) = fs::read(&upload_path) {
+                                    memory_cache.insert(&partition, &file_name, Bytes::from(contents));
+                                }
+                            }
+                            Ok(Some(file_name))
+                        } else {
+                            fs::remove_file(&file_path).unwrap_or(());
+                            Ok(None)
+                        }
+                    })
                 })
-                .map_err(|e| {
+                .map_err(move |e| {
                     warn!("Inner downloader error: {:?}", e);
-                    e.to_string()
+                    // Nothing else will ever clean up after a put that didn't make it in
+                    fs::remove_file(&failed_path).unwrap_or(());
+                    e
                 }),
         )
     }
@@ -232,8 +771,11 @@ impl Downloader {
     pub fn fetch_file<'a, C: Connect + 'static>(
         self: &Self,
         http_client: &Client<C>,
+        upstream: &UpstreamConfig,
         uri: &Uri,
+        repo: &String,
         file_name: &String,
+        request_log: &RequestLog,
     ) -> Box<Future<Item = Option<u64>, Error = String> + Send> {
         debug!("Querying for uri: {:?}", uri);
 
@@ -251,35 +793,49 @@ impl Downloader {
         };
 
         let fetched_fut = internal_fetch_file_with_retries(
-            self.config.maximum_download_size,
+            upstream.maximum_download_size,
             download_root,
             http_client.clone(),
             uri.clone(),
-            3,
+            None,
+            upstream.retries,
             Duration::from_millis(20000),
             2,
+            request_log.clone(),
         );
 
         let lru_cache_copy = Arc::clone(&self.lru_cache);
+        let config = self.config.clone();
+        let repo = repo.clone();
         let req_uri3 = uri.clone();
         let req_uri4 = uri.clone();
+        let upstream_uri = upstream.uri.clone();
 
+        // Committing syncs the journal and may evict, so it happens on the blocking pool
         Box::new(
             fetched_fut
-                .map(move |file_path_opt| {
+                .and_then(move |fetched| {
                     debug!("Finished operating on uri: {:?}", req_uri3);
-                    match file_path_opt {
-                        None => Ok(None),
-                        Some((file_size, file_path)) => {
+                    blocking_io::run(move || match fetched {
+                        Fetched::Downloaded(written, file_path, validators) => {
+                            let file_size = written.len;
                             let mut lru_cache = lru_cache_copy.lock().unwrap();
                             lru_cache
-                                .insert_file(&file_name, file_path)
-                                .map_err(|e| e.description().to_string())
-                                .map(move |_| Some(file_size))
+                                .insert_file(&repo, &file_name, file_path, written.sha256)
+                                .map(|_| {
+                                    ac_freshness::record_validated(
+                                        &config,
+                                        &repo,
+                                        &file_name,
+                                        &upstream_uri,
+                                        &validators,
+                                    );
+                                    Some(file_size)
+                                })
                         }
-                    }
+                        _ => Ok(None),
+                    })
                 })
-                .and_then(|e| futures::future::result(e))
                 .map_err(move |e| {
                     warn!(
                         "Failed operating on uri: {:?}, with error: {:?}",
@@ -289,4 +845,258 @@ impl Downloader {
                 }),
         )
     }
+
+    // Like `fetch_file`, but the body is handed back as soon as the upstream starts sending it,
+    // while it's also written to the cache. Resolves to None when there's nothing to stream.
+    pub fn tee_file<C: Connect + 'static>(
+        self: &Self,
+        http_client: &Client<C>,
+        upstream: &UpstreamConfig,
+        uri: &Uri,
+        repo: &String,
+        file_name: &String,
+        request_log: &RequestLog,
+    ) -> Box<Future<Item = Option<(u64, Body)>, Error = String> + Send> {
+        let file_path = self.tmp_download_root
+            .lock()
+            .unwrap()
+            .path()
+            .join(rand::random::<u64>().to_string());
+        let maximum_download_size = upstream.maximum_download_size;
+        let lru_cache_copy = Arc::clone(&self.lru_cache);
+        let repo = repo.clone();
+        let file_name = file_name.clone();
+        let request_log = request_log.clone();
+        let req_uri = uri.clone();
+
+        Box::new(
+            connect_for_file(
+                http_client.clone(),
+                uri.clone(),
+                HeaderMap::new(),
+                upstream.retries,
+                Duration::from_millis(20000),
+                2,
+            ).map(move |res| {
+                let len = match streamable_length(
+                    &res,
+                    maximum_download_size,
+                    &req_uri,
+                    &request_log,
+                ) {
+                    Some(len) => len,
+                    None => return None,
+                };
+                let (sender, body) = Body::channel();
+                let tee = TeeStream::new(BodyStreamer::new(res.into_body()), sender, len);
+
+                let discard_path = file_path.clone();
+                hyper::rt::spawn(
+                    blocking_io::write_stream(file_path.clone(), tee)
+                        .and_then(move |written| {
+                            blocking_io::run(move || {
+                                if written.len != len {
+                                    return Err(format!(
+                                        "Download ended after {} of {} bytes",
+                                        written.len, len
+                                    ));
+                                }
+                                lru_cache_copy.lock().unwrap().insert_file(
+                                    &repo,
+                                    &file_name,
+                                    file_path.to_string_lossy().to_string(),
+                                    written.sha256,
+                                )
+                            })
+                        })
+                        .map_err(move |e| {
+                            warn!("Failed streaming {:?} from upstream: {}", req_uri, e);
+                            fs::remove_file(&discard_path).unwrap_or(());
+                        }),
+                );
+                Some((len, body))
+            }),
+        )
+    }
+
+    // Stream an entry from the upstream without caching it, for when the cache disk is failing.
+    // Resolves to None when there's nothing to stream.
+    pub fn pass_through_file<C: Connect + 'static>(
+        self: &Self,
+        http_client: &Client<C>,
+        upstream: &UpstreamConfig,
+        uri: &Uri,
+        request_log: &RequestLog,
+    ) -> Box<Future<Item = Option<(u64, Body)>, Error = String> + Send> {
+        let maximum_download_size = upstream.maximum_download_size;
+        let request_log = request_log.clone();
+        let req_uri = uri.clone();
+
+        Box::new(
+            connect_for_file(
+                http_client.clone(),
+                uri.clone(),
+                HeaderMap::new(),
+                upstream.retries,
+                Duration::from_millis(20000),
+                2,
+            ).map(move |res| {
+                streamable_length(&res, maximum_download_size, &req_uri, &request_log)
+                    .map(|len| (len, res.into_body()))
+            }),
+        )
+    }
+
+    // Fetch a CAS blob from a LAN peer, only accepting it if it matches its digest.
+    pub fn fetch_peer_file<C: Connect + 'static>(
+        self: &Self,
+        http_client: &Client<C>,
+        uri: &Uri,
+        repo: &String,
+        file_name: &String,
+        expected_digest: &String,
+        request_log: &RequestLog,
+    ) -> Box<Future<Item = Option<u64>, Error = String> + Send> {
+        let file_name = file_name.clone();
+        let repo = repo.clone();
+        let expected_digest = expected_digest.clone();
+
+        let download_root = {
+            self.tmp_download_root
+                .lock()
+                .unwrap()
+                .path()
+                .to_string_lossy()
+                .to_string()
+        };
+
+        // Peers stand in for the repo's upstreams, so take no more than they would give us
+        let maximum_download_size = self.config
+            .upstreams_for(&repo)
+            .iter()
+            .map(|e| e.maximum_download_size)
+            .max()
+            .unwrap_or(self.config.maximum_download_size);
+
+        // Peers are on the local network so no retries, on any failure we just move on to the
+        // next peer/upstream.
+        let fetched_fut = internal_fetch_file_with_retries(
+            maximum_download_size,
+            download_root,
+            http_client.clone(),
+            uri.clone(),
+            None,
+            0,
+            Duration::from_millis(500),
+            1,
+            request_log.clone(),
+        );
+
+        let lru_cache_copy = Arc::clone(&self.lru_cache);
+        let req_uri = uri.clone();
+
+        // Hashing the whole blob is slow, so it happens on the blocking pool
+        Box::new(fetched_fut.and_then(move |fetched| match fetched {
+            Fetched::Downloaded(written, file_path, _) => Either::A(blocking_io::run(move || {
+                let file_size = written.len;
+                // Hashed as it was written, no need to read it back
+                if !written.sha256.eq_ignore_ascii_case(&expected_digest) {
+                    warn!(
+                        "Peer {:?} returned content not matching digest {}, discarding",
+                        req_uri, expected_digest
+                    );
+                    fs::remove_file(&file_path).unwrap_or(());
+                    return Ok(None);
+                }
+                let mut lru_cache = lru_cache_copy.lock().unwrap();
+                lru_cache
+                    .insert_file(&repo, &file_name, file_path, written.sha256)
+                    .map(move |_| Some(file_size))
+            })),
+            _ => Either::B(futures::future::ok(None)),
+        }))
+    }
+
+    // Check an entry we already have is still what the upstream has, replacing or dropping
+    // it if not. Without validators this is just a fetch of the current version.
+    pub fn revalidate_file<C: Connect + 'static>(
+        self: &Self,
+        http_client: &Client<C>,
+        upstream: &UpstreamConfig,
+        uri: &Uri,
+        repo: &String,
+        file_name: &String,
+        validators: Option<Validators>,
+        request_log: &RequestLog,
+    ) -> Box<Future<Item = Revalidation, Error = String> + Send> {
+        let download_root = {
+            self.tmp_download_root
+                .lock()
+                .unwrap()
+                .path()
+                .to_string_lossy()
+                .to_string()
+        };
+
+        let fetched_fut = internal_fetch_file_with_retries(
+            upstream.maximum_download_size,
+            download_root,
+            http_client.clone(),
+            uri.clone(),
+            validators.clone(),
+            upstream.retries,
+            Duration::from_millis(20000),
+            2,
+            request_log.clone(),
+        );
+
+        let lru_cache_copy = Arc::clone(&self.lru_cache);
+        let config = self.config.clone();
+        let partition = self.config.partition_for(repo);
+        let repo = repo.clone();
+        let file_name = file_name.clone();
+        let upstream_uri = upstream.uri.clone();
+
+        // Replacing or dropping the entry touches the journal, so it happens on the blocking pool
+        Box::new(fetched_fut.and_then(move |fetched| blocking_io::run(move || match fetched {
+            Fetched::NotModified => {
+                ac_freshness::record_validated(
+                    &config,
+                    &repo,
+                    &file_name,
+                    &upstream_uri,
+                    &validators.unwrap_or_default(),
+                );
+                Ok(Revalidation::NotModified)
+            }
+            Fetched::Downloaded(written, file_path, validators) => {
+                let mut lru_cache = lru_cache_copy.lock().unwrap();
+                lru_cache.insert_file(&repo, &file_name, file_path, written.sha256)?;
+                ac_freshness::record_validated(
+                    &config,
+                    &repo,
+                    &file_name,
+                    &upstream_uri,
+                    &validators,
+                );
+                Ok(Revalidation::Replaced(written.len))
+            }
+            // Written locally since we checked, the upload may still be queued, or filled from
+            // another upstream, which may well still have it
+            Fetched::Missing
+                if ac_freshness::validated_by(&config, &repo, &file_name)
+                    != Some(upstream_uri.to_string()) =>
+            {
+                Ok(Revalidation::Unknown)
+            }
+            Fetched::Missing => {
+                info!("Upstream no longer has {}, dropping it", file_name);
+                let mut lru_cache = lru_cache_copy.lock().unwrap();
+                lru_cache.remove_from(&partition, &file_name)?;
+                ac_freshness::forget(&config, &repo, &file_name);
+                Ok(Revalidation::Gone)
+            }
+            Fetched::Skipped => Ok(Revalidation::Unknown),
+        })))
+    }
 }
@@ -0,0 +1,551 @@
+use config::EvictionPolicy;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+
+/// Decides which entry a cache partition gives up next. Policies only see keys and sizes,
+/// the cache keeps track of what it holds and does the evicting.
+pub trait Policy: Send {
+    // A new entry, or one replaced with new contents
+    fn insert(&mut self, key: &str, size: u64);
+    // A hit on an entry we hold
+    fn touch(&mut self, key: &str);
+    // Gone for some reason other than us evicting it, e.g. evicted by hand
+    fn remove(&mut self, key: &str);
+    // Pick the next entry to evict and forget it, None once we hold nothing
+    fn evict(&mut self) -> Option<String>;
+}
+
+pub fn new_policy(policy: EvictionPolicy, capacity: u64) -> Box<Policy> {
+    match policy {
+        EvictionPolicy::Lru => Box::new(Lru::new()),
+        EvictionPolicy::Gdsf => Box::new(Gdsf::new()),
+        EvictionPolicy::Arc => Box::new(AdaptiveReplacement::new(capacity)),
+        EvictionPolicy::LruAdmission => Box::new(LruAdmission::new(capacity)),
+    }
+}
+
+// Keys in order of use
+struct Recency {
+    next_mru: u64,
+    by_key: HashMap<String, u64>,
+    by_use: BTreeMap<u64, String>,
+}
+
+impl Recency {
+    fn new() -> Recency {
+        Recency {
+            next_mru: 0,
+            by_key: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.by_key.contains_key(key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.by_key.is_empty()
+    }
+
+    fn push_mru(&mut self, key: &str) {
+        self.remove(key);
+        let tick = self.next_mru;
+        self.next_mru += 1;
+        self.by_key.insert(key.to_string(), tick);
+        self.by_use.insert(tick, key.to_string());
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.by_key.remove(key) {
+            Some(tick) => {
+                self.by_use.remove(&tick);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_lru(&mut self) -> Option<String> {
+        let tick = match self.by_use.keys().next() {
+            Some(&tick) => tick,
+            None => return None,
+        };
+        let key = self.by_use.remove(&tick).unwrap();
+        self.by_key.remove(&key);
+        Some(key)
+    }
+}
+
+pub struct Lru {
+    recency: Recency,
+}
+
+impl Lru {
+    pub fn new() -> Lru {
+        Lru {
+            recency: Recency::new(),
+        }
+    }
+}
+
+impl Policy for Lru {
+    fn insert(&mut self, key: &str, _size: u64) {
+        self.recency.push_mru(key);
+    }
+
+    fn touch(&mut self, key: &str) {
+        if self.recency.contains(key) {
+            self.recency.push_mru(key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.recency.remove(key);
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        self.recency.pop_lru()
+    }
+}
+
+// Priorities are non-negative, so their bits order the same way they do
+fn priority_key(priority: f64, seq: u64) -> (u64, u64) {
+    (priority.to_bits(), seq)
+}
+
+/// Greedy-Dual-Size-Frequency: each entry is worth how often it's used per byte it takes,
+/// plus an inflation value that ages out entries which were once popular.
+pub struct Gdsf {
+    inflation: f64,
+    seq: u64,
+    // key -> (uses, size, queue position)
+    entries: HashMap<String, (u64, u64, (u64, u64))>,
+    queue: BTreeMap<(u64, u64), String>,
+}
+
+impl Gdsf {
+    pub fn new() -> Gdsf {
+        Gdsf {
+            inflation: 0.0,
+            seq: 0,
+            entries: HashMap::new(),
+            queue: BTreeMap::new(),
+        }
+    }
+
+    fn requeue(&mut self, key: &str, uses: u64, size: u64) {
+        if let Some((_, _, position)) = self.entries.remove(key) {
+            self.queue.remove(&position);
+        }
+        let priority = self.inflation + uses as f64 / cmp::max(size, 1) as f64;
+        let position = priority_key(priority, self.seq);
+        self.seq += 1;
+        self.queue.insert(position, key.to_string());
+        self.entries.insert(key.to_string(), (uses, size, position));
+    }
+}
+
+impl Policy for Gdsf {
+    fn insert(&mut self, key: &str, size: u64) {
+        let uses = self.entries.get(key).map(|e| e.0).unwrap_or(0) + 1;
+        self.requeue(key, uses, size);
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(&(uses, size, _)) = self.entries.get(key) {
+            self.requeue(key, uses + 1, size);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((_, _, position)) = self.entries.remove(key) {
+            self.queue.remove(&position);
+        }
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let position = match self.queue.keys().next() {
+            Some(&position) => position,
+            None => return None,
+        };
+        let key = self.queue.remove(&position).unwrap();
+        self.entries.remove(&key);
+        self.inflation = f64::from_bits(position.0);
+        Some(key)
+    }
+}
+
+/// ARC, sized in bytes: entries used once and entries used again are kept in separate lists,
+/// and hits on recently evicted ones shift how much room each list gets.
+pub struct AdaptiveReplacement {
+    capacity: u64,
+    // Bytes we aim to give entries only used once
+    target: u64,
+    once: Recency,
+    again: Recency,
+    once_evicted: Recency,
+    again_evicted: Recency,
+    sizes: HashMap<String, u64>,
+    once_size: u64,
+    again_size: u64,
+    once_evicted_size: u64,
+    again_evicted_size: u64,
+}
+
+impl AdaptiveReplacement {
+    pub fn new(capacity: u64) -> AdaptiveReplacement {
+        AdaptiveReplacement {
+            capacity: capacity,
+            target: 0,
+            once: Recency::new(),
+            again: Recency::new(),
+            once_evicted: Recency::new(),
+            again_evicted: Recency::new(),
+            sizes: HashMap::new(),
+            once_size: 0,
+            again_size: 0,
+            once_evicted_size: 0,
+            again_evicted_size: 0,
+        }
+    }
+
+    // Forget the key wherever it is, returning its size
+    fn take(&mut self, key: &str) -> u64 {
+        let size = self.sizes.remove(key).unwrap_or(0);
+        if self.once.remove(key) {
+            self.once_size -= size;
+        } else if self.again.remove(key) {
+            self.again_size -= size;
+        } else if self.once_evicted.remove(key) {
+            self.once_evicted_size -= size;
+        } else if self.again_evicted.remove(key) {
+            self.again_evicted_size -= size;
+        }
+        size
+    }
+
+    fn push_again(&mut self, key: &str, size: u64) {
+        self.sizes.insert(key.to_string(), size);
+        self.again.push_mru(key);
+        self.again_size += size;
+    }
+
+    // Remember no more evicted entries than we have room for
+    fn trim_evicted(&mut self) {
+        while self.once_size + self.once_evicted_size > self.capacity {
+            match self.once_evicted.pop_lru() {
+                Some(key) => self.once_evicted_size -= self.sizes.remove(&key).unwrap_or(0),
+                None => break,
+            }
+        }
+        while self.once_size + self.again_size + self.once_evicted_size + self.again_evicted_size
+            > 2 * self.capacity
+        {
+            match self.again_evicted.pop_lru() {
+                Some(key) => self.again_evicted_size -= self.sizes.remove(&key).unwrap_or(0),
+                None => break,
+            }
+        }
+    }
+}
+
+impl Policy for AdaptiveReplacement {
+    fn insert(&mut self, key: &str, size: u64) {
+        if self.once_evicted.contains(key) {
+            // We gave up on it too early, give entries used once more room
+            let delta = cmp::max(self.again_evicted_size / cmp::max(self.once_evicted_size, 1), 1);
+            self.target = cmp::min(self.capacity, self.target + delta * size);
+            self.take(key);
+            self.push_again(key, size);
+        } else if self.again_evicted.contains(key) {
+            let delta = cmp::max(self.once_evicted_size / cmp::max(self.again_evicted_size, 1), 1);
+            self.target = self.target.saturating_sub(delta * size);
+            self.take(key);
+            self.push_again(key, size);
+        } else if self.once.contains(key) || self.again.contains(key) {
+            self.take(key);
+            self.push_again(key, size);
+        } else {
+            self.sizes.insert(key.to_string(), size);
+            self.once.push_mru(key);
+            self.once_size += size;
+        }
+        self.trim_evicted();
+    }
+
+    fn touch(&mut self, key: &str) {
+        if self.once.contains(key) || self.again.contains(key) {
+            let size = self.take(key);
+            self.push_again(key, size);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.take(key);
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let from_once =
+            !self.once.is_empty() && (self.once_size > self.target || self.again.is_empty());
+        let key = if from_once {
+            self.once.pop_lru()
+        } else {
+            self.again.pop_lru()
+        };
+        let key = match key {
+            Some(e) => e,
+            None => return None,
+        };
+        let size = self.sizes.get(&key).cloned().unwrap_or(0);
+        if from_once {
+            self.once_size -= size;
+            self.once_evicted.push_mru(&key);
+            self.once_evicted_size += size;
+        } else {
+            self.again_size -= size;
+            self.again_evicted.push_mru(&key);
+            self.again_evicted_size += size;
+        }
+        self.trim_evicted();
+        Some(key)
+    }
+}
+
+const SKETCH_ROWS: usize = 4;
+const SKETCH_WIDTH: usize = 1 << 16;
+const SKETCH_MAX: u8 = 15;
+
+// Approximate use counts in fixed memory, halved every so often so old popularity fades
+struct FrequencySketch {
+    counters: Vec<u8>,
+    additions: usize,
+}
+
+impl FrequencySketch {
+    fn new() -> FrequencySketch {
+        FrequencySketch {
+            counters: vec![0; SKETCH_ROWS * SKETCH_WIDTH],
+            additions: 0,
+        }
+    }
+
+    fn slots(key: &str) -> Vec<usize> {
+        (0..SKETCH_ROWS)
+            .map(|row| {
+                let mut hasher = DefaultHasher::new();
+                row.hash(&mut hasher);
+                key.hash(&mut hasher);
+                row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH)
+            })
+            .collect()
+    }
+
+    fn increment(&mut self, key: &str) {
+        for slot in FrequencySketch::slots(key) {
+            self.counters[slot] = cmp::min(self.counters[slot] + 1, SKETCH_MAX);
+        }
+        self.additions += 1;
+        if self.additions >= 10 * SKETCH_WIDTH {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions = 0;
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u8 {
+        FrequencySketch::slots(key)
+            .into_iter()
+            .map(|slot| self.counters[slot])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// LRU with a frequency based admission filter: entries we've no record of seeing before wait
+/// on probation and are evicted first, so a one off flood, e.g. a clean build of something
+/// nobody builds, only displaces itself.
+pub struct LruAdmission {
+    // At most this many bytes are protected, the rest of the partition is probation
+    protected_capacity: u64,
+    probation: Recency,
+    protected: Recency,
+    sizes: HashMap<String, u64>,
+    protected_size: u64,
+    sketch: FrequencySketch,
+}
+
+impl LruAdmission {
+    pub fn new(capacity: u64) -> LruAdmission {
+        LruAdmission {
+            protected_capacity: capacity / 5 * 4,
+            probation: Recency::new(),
+            protected: Recency::new(),
+            sizes: HashMap::new(),
+            protected_size: 0,
+            sketch: FrequencySketch::new(),
+        }
+    }
+
+    fn protect(&mut self, key: &str) {
+        let size = self.sizes.get(key).cloned().unwrap_or(0);
+        self.probation.remove(key);
+        if !self.protected.contains(key) {
+            self.protected_size += size;
+        }
+        self.protected.push_mru(key);
+        // Demote what's gone longest unused, it has to earn its place back
+        while self.protected_size > self.protected_capacity {
+            match self.protected.pop_lru() {
+                Some(demoted) => {
+                    self.protected_size -= self.sizes.get(&demoted).cloned().unwrap_or(0);
+                    self.probation.push_mru(&demoted);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Policy for LruAdmission {
+    fn insert(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        self.sketch.increment(key);
+        self.sizes.insert(key.to_string(), size);
+        if self.sketch.estimate(key) > 1 {
+            self.protect(key);
+        } else {
+            self.probation.push_mru(key);
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.sketch.increment(key);
+        if self.sizes.contains_key(key) {
+            self.protect(key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(size) = self.sizes.remove(key) {
+            self.probation.remove(key);
+            if self.protected.remove(key) {
+                self.protected_size -= size;
+            }
+        }
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let key = match self.probation.pop_lru() {
+            Some(e) => e,
+            None => {
+                let key = self.protected.pop_lru()?;
+                self.protected_size -= self.sizes.get(&key).cloned().unwrap_or(0);
+                key
+            }
+        };
+        self.sizes.remove(&key);
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evict_all(policy: &mut Policy) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while let Some(key) = policy.evict() {
+            evicted.push(key);
+        }
+        evicted
+    }
+
+    #[test]
+    fn every_policy_evicts_each_entry_once() {
+        for kind in EvictionPolicy::all() {
+            let mut policy = new_policy(kind, 1000);
+            for key in &["a", "b", "c", "d"] {
+                policy.insert(key, 10);
+            }
+            policy.touch("b");
+            policy.remove("c");
+            let mut evicted = evict_all(&mut *policy);
+            evicted.sort();
+            assert_eq!(evicted, vec!["a", "b", "d"], "{:?}", kind);
+            assert_eq!(policy.evict(), None, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new();
+        lru.insert("a", 10);
+        lru.insert("b", 10);
+        lru.insert("c", 10);
+        lru.touch("a");
+        assert_eq!(evict_all(&mut lru), vec!["b", "c", "a"]);
+    }
+
+    #[test]
+    fn gdsf_evicts_large_rarely_used_entries_first() {
+        let mut gdsf = Gdsf::new();
+        gdsf.insert("small", 10);
+        gdsf.insert("large", 1000);
+        gdsf.insert("popular", 1000);
+        gdsf.touch("popular");
+        gdsf.touch("popular");
+        assert_eq!(gdsf.evict(), Some("large".to_string()));
+        assert_eq!(gdsf.evict(), Some("popular".to_string()));
+        assert_eq!(gdsf.evict(), Some("small".to_string()));
+    }
+
+    #[test]
+    fn arc_keeps_entries_used_again_through_a_scan() {
+        let mut arc = AdaptiveReplacement::new(100);
+        arc.insert("hot", 10);
+        arc.touch("hot");
+        for i in 0..20 {
+            arc.insert(&format!("scan{}", i), 10);
+            if i >= 9 {
+                let victim = arc.evict().unwrap();
+                assert_ne!(victim, "hot");
+            }
+        }
+        assert!(arc.again.contains("hot"));
+        assert_eq!(arc.once_size + arc.again_size, 100);
+    }
+
+    #[test]
+    fn lru_admission_evicts_probation_first() {
+        let mut admission = LruAdmission::new(100);
+        admission.insert("seen", 10);
+        admission.touch("seen");
+        admission.insert("once", 10);
+        assert_eq!(admission.evict(), Some("once".to_string()));
+        assert_eq!(admission.evict(), Some("seen".to_string()));
+        assert_eq!(admission.evict(), None);
+    }
+
+    #[test]
+    fn lru_admission_accounts_for_protected_evictions() {
+        let mut admission = LruAdmission::new(100);
+        for round in 0..3 {
+            for i in 0..4 {
+                let key = format!("{}-{}", round, i);
+                admission.insert(&key, 20);
+                admission.touch(&key);
+            }
+            evict_all(&mut admission);
+            assert_eq!(admission.protected_size, 0);
+            assert!(admission.sizes.is_empty());
+        }
+        // With nothing held, a protected entry stays protected
+        admission.insert("kept", 20);
+        admission.touch("kept");
+        assert!(admission.protected.contains("kept"));
+    }
+}
@@ -0,0 +1,155 @@
+use config::AppConfig;
+use hyper::Uri as HyperUri;
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// How long we keep trying for the cache folder's lock before deciding it's in use
+const LOCK_ATTEMPTS: usize = 20;
+const LOCK_RETRY_MS: u64 = 50;
+
+fn lock_path(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("proxy.lock")
+}
+
+fn pid_path(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("proxy.pid")
+}
+
+fn try_flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::WouldBlock {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+// `running_instance` probes by taking a shared lock for a moment, e.g. `--ensure` polling while
+// the proxy it started gets going, so keep trying for a little before deciding it's in use
+fn lock_exclusive(file: &File) -> io::Result<bool> {
+    for _ in 0..LOCK_ATTEMPTS {
+        if try_flock(file, libc::LOCK_EX)? {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(LOCK_RETRY_MS));
+    }
+    Ok(false)
+}
+
+/// A proxy that currently owns a cache folder.
+#[derive(Debug)]
+pub struct RunningInstance {
+    pub pid: u32,
+    pub bind_target: String,
+}
+
+/// Held for the lifetime of a proxy, only one proxy may use a cache folder at a time.
+pub struct InstanceLock {
+    _file: File,
+    pid_path: PathBuf,
+}
+
+impl InstanceLock {
+    pub fn acquire(config: &AppConfig) -> Result<InstanceLock, String> {
+        fs::create_dir_all(&config.cache_folder).map_err(|e| e.to_string())?;
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(lock_path(config))
+            .map_err(|e| e.to_string())?;
+
+        if !lock_exclusive(&file).map_err(|e| e.to_string())? {
+            return Err(match running_instance(config) {
+                Some(running) => format!(
+                    "Cache folder {} is already in use by pid {} serving {}",
+                    config.cache_folder, running.pid, running.bind_target
+                ),
+                None => format!("Cache folder {} is already in use", config.cache_folder),
+            });
+        }
+
+        let pid_path = pid_path(config);
+        let mut pid_file = File::create(&pid_path).map_err(|e| e.to_string())?;
+        writeln!(pid_file, "{}\n{}", process::id(), config.bind_target)
+            .map_err(|e| e.to_string())?;
+
+        Ok(InstanceLock {
+            _file: file,
+            pid_path: pid_path,
+        })
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.pid_path).unwrap_or(());
+    }
+}
+
+// The proxy holding the cache folder's lock, if there is one.
+pub fn running_instance(config: &AppConfig) -> Option<RunningInstance> {
+    let file = File::open(lock_path(config)).ok()?;
+    // If we can take the lock nobody else has it
+    if try_flock(&file, libc::LOCK_SH).unwrap_or(true) {
+        return None;
+    }
+
+    let mut contents = String::new();
+    File::open(pid_path(config))
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .ok()?;
+    let mut lines = contents.lines();
+    let pid = lines.next().and_then(|e| e.parse().ok())?;
+    let bind_target = lines.next()?.to_string();
+    Some(RunningInstance {
+        pid: pid,
+        bind_target: bind_target,
+    })
+}
+
+fn health_check<S: Read + Write>(mut stream: S) -> io::Result<bool> {
+    stream.write_all(b"GET /health HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response.starts_with("HTTP/1.1 200") || response.starts_with("HTTP/1.0 200"))
+}
+
+// Whether a proxy is serving on this bind target and not shutting down.
+pub fn is_healthy(bind_target: &HyperUri) -> bool {
+    let timeout = Duration::from_millis(2000);
+    let result = match bind_target.scheme() {
+        Some("unix") => UnixStream::connect(bind_target.authority().unwrap_or("")).and_then(
+            |stream| {
+                stream.set_read_timeout(Some(timeout))?;
+                health_check(stream)
+            },
+        ),
+        // A bind target that swallows connections mustn't hang whoever is asking
+        _ => (
+            bind_target.host().unwrap_or("127.0.0.1"),
+            bind_target.port().unwrap_or(80),
+        ).to_socket_addrs()
+            .and_then(|mut addrs| {
+                addrs.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "Bind target has no address")
+                })
+            })
+            .and_then(|addr| TcpStream::connect_timeout(&addr, timeout))
+            .and_then(|stream| {
+                stream.set_read_timeout(Some(timeout))?;
+                health_check(stream)
+            }),
+    };
+    result.unwrap_or(false)
+}
@@ -0,0 +1,245 @@
+use bytes::Buf;
+use futures::{Async, Future, Poll};
+use futures_cpupool::CpuFuture;
+use hyper::body::Payload;
+use hyper::{Body, Chunk};
+use libc;
+use net::blocking_io;
+use std::cmp;
+use std::error::Error as StdError;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+// Below this, mapping costs more than the copies it saves
+pub const MIN_MAPPED_SIZE: u64 = 64 * 1024;
+
+// How much of the mapping we hand hyper at a time
+const MAPPED_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Touching one byte in every page this far apart reads in the whole range
+const PAGE_SIZE: usize = 4096;
+
+// Failing a body aborts the connection, so the client can't take a short body as the whole
+pub type BodyError = Box<StdError + Send + Sync>;
+
+/// A cached blob mapped read only into memory, unmapped once nothing is sending from it.
+///
+/// Truncating or rewriting a mapped file in place takes away pages we've yet to read, and
+/// touching them kills the process with SIGBUS. Cache entries are only ever unlinked (eviction,
+/// pruning quarantine) or renamed (commits, quarantine), never written to once committed, and
+/// anything new that touches them has to keep to that.
+pub struct MappedFile {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+// Nothing ever writes through the mapping
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+    // Renaming over an entry or unlinking it leaves the mapping with the file we opened.
+    // A private mapping wouldn't help, pages we haven't copied are still the file's.
+    pub fn from_file(file: &File) -> io::Result<MappedFile> {
+        let len = file.metadata()?.len() as usize;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Can't map an empty file",
+            ));
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Start reading it in now, so sending it doesn't wait on the disk a page at a time
+        unsafe {
+            libc::madvise(ptr, len, libc::MADV_WILLNEED);
+        }
+        Ok(MappedFile { ptr: ptr, len: len })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    // Fault in every page of this range, so copying from it later doesn't wait on the disk
+    fn page_in(&self, start: usize, end: usize) {
+        let range = &self.as_slice()[start..end];
+        for offset in (0..range.len()).step_by(PAGE_SIZE) {
+            unsafe {
+                ptr::read_volatile(&range[offset]);
+            }
+        }
+    }
+}
+
+// Read the next chunk in on the pool, resolving to where it ends
+fn page_in(file: &Arc<MappedFile>, start: usize) -> CpuFuture<usize, ()> {
+    let file = Arc::clone(file);
+    let end = cmp::min(start + MAPPED_CHUNK_SIZE, file.len());
+    blocking_io::run(move || {
+        file.page_in(start, end);
+        Ok(end)
+    })
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+/// Part of a mapped file, written to the connection straight from the mapping.
+pub struct MappedChunk {
+    file: Arc<MappedFile>,
+    pos: usize,
+    end: usize,
+}
+
+impl Buf for MappedChunk {
+    fn remaining(&self) -> usize {
+        self.end - self.pos
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.file.as_slice()[self.pos..self.end]
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        self.pos = cmp::min(self.pos + cnt, self.end);
+    }
+}
+
+pub enum BodyData {
+    Chunk(Chunk),
+    Mapped(MappedChunk),
+}
+
+impl Buf for BodyData {
+    fn remaining(&self) -> usize {
+        match *self {
+            BodyData::Chunk(ref e) => e.remaining(),
+            BodyData::Mapped(ref e) => e.remaining(),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match *self {
+            BodyData::Chunk(ref e) => e.bytes(),
+            BodyData::Mapped(ref e) => e.bytes(),
+        }
+    }
+
+    fn advance(&mut self, cnt: usize) {
+        match *self {
+            BodyData::Chunk(ref mut e) => e.advance(cnt),
+            BodyData::Mapped(ref mut e) => e.advance(cnt),
+        }
+    }
+}
+
+/// A file sent from its mapping. Hyper copies from it on the event loop, so each chunk is read
+/// in on the blocking pool before it's handed over, with the next one read in while it's sent.
+pub struct MappedBody {
+    file: Arc<MappedFile>,
+    sent: usize,
+    paging: Option<CpuFuture<usize, ()>>,
+}
+
+impl MappedBody {
+    pub fn new(file: MappedFile) -> MappedBody {
+        MappedBody {
+            file: Arc::new(file),
+            sent: 0,
+            paging: None,
+        }
+    }
+
+    fn poll_chunk(&mut self) -> Poll<Option<BodyData>, BodyError> {
+        if self.sent >= self.file.len() {
+            return Ok(Async::Ready(None));
+        }
+        let sent = self.sent;
+        if self.paging.is_none() {
+            self.paging = Some(page_in(&self.file, sent));
+        }
+        let end = match self.paging.as_mut().map(|e| e.poll()) {
+            Some(Ok(Async::Ready(end))) => end,
+            Some(Ok(Async::NotReady)) => return Ok(Async::NotReady),
+            // Touching the pages can't fail, the pool would have to be gone
+            _ => {
+                return Err(From::from(format!(
+                    "Failed to read in a mapped file from byte {} of {}",
+                    sent,
+                    self.file.len()
+                )))
+            }
+        };
+        self.sent = end;
+        self.paging = if end < self.file.len() {
+            Some(page_in(&self.file, end))
+        } else {
+            None
+        };
+        Ok(Async::Ready(Some(BodyData::Mapped(MappedChunk {
+            file: Arc::clone(&self.file),
+            pos: sent,
+            end: end,
+        }))))
+    }
+}
+
+/// What we actually send, either a regular hyper body or a file sent from its mapping.
+pub enum ResponseBody {
+    Streamed(Body),
+    Mapped(MappedBody),
+}
+
+impl Payload for ResponseBody {
+    type Data = BodyData;
+    type Error = BodyError;
+
+    fn poll_data(&mut self) -> Poll<Option<BodyData>, BodyError> {
+        match *self {
+            ResponseBody::Streamed(ref mut body) => {
+                let chunk = try_ready!(body.poll_data());
+                Ok(Async::Ready(chunk.map(BodyData::Chunk)))
+            }
+            ResponseBody::Mapped(ref mut body) => body.poll_chunk(),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match *self {
+            ResponseBody::Streamed(ref body) => body.is_end_stream(),
+            ResponseBody::Mapped(ref body) => body.sent >= body.file.len(),
+        }
+    }
+
+    fn content_length(&self) -> Option<u64> {
+        match *self {
+            ResponseBody::Streamed(ref body) => body.content_length(),
+            ResponseBody::Mapped(ref body) => Some((body.file.len() - body.sent) as u64),
+        }
+    }
+}
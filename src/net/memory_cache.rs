@@ -0,0 +1,126 @@
+use bytes::Bytes;
+use config::AppConfig;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+// (partition, file name)
+type Key = (String, String);
+
+struct Entries {
+    by_key: HashMap<Key, (Bytes, u64)>,
+    // Least recently used first
+    by_use: BTreeMap<u64, Key>,
+    size: u64,
+    next_use: u64,
+}
+
+/// Small cache entries kept in memory, in front of the disk cache, so we can answer the flood of
+/// action cache lookups at the start of a build without touching the disk.
+#[derive(Clone)]
+pub struct MemoryCache {
+    capacity: u64,
+    max_entry_size: u64,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl MemoryCache {
+    pub fn new(config: &AppConfig) -> MemoryCache {
+        MemoryCache {
+            capacity: config.memory_cache_size,
+            max_entry_size: config.memory_cache_max_entry_size,
+            entries: Arc::new(Mutex::new(Entries {
+                by_key: HashMap::new(),
+                by_use: BTreeMap::new(),
+                size: 0,
+                next_use: 0,
+            })),
+        }
+    }
+
+    // Whether an entry this large belongs in memory at all
+    pub fn fits(&self, len: u64) -> bool {
+        len <= self.max_entry_size && len <= self.capacity
+    }
+
+    pub fn get(&self, partition: &str, file_name: &str) -> Option<Bytes> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let key = (partition.to_string(), file_name.to_string());
+        let last_use = match entries.by_key.get(&key) {
+            Some(&(_, last_use)) => last_use,
+            None => return None,
+        };
+        let next_use = entries.next_use;
+        entries.next_use += 1;
+        entries.by_use.remove(&last_use);
+        entries.by_use.insert(next_use, key.clone());
+        let entry = entries.by_key.get_mut(&key).unwrap();
+        entry.1 = next_use;
+        Some(entry.0.clone())
+    }
+
+    pub fn insert(&self, partition: &str, file_name: &str, contents: Bytes) {
+        if !self.fits(contents.len() as u64) {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let key = (partition.to_string(), file_name.to_string());
+        remove_key(&mut entries, &key);
+
+        let next_use = entries.next_use;
+        entries.next_use += 1;
+        entries.size += contents.len() as u64;
+        entries.by_use.insert(next_use, key.clone());
+        entries.by_key.insert(key, (contents, next_use));
+
+        while entries.size > self.capacity {
+            let oldest = match entries.by_use.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            remove_key(&mut entries, &oldest);
+        }
+    }
+
+    // Drop entries the disk cache no longer has, or has replaced, so we never serve them
+    pub fn remove_where<F>(&self, matches: F)
+    where
+        F: Fn(&str, &str) -> bool,
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let removed: Vec<Key> = entries
+            .by_key
+            .keys()
+            .filter(|&&(ref partition, ref file_name)| matches(partition, file_name))
+            .cloned()
+            .collect();
+        for key in removed {
+            remove_key(&mut entries, &key);
+        }
+    }
+
+    pub fn remove(&self, partition: &str, file_name: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = (partition.to_string(), file_name.to_string());
+        remove_key(&mut self.entries.lock().unwrap(), &key);
+    }
+
+    /// Bytes held and allowed.
+    pub fn usage(&self) -> (u64, u64) {
+        (self.entries.lock().unwrap().size, self.capacity)
+    }
+}
+
+fn remove_key(entries: &mut Entries, key: &Key) {
+    if let Some((contents, last_use)) = entries.by_key.remove(key) {
+        entries.by_use.remove(&last_use);
+        entries.size -= contents.len() as u64;
+    }
+}
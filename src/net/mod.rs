@@ -1,17 +1,37 @@
+pub mod ac_freshness;
+pub mod access_log;
+mod admin;
 pub mod background_uploader;
+pub(super) mod blocking_io;
 pub(super) mod buffered_send_stream;
+mod cache_journal;
 pub(super) mod client;
+mod denylist;
 mod client_proxy_server;
+pub mod digest;
+pub mod disk_health;
+mod disk_cache;
 pub(super) mod downloader;
-pub(super) mod process_action_cache;
+mod eviction;
+pub mod instance;
+pub mod mapped_file;
+mod memory_cache;
+mod peer_discovery;
+pub mod pins;
+pub mod process_action_cache;
 mod proxy;
 mod proxy_request;
 mod remote_cache_server;
+pub mod repo_entries;
+mod scrubber;
 pub mod server_error;
 mod server_io;
 mod server_start;
+mod shutdown;
 mod state;
+mod tee_stream;
 pub(super) mod terminator;
+mod upstream_misses;
 
 pub use self::client_proxy_server::start_server as start_client_proxy_server;
 pub use self::downloader::Downloader;
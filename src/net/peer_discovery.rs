@@ -0,0 +1,269 @@
+use config::AppConfig;
+use hyper::Uri as HyperUri;
+use net2::UdpBuilder;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+use net2::unix::UnixUdpBuilderExt;
+
+const MDNS_PORT: u16 = 5353;
+const SERVICE_NAME: &str = "_bazel-cache._tcp.local";
+const DNS_TYPE_TXT: u16 = 16;
+const DNS_CLASS_IN: u16 = 1;
+
+// How often we announce ourselves, peers we haven't heard from in 3 intervals are dropped.
+const ANNOUNCE_INTERVAL_SECS: u64 = 30;
+
+// Announcements aren't authenticated, this bounds how many peers anyone on the LAN can add
+const MAX_DISCOVERED_PEERS: usize = 32;
+
+fn mdns_addr() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 251)
+}
+
+/// The set of LAN peers we may fetch CAS blobs from, either configured up front
+/// or learnt about from mDNS announcements.
+#[derive(Debug, Clone)]
+pub struct Peers {
+    configured: Vec<HyperUri>,
+    discovered: Arc<Mutex<HashMap<String, Instant>>>,
+    advertise_uri: Option<String>,
+}
+
+impl Peers {
+    pub fn new(config: &AppConfig) -> Peers {
+        Peers {
+            configured: config.peers.clone(),
+            discovered: Arc::new(Mutex::new(HashMap::new())),
+            advertise_uri: config.advertise_uri.as_ref().map(|e| format!("{}", e)),
+        }
+    }
+
+    // Configured peers come first, so they're the ones asked when there are many
+    pub fn current(&self) -> Vec<HyperUri> {
+        let mut peers = self.configured.clone();
+        let mut discovered = self.discovered.lock().unwrap();
+        drop_expired(&mut discovered);
+        for uri in discovered.keys() {
+            if self.advertise_uri.as_ref() == Some(uri) {
+                continue;
+            }
+            match uri.parse() {
+                Ok(u) => {
+                    if !peers.contains(&u) {
+                        peers.push(u)
+                    }
+                }
+                Err(e) => warn!("Ignoring unparsable peer uri {:?}: {:?}", uri, e),
+            }
+        }
+        peers
+    }
+
+    fn saw_peer(&self, uri: String) {
+        if let Err(e) = uri.parse::<HyperUri>() {
+            debug!("Ignoring unparsable peer uri {:?}: {:?}", uri, e);
+            return;
+        }
+        let mut discovered = self.discovered.lock().unwrap();
+        drop_expired(&mut discovered);
+        if !discovered.contains_key(&uri) {
+            if discovered.len() >= MAX_DISCOVERED_PEERS {
+                debug!(
+                    "Ignoring LAN peer {}, already have {} peers",
+                    uri,
+                    discovered.len()
+                );
+                return;
+            }
+            info!("Discovered LAN peer {}", uri);
+        }
+        discovered.insert(uri, Instant::now());
+    }
+}
+
+fn drop_expired(discovered: &mut HashMap<String, Instant>) {
+    let expiry = Duration::from_secs(ANNOUNCE_INTERVAL_SECS * 3);
+    discovered.retain(|_, last_seen| last_seen.elapsed() <= expiry);
+}
+
+fn encode_name(name: &str, out: &mut Vec<u8>) {
+    for label in name.split('.').filter(|e| !e.is_empty()) {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn push_u16(v: u16, out: &mut Vec<u8>) {
+    out.push((v >> 8) as u8);
+    out.push(v as u8);
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    let hi = *packet.get(pos)? as u16;
+    let lo = *packet.get(pos + 1)? as u16;
+    Some((hi << 8) | lo)
+}
+
+fn build_query() -> Vec<u8> {
+    let mut packet = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    encode_name(SERVICE_NAME, &mut packet);
+    push_u16(DNS_TYPE_TXT, &mut packet);
+    push_u16(DNS_CLASS_IN, &mut packet);
+    packet
+}
+
+fn build_announcement(uri: &str) -> Vec<u8> {
+    // Authoritative response with a single answer
+    let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+    encode_name(SERVICE_NAME, &mut packet);
+    push_u16(DNS_TYPE_TXT, &mut packet);
+    push_u16(DNS_CLASS_IN, &mut packet);
+    packet.extend_from_slice(&[0, 0, 0, (ANNOUNCE_INTERVAL_SECS * 4) as u8]);
+
+    let txt = format!("url={}", uri);
+    push_u16((txt.len() + 1) as u16, &mut packet);
+    packet.push(txt.len() as u8);
+    packet.extend_from_slice(txt.as_bytes());
+    packet
+}
+
+// Read a possibly compressed DNS name, returning it and the position after it.
+fn read_name(packet: &[u8], start: usize) -> Option<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = start;
+    let mut end = None;
+    let mut jumps = 0;
+    loop {
+        let len = *packet.get(pos)? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xC0 == 0xC0 {
+            let pointer = ((len & 0x3F) << 8) | *packet.get(pos + 1)? as usize;
+            if end.is_none() {
+                end = Some(pos + 2);
+            }
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+            pos = pointer;
+            continue;
+        }
+        let label = packet.get(pos + 1..pos + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len;
+    }
+    Some((labels.join("."), end.unwrap_or(pos)))
+}
+
+// Returns whether the packet asked about our service, and any peer uris it announced.
+fn parse_packet(packet: &[u8]) -> Option<(bool, Vec<String>)> {
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize
+        + read_u16(packet, 8)? as usize
+        + read_u16(packet, 10)? as usize;
+
+    let mut pos = 12;
+    let mut queried = false;
+    for _ in 0..questions {
+        let (name, next) = read_name(packet, pos)?;
+        queried = queried || name.eq_ignore_ascii_case(SERVICE_NAME);
+        pos = next + 4;
+    }
+
+    let mut uris = Vec::new();
+    for _ in 0..records {
+        let (name, next) = read_name(packet, pos)?;
+        let record_type = read_u16(packet, next)?;
+        let data_len = read_u16(packet, next + 8)? as usize;
+        let data = packet.get(next + 10..next + 10 + data_len)?;
+        if record_type == DNS_TYPE_TXT && name.eq_ignore_ascii_case(SERVICE_NAME) {
+            let mut txt_pos = 0;
+            while txt_pos < data.len() {
+                let len = data[txt_pos] as usize;
+                let entry = data.get(txt_pos + 1..txt_pos + 1 + len)?;
+                let entry = String::from_utf8_lossy(entry);
+                if entry.starts_with("url=") {
+                    uris.push(entry["url=".len()..].to_string());
+                }
+                txt_pos += 1 + len;
+            }
+        }
+        pos = next + 10 + data_len;
+    }
+    Some((queried, uris))
+}
+
+fn bind_mdns_socket() -> io::Result<UdpSocket> {
+    let builder = UdpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    // Share the port with any system mDNS responder
+    #[cfg(unix)]
+    builder.reuse_port(true)?;
+    let socket = builder.bind(("0.0.0.0", MDNS_PORT))?;
+    socket.join_multicast_v4(&mdns_addr(), &Ipv4Addr::new(0, 0, 0, 0))?;
+    socket.set_multicast_loop_v4(true)?;
+    Ok(socket)
+}
+
+fn announce(socket: &UdpSocket, uri: &Option<String>) {
+    match uri {
+        Some(u) => match socket.send_to(&build_announcement(u), (mdns_addr(), MDNS_PORT)) {
+            Ok(_) => debug!("Announced ourselves as LAN peer {}", u),
+            Err(e) => warn!("Failed to send mDNS announcement: {:?}", e),
+        },
+        None => (),
+    }
+}
+
+/// Listen for (and if configured, send) mDNS announcements of peer cache proxies.
+pub fn start_peer_discovery(peers: &Peers) -> io::Result<()> {
+    let socket = bind_mdns_socket()?;
+    let announce_socket = socket.try_clone()?;
+    let listen_peers = peers.clone();
+    let advertise_uri = peers.advertise_uri.clone();
+
+    // Ask anyone already running to tell us about themselves
+    socket.send_to(&build_query(), (mdns_addr(), MDNS_PORT))?;
+
+    thread::spawn(move || {
+        let mut buf = vec![0; 9000];
+        loop {
+            let size = match socket.recv_from(&mut buf) {
+                Ok((size, _)) => size,
+                Err(e) => {
+                    warn!("Error receiving mDNS packet: {:?}", e);
+                    continue;
+                }
+            };
+            match parse_packet(&buf[0..size]) {
+                Some((queried, uris)) => {
+                    for uri in uris {
+                        listen_peers.saw_peer(uri);
+                    }
+                    if queried {
+                        announce(&socket, &listen_peers.advertise_uri);
+                    }
+                }
+                None => debug!("Ignoring malformed mDNS packet"),
+            }
+        }
+    });
+
+    if advertise_uri.is_some() {
+        thread::spawn(move || loop {
+            announce(&announce_socket, &advertise_uri);
+            thread::sleep(Duration::from_secs(ANNOUNCE_INTERVAL_SECS));
+        });
+    }
+    Ok(())
+}
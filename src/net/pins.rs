@@ -0,0 +1,176 @@
+use config::AppConfig;
+use net::process_action_cache::{read_action_result, referenced_cas_digests};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Named sets of digests whose entries are never evicted, e.g. toolchain outputs or a "golden"
+/// list of action cache entries. Pinning an action cache digest also pins the CAS blobs its
+/// result references.
+///
+/// Sets come from `--pin-file`, with one `<set> <digest>` pair per line, and from the admin API,
+/// which keeps each of its sets as a file of digests under `<cache folder>/pins`.
+#[derive(Debug, Clone)]
+pub struct PinSets {
+    folder: PathBuf,
+    // Set name -> (digests, whether it came from the pin file)
+    sets: BTreeMap<String, (Vec<String>, bool)>,
+}
+
+// One digest per line, `#` starts a comment
+fn parse_digests(contents: &str) -> Vec<String> {
+    let mut digests: Vec<String> = contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect();
+    digests.sort();
+    digests.dedup();
+    digests
+}
+
+pub fn valid_set_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !name.starts_with('.')
+}
+
+impl PinSets {
+    pub fn load(config: &AppConfig) -> Result<PinSets, String> {
+        let mut pins = PinSets {
+            folder: PathBuf::from(&config.cache_folder).join("pins"),
+            sets: BTreeMap::new(),
+        };
+
+        if let Some(ref path) = config.pin_file {
+            let contents = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to open {}: {}", path, e))?;
+            for line in contents.lines() {
+                let line = line.split('#').next().unwrap_or("").trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                if parts.len() != 2 || !valid_set_name(parts[0]) {
+                    return Err(format!("Invalid pin line: {:?}", line));
+                }
+                let set = pins
+                    .sets
+                    .entry(parts[0].to_string())
+                    .or_insert((Vec::new(), true));
+                set.0.push(parts[1].to_lowercase());
+            }
+        }
+
+        if let Ok(paths) = fs::read_dir(&pins.folder) {
+            for path in paths.filter_map(|e| e.ok()) {
+                let name = path.file_name().to_string_lossy().to_string();
+                if !valid_set_name(&name) {
+                    continue;
+                }
+                if pins.sets.contains_key(&name) {
+                    warn!("Pin set {} is in the pin file, ignoring {:?}", name, path.path());
+                    continue;
+                }
+                let contents = fs::read_to_string(path.path()).map_err(|e| e.to_string())?;
+                pins.sets.insert(name, (parse_digests(&contents), false));
+            }
+        }
+        Ok(pins)
+    }
+
+    /// Every set, with its digests and whether it came from the pin file.
+    pub fn sets(&self) -> Vec<(String, Vec<String>, bool)> {
+        self.sets
+            .iter()
+            .map(|(name, &(ref digests, from_file))| (name.clone(), digests.clone(), from_file))
+            .collect()
+    }
+
+    pub fn digests(&self) -> HashSet<String> {
+        self.sets
+            .values()
+            .flat_map(|&(ref digests, _)| digests.iter().cloned())
+            .collect()
+    }
+
+    // Every digest pinned were the named set replaced with these contents
+    pub fn digests_with(&self, name: &str, contents: &str) -> HashSet<String> {
+        let mut digests: HashSet<String> = self
+            .sets
+            .iter()
+            .filter(|&(set, _)| set != name)
+            .flat_map(|(_, &(ref digests, _))| digests.iter().cloned())
+            .collect();
+        digests.extend(parse_digests(contents));
+        digests
+    }
+
+    // Create or replace a set, sets from the pin file can only be changed there
+    pub fn put(&mut self, name: &str, contents: &str) -> Result<usize, String> {
+        if !valid_set_name(name) {
+            return Err(format!("Invalid pin set name {:?}", name));
+        }
+        if self.sets.get(name).map(|e| e.1).unwrap_or(false) {
+            return Err(format!("Pin set {} is declared in the pin file", name));
+        }
+        let digests = parse_digests(contents);
+        fs::create_dir_all(&self.folder).map_err(|e| e.to_string())?;
+        // Written aside and renamed, a crash mustn't leave half a set behind
+        let path = self.folder.join(name);
+        let tmp_path = self.folder.join(format!(".{}.tmp", name));
+        {
+            let mut file = fs::File::create(&tmp_path).map_err(|e| e.to_string())?;
+            file.write_all(format!("{}\n", digests.join("\n")).as_bytes())
+                .and_then(|_| file.sync_all())
+                .map_err(|e| e.to_string())?;
+        }
+        fs::rename(&tmp_path, &path)
+            .and_then(|_| fs::File::open(&self.folder))
+            .and_then(|e| e.sync_all())
+            .map_err(|e| e.to_string())?;
+        let len = digests.len();
+        self.sets.insert(name.to_string(), (digests, false));
+        Ok(len)
+    }
+
+    // Returns whether there was such a set
+    pub fn remove(&mut self, name: &str) -> Result<bool, String> {
+        match self.sets.get(name).map(|e| e.1) {
+            None => return Ok(false),
+            Some(true) => return Err(format!("Pin set {} is declared in the pin file", name)),
+            Some(false) => (),
+        }
+        fs::remove_file(self.folder.join(name)).map_err(|e| e.to_string())?;
+        self.sets.remove(name);
+        Ok(true)
+    }
+}
+
+/// Keys of the CAS blobs the action result at `path` references, none if it can't be read.
+pub fn referenced_keys(path: &Path) -> Vec<String> {
+    match read_action_result(path) {
+        Ok(action_result) => referenced_cas_digests(&action_result)
+            .into_iter()
+            .map(|e| format!("cas__{}", e))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// The cache entries pinned by these digests, as file names.
+pub fn pinned_keys(config: &AppConfig, digests: &HashSet<String>) -> HashSet<String> {
+    let mut keys = HashSet::new();
+    for digest in digests {
+        let ac_file_name = format!("ac__{}", digest);
+        if let Some(path) = config.find_cached(&ac_file_name) {
+            keys.extend(referenced_keys(&path));
+        }
+        keys.insert(ac_file_name);
+        keys.insert(format!("cas__{}", digest));
+    }
+    keys
+}
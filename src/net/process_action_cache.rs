@@ -3,78 +3,187 @@ use protobuf::{CodedInputStream, Message}; //, ProtobufResult, RepeatedField};
 use std::io::BufReader;
 // use std::io::{self, stdin, BufRead, BufReader};
 use action_result::ActionResult;
+use net::background_uploader::upload_queued;
+use net::repo_entries;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::path::Path;
+use std::io::{BufRead, Read, Write};
+use std::path::{Path, PathBuf};
 use std::thread;
+use std::time::Duration;
 
-//from https://doc.rust-lang.org/stable/rust-by-example/std_misc/fs.html
-fn touch(path: &Path) -> io::Result<()> {
-    match OpenOptions::new().create(true).write(true).open(path) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
+// How many action cache entries we remember referencing a CAS blob
+const MAX_GATE_REFERENCES: usize = 16;
+
+// How often we drop gates and references left behind by evicted action cache entries
+const GATE_TRIM_INTERVAL_SECS: u64 = 3600;
+
+pub fn gate_path(config: &AppConfig, cas_digest: &str) -> PathBuf {
+    Path::new(&config.cache_folder).join(format!("enable_cas__{}", cas_digest))
+}
+
+// The action cache digests that referenced a CAS blob, as recorded in its gate file
+pub fn gate_references(config: &AppConfig, cas_digest: &str) -> Vec<String> {
+    match File::open(gate_path(config, cas_digest)) {
+        Ok(file) => BufReader::new(file)
+            .lines()
+            .filter_map(|e| e.ok())
+            .filter(|e| !e.is_empty())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// Allow fetching a CAS blob, recording which action cache entry referenced it. Only the latest
+// few references are kept, a popular blob would otherwise have a gate file that grows forever.
+fn enable_cas(config: &AppConfig, cas_digest: &str, ac_digest: &str) -> io::Result<()> {
+    let mut references = gate_references(config, cas_digest);
+    if references.iter().any(|e| e == ac_digest) {
+        return Ok(());
+    }
+    if references.len() < MAX_GATE_REFERENCES {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(gate_path(config, cas_digest))?;
+        return writeln!(file, "{}", ac_digest);
     }
+    // The earliest are the likeliest to have been evicted already
+    references.remove(0);
+    references.push(ac_digest.to_string());
+    write_gate(config, cas_digest, &references)
+}
+
+// Losing this part way through leaves the blob enabled, just with fewer references recorded
+fn write_gate(config: &AppConfig, cas_digest: &str, references: &[String]) -> io::Result<()> {
+    let mut contents = String::new();
+    for reference in references {
+        contents.push_str(reference);
+        contents.push('\n');
+    }
+    File::create(gate_path(config, cas_digest))?.write_all(contents.as_bytes())
+}
+
+// The references in a gate file to action cache entries we still have cached
+pub fn live_references(config: &AppConfig, cas_digest: &str) -> Vec<String> {
+    gate_references(config, cas_digest)
+        .into_iter()
+        .filter(|ac_digest| config.find_cached(&format!("ac__{}", ac_digest)).is_some())
+        .collect()
+}
+
+// Drop references to evicted action cache entries, and the gates of blobs nothing cached
+// refers to anymore. They're recreated for any entry fetched again.
+pub fn trim_gates(config: &AppConfig) {
+    for cas_digest in gated_digests(config) {
+        // The uploader checks the gate once it gets to the upload, leave it until then
+        if upload_queued(&format!("cas__{}", cas_digest)) {
+            continue;
+        }
+        let references = gate_references(config, &cas_digest);
+        let live = live_references(config, &cas_digest);
+        let trimmed = if live.is_empty() {
+            fs::remove_file(gate_path(config, &cas_digest))
+        } else if live.len() < references.len() {
+            write_gate(config, &cas_digest, &live)
+        } else {
+            Ok(())
+        };
+        trimmed.unwrap_or_else(|e| warn!("Failed to trim gate for {}: {}", cas_digest, e));
+    }
+}
+
+// The CAS digests with a gate file, whether or not the blob itself is cached
+pub fn gated_digests(config: &AppConfig) -> Vec<String> {
+    match fs::read_dir(&config.cache_folder) {
+        Ok(paths) => paths
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|e| e.starts_with("enable_cas__"))
+            .map(|e| e.trim_left_matches("enable_cas__").to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn read_action_result(path: &Path) -> Result<ActionResult, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    parse_action_result(file)
+}
+
+pub fn parse_action_result<R: Read>(reader: R) -> Result<ActionResult, String> {
+    let mut s = ActionResult::new();
+
+    let mut br = BufReader::new(reader);
+    let mut cis = CodedInputStream::from_buffered_reader(&mut br);
+    s.merge_from(&mut cis).map_err(|e| e.to_string())?;
+    Ok(s)
+}
+
+// Every CAS blob an action result points at
+pub fn referenced_cas_digests(action_result: &ActionResult) -> Vec<String> {
+    action_result
+        .output_files
+        .iter()
+        .filter_map(|f| f.digest.as_ref())
+        .chain(action_result.stdout_digest.as_ref())
+        .chain(action_result.stderr_digest.as_ref())
+        .map(|h| h.hash.clone())
+        .collect()
 }
 
 pub fn process_existing_action_caches(config: AppConfig) {
     thread::spawn(move || {
-        let paths = fs::read_dir(&config.cache_folder).unwrap();
-
-        for p in paths {
-            for pe in p.iter() {
-                process_action_cache_response(&config, &pe.file_name().into_string().unwrap())
-                    .unwrap_or(());
-            }
+        process_action_caches(&config);
+        loop {
+            trim_gates(&config);
+            repo_entries::trim(&config);
+            thread::sleep(Duration::from_secs(GATE_TRIM_INTERVAL_SECS));
         }
     });
 }
+
+// Walk every cached action cache entry, opening up the CAS blobs it references.
+pub fn process_action_caches(config: &AppConfig) {
+    for (_, path) in config.cached_entries() {
+        process_action_cache_file(config, &path).unwrap_or(());
+    }
+}
+
 pub fn process_action_cache_response(
     config: &AppConfig,
+    repo: &String,
     downloaded_file: &String,
 ) -> Result<(), String> {
-    if downloaded_file.starts_with("ac__") {
-        let data_source_path = Path::new(&config.cache_folder).join(downloaded_file);
+    process_action_cache_file(config, &config.cache_path(repo, downloaded_file))
+}
+
+fn process_action_cache_file(config: &AppConfig, data_source_path: &Path) -> Result<(), String> {
+    let is_action_cache = data_source_path
+        .file_name()
+        .map(|e| e.to_string_lossy().starts_with("ac__"))
+        .unwrap_or(false);
+    if is_action_cache {
         debug!(
             "Processing for action cache entries: {:?}",
             data_source_path
         );
 
-        let mut s = ActionResult::new();
-
-        let file = File::open(data_source_path).map_err(|e| e.to_string())?;
-        let mut br = BufReader::new(file);
-        let mut cis = CodedInputStream::from_buffered_reader(&mut br);
-        s.merge_from(&mut cis).map_err(|e| e.to_string())?;
-
-        for f in s.output_files.iter() {
-            match f.digest.as_ref() {
-                Some(h) => {
-                    let file_name = format!("enable_cas__{}", h.hash);
-                    touch(&Path::new(&config.cache_folder).join(&file_name)).map_err(|e| {
-                        warn!("{:?}", e);
-                        e.to_string()
-                    })?;
-                }
-                None => (),
-            }
-        }
+        let ac_digest = data_source_path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .trim_left_matches("ac__")
+            .to_string();
 
-        match s.stdout_digest.as_ref() {
-            Some(h) => {
-                let file_name = format!("enable_cas__{}", h.hash);
-                touch(&Path::new(&config.cache_folder).join(&file_name))
-                    .map_err(|e| e.to_string())?;
-            }
-            None => (),
-        }
-        match s.stderr_digest.as_ref() {
-            Some(h) => {
-                let file_name = format!("enable_cas__{}", h.hash);
-                touch(&Path::new(&config.cache_folder).join(&file_name))
-                    .map_err(|e| e.to_string())?;
-            }
-            None => (),
+        let s = read_action_result(data_source_path)?;
+
+        for cas_digest in referenced_cas_digests(&s) {
+            enable_cas(config, &cas_digest, &ac_digest).map_err(|e| {
+                warn!("{:?}", e);
+                e.to_string()
+            })?;
         }
     }
 
@@ -1,6 +1,7 @@
 use hyper::Uri as HyperUri;
 use net::server_error::ServerError;
 
+#[derive(Debug, Clone)]
 pub struct ProxyRequest {
     pub repo: String,
     pub tpe: String, // ac or cas
@@ -11,6 +12,21 @@ impl ProxyRequest {
         format!("{}__{}", self.tpe, self.digest).to_string()
     }
 
+    // Back from a cache entry's file name, the repo it's for isn't part of it
+    pub fn from_file_name(repo: &str, file_name: &str) -> Option<ProxyRequest> {
+        let mut parts = file_name.splitn(2, "__");
+        match (parts.next(), parts.next()) {
+            (Some(tpe), Some(digest)) if (tpe == "ac" || tpe == "cas") && !digest.is_empty() => {
+                Some(ProxyRequest {
+                    repo: repo.to_string(),
+                    tpe: tpe.to_string(),
+                    digest: digest.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+
     pub fn build_query_uri(self: &Self, upstream_uri: &HyperUri) -> Result<HyperUri, ServerError> {
         let upstream_str = format!("{}", upstream_uri)
             .trim_right_matches('/')
@@ -22,6 +38,14 @@ impl ProxyRequest {
             .map_err(From::from)
     }
 
+    // Uri for fetching this blob from a LAN peer's local cache
+    pub fn build_peer_uri(self: &Self, peer_uri: &HyperUri) -> Result<HyperUri, ServerError> {
+        let peer_str = format!("{}", peer_uri).trim_right_matches('/').to_string();
+        format!("{}/peer/{}/{}", peer_str, self.tpe, self.digest)
+            .parse()
+            .map_err(From::from)
+    }
+
     pub fn new(uri: &HyperUri) -> ProxyRequest {
         let path: &str = uri.path().trim_matches('/');
         let mut elements: Vec<&str> = path.split('/').collect();
@@ -1,17 +1,28 @@
 use config::S3Config;
 use futures::Stream;
+use net::access_log::{log_response, AccessLog, Outcome, RequestLog};
+use net::admin::admin_request;
+use net::blocking_io;
+use net::denylist::{start_denylist_refresh, Denylist};
+use net::disk_health;
 use net::proxy_request::ProxyRequest;
+use net::scrubber::start_scrubber;
 use net::server_error::ServerError;
+use net::server_io::bearer_credential;
+use net::server_io::denylist_request;
 use net::server_io::empty_with_status_code;
 use net::server_io::empty_with_status_code_fut;
-use net::server_io::send_file;
+use net::server_io::health_request;
+use net::server_io::{into_response_body, open_cached, send_open_file};
 use rusoto_s3::GetObjectRequest;
 use std::io::Read;
-use std::io::Write;
 
+use net::server_start::activated_listener;
+use net::server_start::start_activated_server_impl;
 use net::server_start::start_http_server_impl;
 use net::server_start::start_unix_server_impl;
-use net::state::State;
+use net::shutdown::{graceful_shutdown, shutdown_signal};
+use net::state::{InFlight, State};
 use rusoto_s3::PutObjectRequest;
 use std::fs::File;
 use std::time::Duration;
@@ -30,6 +41,7 @@ use hyper::client::connect::Connect;
 use hyper::service::NewService;
 use hyper::Server;
 use hyper::Uri as HyperUri;
+use http::header::{self, HeaderValue};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use net::background_uploader::RequestUpload;
 use net::downloader::Downloader;
@@ -63,7 +75,10 @@ fn get_object_from_s3_with_file_name(
     client: &S3Client,
     bucket: &str,
     prefix: &str,
-    local_filename: &Path,
+    downloader: &Downloader,
+    repo: String,
+    file_name: String,
+    request_log: &RequestLog,
 ) -> Box<Future<Item = Option<u64>, Error = String> + Send + 'static> {
     info!("Issuing request to s3://{}/{}", bucket, prefix);
     let get_req = GetObjectRequest {
@@ -76,51 +91,108 @@ fn get_object_from_s3_with_file_name(
         Err(GetObjectError::NoSuchKey(_)) => Box::new(futures::future::ok(None)),
         Err(o) => {
             warn!("Unknown other error {:?}", o);
+            request_log.set_outcome(Outcome::Error);
             Box::new(futures::future::ok(None))
         }
 
         Ok(result) => {
-            let mut f = File::create(local_filename).unwrap();
             let total_size: Option<u64> = result.content_length.map(|e| e as u64);
 
-            println!("get object result: {:#?}", result);
+            debug!("get object result: {:?}", result);
+            request_log.set_outcome(Outcome::UpstreamHit);
 
+            // Only committed to the cache once it's all here, a failed download leaves nothing behind
             let stream = result.body.unwrap();
+            let tmp_path = downloader.temp_path();
+            let downloader = downloader.clone();
             Box::new(
-                stream
-                    .for_each(move |e| f.write_all(&e).map(|_| ()).map_err(From::from))
-                    .map(move |_| total_size)
-                    .map_err(|e| e.to_string()),
+                blocking_io::write_stream(tmp_path.clone(), stream).and_then(move |written| {
+                    blocking_io::run(move || {
+                        if total_size.map(|e| e != written.len).unwrap_or(false) {
+                            fs::remove_file(&tmp_path).unwrap_or(());
+                            return Err(format!(
+                                "Download ended after {} of {:?} bytes",
+                                written.len, total_size
+                            ));
+                        }
+                        downloader.insert_file(&repo, &file_name, &tmp_path, written.sha256)?;
+                        Ok(Some(written.len))
+                    })
+                }),
             )
         }
     }
 }
 
+// Stream an object from S3 without caching it, for when the cache disk is failing
+fn pass_through_s3(
+    client: &S3Client,
+    bucket: &str,
+    prefix: &str,
+    request_log: &RequestLog,
+) -> ResponseFuture {
+    info!("Passing through s3://{}/{}", bucket, prefix);
+    let get_req = GetObjectRequest {
+        bucket: bucket.to_owned(),
+        key: prefix.to_owned(),
+        ..Default::default()
+    };
+
+    match client.get_object(&get_req).sync() {
+        Err(GetObjectError::NoSuchKey(_)) => {
+            request_log.set_outcome(Outcome::Miss);
+            Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND))
+        }
+        Err(o) => {
+            warn!("Unknown other error {:?}", o);
+            request_log.set_outcome(Outcome::Error);
+            Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND))
+        }
+        Ok(result) => {
+            request_log.set_outcome(Outcome::PassedThrough);
+            let mut res = Response::new(Body::wrap_stream(result.body.unwrap()));
+            if let Some(len) = result.content_length {
+                res.headers_mut()
+                    .insert(header::CONTENT_LENGTH, HeaderValue::from(len as u64));
+            }
+            Box::new(futures::future::ok(res))
+        }
+    }
+}
+
+fn put_object(
+    client: &S3Client,
+    bucket: &str,
+    dest_filename: &str,
+    contents: Vec<u8>,
+) -> Result<(), String> {
+    let req = PutObjectRequest {
+        bucket: bucket.to_owned(),
+        key: dest_filename.to_owned(),
+        body: Some(contents.into()),
+        ..Default::default()
+    };
+    client
+        .put_object(&req)
+        .sync()
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
 fn put_object_with_file_name(
     client: &S3Client,
     bucket: &str,
     dest_filename: &str,
     local_filename: &Path,
 ) -> Result<(), String> {
-    let mut f = File::open(local_filename).unwrap();
+    // Possibly evicted already, by other uploads
+    let mut f = File::open(local_filename)
+        .map_err(|e| format!("Error opening {:?} to send to S3: {}", local_filename, e))?;
     let mut contents: Vec<u8> = Vec::new();
     match f.read_to_end(&mut contents) {
-        Err(why) => return Err(format!("Error opening file to send to S3: {}", why)),
-        Ok(_) => {
-            let req = PutObjectRequest {
-                bucket: bucket.to_owned(),
-                key: dest_filename.to_owned(),
-                body: Some(contents.into()),
-                ..Default::default()
-            };
-            client
-                .put_object(&req)
-                .sync()
-                .map(|_| ())
-                .map_err(|e| e.to_string())?
-        }
+        Err(why) => Err(format!("Error opening file to send to S3: {}", why)),
+        Ok(_) => put_object(client, bucket, dest_filename, contents),
     }
-    Ok(())
 }
 
 fn to_upstream_path(proxy_request: &ProxyRequest, s3_config: &S3Config) -> String {
@@ -133,20 +205,39 @@ fn get_request(
     instant: Instant,
     req: Request<Body>,
     s3_client: Arc<S3Client>,
+    downloader: &Downloader,
     config: &AppConfig,
     s3_config: &S3Config,
+    denylist: &Denylist,
+    request_log: RequestLog,
 ) -> ResponseFuture {
     info!("Start Get request to {:?}", req.uri());
     let proxy_request = ProxyRequest::new(req.uri());
     let file_name = proxy_request.file_name();
+    request_log.set_request(&proxy_request);
+
+    if denylist.contains(&proxy_request.digest) {
+        warn!("Refusing to serve denylisted {:?}", file_name);
+        request_log.set_outcome(Outcome::Denied);
+        return Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND));
+    }
 
-    let data_source_path = Path::new(&config.cache_folder).join(&file_name);
+    let data_source_path = config.cache_path(&proxy_request.repo, &file_name);
     let path = req.uri().path().to_string().clone();
+    let partition = config.partition_for(&proxy_request.repo);
+    let on_disk = current_file_size(data_source_path.to_str().unwrap()).is_some();
+    downloader.record_lookup(&partition, &file_name, on_disk);
 
     let s3_cfg2 = s3_config.clone();
+    let inner_downloader = downloader.clone();
+    let repo = proxy_request.repo.clone();
 
     let upstream_path = to_upstream_path(&proxy_request, s3_config);
 
+    if !on_disk && !disk_health::caching() {
+        return pass_through_s3(&s3_client, &s3_config.bucket, &upstream_path, &request_log);
+    }
+
     let inner_s3_client = Arc::clone(&s3_client);
     Box::new(
         futures::done(Ok(upstream_path)).and_then(move |prefix_uri| {
@@ -158,31 +249,52 @@ fn get_request(
                         &inner_s3_client,
                         &s3_cfg2.bucket,
                         &prefix_uri,
-                        &data_source_path,
+                        &inner_downloader,
+                        repo,
+                        file_name,
+                        &request_log,
                     ).map_err(From::from),
                 ),
-                Some(len) => Box::new(futures::future::ok(Some(len))),
+                Some(len) => {
+                    request_log.set_outcome(Outcome::LocalHit);
+                    Box::new(futures::future::ok(Some(len)))
+                }
             };
 
             let req_uri_string = prefix_uri.clone();
+            let error_log = request_log.clone();
 
             let downloaded_fut = downloaded_file_future
                 .and_then(move |file_path| {
                     // info!("Get request issued to : {} --> {:?}", req.uri(), file_path);
                     match file_path {
-                        Some(file_len) => {
-                            if instant.elapsed().as_secs() >= 2 {
+                        Some(file_len) => match open_cached(&data_source_path) {
+                            Ok(Some(file)) => {
+                                if instant.elapsed().as_secs() >= 2 {
+                                    info!(
+                                        "[{:?}] took: {} seconds at {} MB/sec",
+                                        path,
+                                        duration_to_float_seconds(instant.elapsed()),
+                                        (file_len as f64 / 1_000_000 as f64)
+                                            / duration_to_float_seconds(instant.elapsed())
+                                    );
+                                }
+                                send_open_file(data_source_path.to_str().unwrap().to_string(), file)
+                            }
+                            Ok(None) => {
                                 info!(
-                                    "[{:?}] took: {} seconds at {} MB/sec",
-                                    path,
-                                    duration_to_float_seconds(instant.elapsed()),
-                                    (file_len as f64 / 1_000_000 as f64)
-                                        / duration_to_float_seconds(instant.elapsed())
+                                    "{:?} was evicted before we could send it, returning 404",
+                                    data_source_path
                                 );
+                                request_log.set_outcome(Outcome::Miss);
+                                Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND))
                             }
-                            send_file(data_source_path.to_str().unwrap().to_string())
-                        }
+                            Err(e) => Box::new(futures::future::err(From::from(e))),
+                        },
                         None => {
+                            if request_log.outcome().is_none() {
+                                request_log.set_outcome(Outcome::Miss);
+                            }
                             let mut res = Response::new(Body::empty());
                             *res.status_mut() = StatusCode::NOT_FOUND;
                             Box::new(futures::future::ok(res))
@@ -195,6 +307,7 @@ fn get_request(
                         o,
                         instant.elapsed().as_secs()
                     );
+                    error_log.set_outcome(Outcome::Error);
                     let e: ResponseFuture = Box::new(futures::future::ok({
                         let mut res = Response::new(
                             format!("Requested uri: {:?}\n{:?}", req_uri_string, o).into(),
@@ -227,25 +340,62 @@ fn put_request(
     downloader: &Downloader,
     config: &AppConfig,
     s3_config: &S3Config,
+    request_log: RequestLog,
 ) -> ResponseFuture {
     let proxy_request = ProxyRequest::new(req.uri());
     let file_name = proxy_request.file_name();
+    request_log.set_request(&proxy_request);
 
     info!("Put request: {:?}", req.uri().path());
 
+    let credential = bearer_credential(req.headers());
+    if !config
+        .write_policy
+        .allows(credential.as_ref().map(|e| e.as_str()), &proxy_request.tpe)
+    {
+        warn!(
+            "Rejecting put to {:?}, credential not permitted to write {}",
+            req.uri().path(),
+            proxy_request.tpe
+        );
+        request_log.set_outcome(Outcome::Rejected);
+        return Box::new(empty_with_status_code_fut(StatusCode::FORBIDDEN));
+    }
+
     let path = req.uri().path().to_string().clone();
 
-    let upload_path = Path::new(&config.cache_folder).join(&file_name);
+    // Nowhere to keep the upload, so it only goes to S3
+    if !disk_health::caching() {
+        request_log.set_outcome(Outcome::PassedThrough);
+        let upstream_path = to_upstream_path(&proxy_request, s3_config);
+        let bucket = s3_config.bucket.clone();
+        return Box::new(
+            req.into_body()
+                .concat2()
+                .map_err(From::from)
+                .and_then(move |contents| {
+                    put_object(&s3_client, &bucket, &upstream_path, contents.to_vec())?;
+                    Ok(empty_with_status_code(StatusCode::CREATED))
+                }),
+        );
+    }
+
+    let upload_path = config.cache_path(&proxy_request.repo, &file_name);
 
     let processor_config = config.clone();
     let s3_cfg = s3_config.clone();
     Box::new(
         downloader
-            .save_file(&file_name, req)
+            .save_file(&proxy_request.repo, &file_name, req)
             .map(move |_file| {
+                request_log.set_outcome(if _file.is_some() {
+                    Outcome::Stored
+                } else {
+                    Outcome::AlreadyPresent
+                });
                 match _file {
                     Some(_f) => {
-                        process_action_cache_response(&processor_config, &_f)
+                        process_action_cache_response(&processor_config, &proxy_request.repo, &_f)
                             .map_err(|e| {
                                 warn!(
                                     "[Put]Failed to process action cache with: {:?} for {:?}",
@@ -282,10 +432,7 @@ pub fn start_server(
     s3_config: &S3Config,
     raw_s3_client: S3Client,
 ) -> Result<(), io::Error> {
-    let s = Arc::new(Mutex::new(State {
-        last_user_facing_request: Instant::now(),
-        last_background_upload: Instant::now(),
-    }));
+    let s = Arc::new(Mutex::new(State::new()));
 
     process_existing_action_caches(config.clone());
 
@@ -293,6 +440,25 @@ pub fn start_server(
     let s3_cfg = s3_config.clone();
     let s3_client = Arc::new(raw_s3_client);
     let downloader = Downloader::new(&cfg).unwrap();
+    start_scrubber(&cfg, &downloader);
+    let denylist = Denylist::new(&cfg);
+    let denylist_refresh = if config.denylist_file.is_some() {
+        Some(start_denylist_refresh(
+            &denylist,
+            &Client::new(),
+            config.denylist_refresh_interval,
+        ))
+    } else {
+        None
+    };
+
+    let shutdown_state = Arc::clone(&s);
+    let shutdown_downloader = downloader.clone();
+
+    let access_log = match config.access_log {
+        Some(ref path) => Some(AccessLog::open(path)?),
+        None => None,
+    };
 
     let new_service = move || {
         // Move a clone of `client` into the `service_fn`.
@@ -302,20 +468,34 @@ pub fn start_server(
         let inner_cfg = cfg.clone();
         let inner_s3_cfg = s3_cfg.clone();
         let inner_downloader = downloader.clone();
+        let access_log = access_log.clone();
+        let denylist = denylist.clone();
         service_fn(move |req| {
-            {
-                let mut locked = state.lock().unwrap();
-                locked.last_user_facing_request = Instant::now();
+            let is_admin_request = req.uri().path().starts_with("/admin/");
+            let is_health_request = req.uri().path() == "/health";
+            let is_denylist_request = req.uri().path() == "/denylist";
+            if !is_admin_request && !is_health_request && !is_denylist_request {
+                state.lock().unwrap().last_user_facing_request = Instant::now();
             }
+            let in_flight = InFlight::new(&state);
+            let request_log = RequestLog::new(&req);
+            let finished_log = request_log.clone();
+            let access_log = access_log.clone();
             info!("{:?}", req);
             Box::new(
                 match req.method() {
+                    _ if is_health_request => health_request(&state.lock().unwrap()),
+                    &Method::GET if is_denylist_request => denylist_request(&denylist),
+                    _ if is_admin_request => admin_request(req, &inner_cfg, &inner_downloader),
                     &Method::GET => get_request(
                         Instant::now(),
                         req,
                         Arc::clone(&inner_s3_client),
+                        &inner_downloader,
                         &inner_cfg.clone(),
                         &inner_s3_cfg.clone(),
+                        &denylist,
+                        request_log,
                     ),
                     &Method::PUT => put_request(
                         Instant::now(),
@@ -324,6 +504,7 @@ pub fn start_server(
                         &inner_downloader,
                         &inner_cfg.clone(),
                         &inner_s3_cfg.clone(),
+                        request_log,
                     ),
                     _ => {
                         info!(
@@ -338,24 +519,52 @@ pub fn start_server(
                 }.map_err(|e| {
                     error!("Ran into error: {}", e.description());
                     e
-                }),
+                })
+                    .map(into_response_body)
+                    .then(move |res| {
+                        log_response(&access_log, finished_log, in_flight, res)
+                    }),
             )
         })
     };
 
-    let server_engine = {
-        info!(
-            "Going to bind/start server on http path for: 0.0.0.0:{}",
-            config.bind_target.port().unwrap()
-        );
-        start_http_server_impl(&config.bind_target, new_service).map_err(|e| {
+    let server_engine = match activated_listener()? {
+        Some(listener) => start_activated_server_impl(listener, new_service).map_err(|e| {
             io::Error::new(
                 io::ErrorKind::Other,
-                format!("Error configuring unix server: {:?}", e),
+                format!("Error configuring socket activated server: {:?}", e),
             )
-        })?
+        })?,
+        None => {
+            info!(
+                "Going to bind/start server on http path for: 0.0.0.0:{}",
+                config.bind_target.port().unwrap()
+            );
+            start_http_server_impl(&config.bind_target, new_service).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("Error configuring unix server: {:?}", e),
+                )
+            })?
+        }
     };
 
-    hyper::rt::run(server_engine.map(|_| ()).map_err(|_| ()));
+    // Uploads to S3 happen inline with requests, so there's no upload queue to drain here
+    let grace_period = config.shutdown_grace_period;
+    hyper::rt::run(futures::lazy(move || {
+        if let Some(refresh) = denylist_refresh {
+            hyper::rt::spawn(refresh);
+        }
+        server_engine
+            .select(shutdown_signal())
+            .then(move |_| {
+                graceful_shutdown(
+                    shutdown_state,
+                    shutdown_downloader,
+                    grace_period,
+                    Box::new(futures::future::ok(())),
+                )
+            })
+    }));
     return Ok(());
 }
@@ -0,0 +1,80 @@
+use config::{is_safe_folder_name, AppConfig};
+use net::disk_health::disk_error;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+fn repo_entries_folder(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("repo_entries")
+}
+
+// An empty file per entry a repo stored, so it can be evicted without a partition of its own.
+// Kept outside the partitions so the LRU doesn't mistake them for entries.
+fn repo_folder(config: &AppConfig, repo: &str) -> PathBuf {
+    repo_entries_folder(config).join(repo)
+}
+
+pub fn record(config: &AppConfig, repo: &str, file_name: &str) {
+    // The repo comes from the request path, it mustn't take us outside the folder
+    if !is_safe_folder_name(repo) {
+        return;
+    }
+    let folder = repo_folder(config, repo);
+    fs::create_dir_all(&folder)
+        .and_then(|_| File::create(folder.join(file_name)))
+        .map(|_| ())
+        .map_err(disk_error)
+        .unwrap_or_else(|e| warn!("Failed to record {} as stored for {}: {}", file_name, repo, e));
+}
+
+// The entries a repo stored, some may have been evicted since
+pub fn entries(config: &AppConfig, repo: &str) -> Vec<String> {
+    if !is_safe_folder_name(repo) {
+        return Vec::new();
+    }
+    match fs::read_dir(repo_folder(config, repo)) {
+        Ok(paths) => paths
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+pub fn forget_repo(config: &AppConfig, repo: &str) {
+    if is_safe_folder_name(repo) {
+        fs::remove_dir_all(repo_folder(config, repo)).unwrap_or(());
+    }
+}
+
+// Records of entries that have since been evicted.
+pub fn orphaned(config: &AppConfig) -> Vec<PathBuf> {
+    let mut orphaned = Vec::new();
+    let repos = match fs::read_dir(repo_entries_folder(config)) {
+        Ok(e) => e,
+        Err(_) => return orphaned,
+    };
+    for repo in repos.filter_map(|e| e.ok()) {
+        let entries = match fs::read_dir(repo.path()) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if config.find_cached(&file_name).is_none() {
+                orphaned.push(entry.path());
+            }
+        }
+    }
+    orphaned
+}
+
+// Drop records of evicted entries, and the folders of repos with none left
+pub fn trim(config: &AppConfig) {
+    for path in orphaned(config) {
+        fs::remove_file(&path).unwrap_or(());
+        if let Some(folder) = path.parent() {
+            // Only succeeds once it's empty
+            fs::remove_dir(folder).unwrap_or(());
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use config::AppConfig;
+use net::digest::verify_file;
+use net::downloader::Downloader;
+use net::process_action_cache::parse_action_result;
+use net::proxy_request::ProxyRequest;
+use std::fs::{self, File, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Wait at least this long between starting passes, so a small cache isn't re-read constantly
+const PASS_INTERVAL_SECS: u64 = 60;
+
+// How many of the latest quarantined entries we report, and keep on disk
+const RECENT_QUARANTINED: usize = 20;
+
+/// What the scrubber has checked since we started.
+#[derive(Debug, Clone, Default)]
+pub struct ScrubStats {
+    pub passes: u64,
+    pub entries: u64,
+    pub bytes: u64,
+    pub corrupt: u64,
+    pub last_pass: Option<SystemTime>,
+    // Where the latest corrupt entries went, and what was wrong with them
+    pub quarantined: Vec<(PathBuf, String)>,
+}
+
+lazy_static! {
+    static ref STATS: Mutex<ScrubStats> = Mutex::new(ScrubStats::default());
+}
+
+pub fn stats() -> ScrubStats {
+    STATS.lock().unwrap().clone()
+}
+
+pub fn quarantine_folder(config: &AppConfig) -> PathBuf {
+    Path::new(&config.cache_folder).join("quarantine")
+}
+
+// Delete all but the latest quarantined entries, so a failing disk can't fill the cache
+// folder with them. Moving an entry into quarantine updates its ctime, which orders them.
+fn prune_quarantine(config: &AppConfig) {
+    let mut quarantined: Vec<(i64, i64, PathBuf)> = Vec::new();
+    let partitions = match fs::read_dir(quarantine_folder(config)) {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+    for partition in partitions.filter_map(|e| e.ok()) {
+        let entries = match fs::read_dir(partition.path()) {
+            Ok(e) => e,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            if let Ok(metadata) = entry.metadata() {
+                quarantined.push((metadata.ctime(), metadata.ctime_nsec(), entry.path()));
+            }
+        }
+    }
+    quarantined.sort();
+    let excess = quarantined.len().saturating_sub(RECENT_QUARANTINED);
+    for &(_, _, ref path) in quarantined[..excess].iter() {
+        match fs::remove_file(path) {
+            Ok(()) => debug!("Deleted old quarantined entry {:?}", path),
+            Err(e) => warn!("Failed to delete old quarantined entry {:?}: {}", path, e),
+        }
+    }
+}
+
+// Replacing an entry renames a new file over it, so this tells us if it changed under us
+fn identity(metadata: &Metadata) -> (u64, u64, i64) {
+    (metadata.dev(), metadata.ino(), metadata.mtime())
+}
+
+// What's wrong with an entry, None if it's fine or we couldn't read it
+fn check(request: &ProxyRequest, path: &Path) -> Option<String> {
+    if request.tpe == "cas" {
+        match verify_file(path, &request.digest) {
+            Ok(true) | Err(_) => None,
+            Ok(false) => Some("content doesn't match its digest".to_string()),
+        }
+    } else {
+        // Only what we can read but not decode is corrupt
+        match File::open(path) {
+            Ok(file) => parse_action_result(file).err(),
+            Err(_) => None,
+        }
+    }
+}
+
+// Check one entry, quarantining it if it's corrupt. Returns the bytes read and whether it was.
+fn scrub_entry(
+    config: &AppConfig,
+    downloader: &Downloader,
+    partition: &str,
+    path: &Path,
+) -> (u64, bool) {
+    let file_name = match path.file_name() {
+        Some(e) => e.to_string_lossy().to_string(),
+        None => return (0, false),
+    };
+    let request = match ProxyRequest::from_file_name(partition, &file_name) {
+        Some(e) => e,
+        None => return (0, false),
+    };
+    // Gone since we listed it, e.g. evicted
+    let metadata = match fs::metadata(path) {
+        Ok(e) => e,
+        Err(_) => return (0, false),
+    };
+    let size = metadata.len();
+    let problem = check(&request, path);
+    {
+        let mut stats = STATS.lock().unwrap();
+        stats.entries += 1;
+        stats.bytes += size;
+    }
+    let problem = match problem {
+        Some(e) => e,
+        None => return (size, false),
+    };
+
+    let folder = quarantine_folder(config).join(partition);
+    let dest = folder.join(&file_name);
+    // Held while we check it's still the entry we read, so it can't be replaced meanwhile
+    let mut lru_cache = downloader.lru_cache.lock().unwrap();
+    if fs::metadata(path).ok().map(|e| identity(&e)) != Some(identity(&metadata)) {
+        debug!("{:?} changed while we checked it, leaving it for the next pass", path);
+        return (size, false);
+    }
+    let quarantined = fs::create_dir_all(&folder)
+        .map_err(|e| e.to_string())
+        .and_then(|_| lru_cache.quarantine(partition, &file_name, &dest));
+    drop(lru_cache);
+    match quarantined {
+        Ok(()) => {
+            warn!(
+                "Quarantined corrupt cache entry {:?} to {:?}: {}",
+                path, dest, problem
+            );
+            let mut stats = STATS.lock().unwrap();
+            stats.corrupt += 1;
+            stats.quarantined.push((dest, problem));
+            let excess = stats.quarantined.len().saturating_sub(RECENT_QUARANTINED);
+            stats.quarantined.drain(0..excess);
+            drop(stats);
+            prune_quarantine(config);
+        }
+        Err(e) => error!("Failed to quarantine corrupt cache entry {:?}: {}", path, e),
+    }
+    (size, true)
+}
+
+/// Re-hash CAS entries and decode AC entries in the background, at about `--scrub-rate` bytes
+/// a second, quarantining and evicting any that are corrupt.
+pub fn start_scrubber(config: &AppConfig, downloader: &Downloader) {
+    if config.scrub_rate == 0 {
+        return;
+    }
+    let config = config.clone();
+    let downloader = downloader.clone();
+    info!(
+        "Checking cache entries for corruption at {} bytes a second",
+        config.scrub_rate
+    );
+
+    thread::spawn(move || loop {
+        prune_quarantine(&config);
+        let started = Instant::now();
+        let mut corrupt = 0;
+        let entries = config.cached_entries();
+        for &(ref partition, ref path) in entries.iter() {
+            let (size, was_corrupt) = scrub_entry(&config, &downloader, partition, path);
+            if was_corrupt {
+                corrupt += 1;
+            }
+            thread::sleep(Duration::from_millis(size * 1000 / config.scrub_rate));
+        }
+        {
+            let mut stats = STATS.lock().unwrap();
+            stats.passes += 1;
+            stats.last_pass = Some(SystemTime::now());
+        }
+        info!(
+            "Scrubbed {} cache entries in {} seconds, {} corrupt",
+            entries.len(),
+            started.elapsed().as_secs(),
+            corrupt
+        );
+        let pass_interval = Duration::from_secs(PASS_INTERVAL_SECS);
+        let elapsed = started.elapsed();
+        if elapsed < pass_interval {
+            thread::sleep(pass_interval - elapsed);
+        }
+    });
+}
@@ -1,13 +1,20 @@
 use futures::{future, Future};
 use http::header;
 use http::header::HeaderValue;
+use http::HeaderMap;
 use http::Response;
 use http::StatusCode;
 use hyper::Body;
 use net::buffered_send_stream;
+use net::denylist::Denylist;
+use net::disk_health;
+use net::mapped_file::{MappedBody, MappedFile, ResponseBody, MIN_MAPPED_SIZE};
 use net::server_error::ServerError;
-use std::fs;
+use net::State;
+use std::fs::File;
+use std::io;
 use std::io::ErrorKind as IoErrorKind;
+use std::path::Path;
 
 // Generate a response future for a given status code
 pub fn empty_with_status_code_fut(
@@ -24,23 +31,88 @@ pub fn empty_with_status_code(status_code: StatusCode) -> Response<Body> {
     res
 }
 
+// Extract the token from an `Authorization: Bearer <token>` header, if present
+pub fn bearer_credential(headers: &HeaderMap<HeaderValue>) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|e| e.to_str().ok())
+        .and_then(|e| {
+            if e.starts_with("Bearer ") {
+                Some(e["Bearer ".len()..].trim().to_string())
+            } else {
+                None
+            }
+        })
+}
+
+// Compare secrets in time independent of where they first differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 type ResponseFuture = Box<Future<Item = Response<Body>, Error = ServerError> + Send>;
 
+// Healthy until we start shutting down, so `ensure` won't hand out a proxy that's going away.
+// Still healthy without a working cache disk, as we pass requests through, but we say so.
+pub fn health_request(state: &State) -> ResponseFuture {
+    if state.shutdown_deadline.is_some() {
+        return Box::new(empty_with_status_code_fut(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    let body = match disk_health::degraded() {
+        Some(degraded) => format!("degraded: {}", degraded.reason),
+        None => "ok".to_string(),
+    };
+    Box::new(future::ok(Response::new(Body::from(body))))
+}
+
+// What we deny, one digest per line, for client proxies to refresh from
+pub fn denylist_request(denylist: &Denylist) -> ResponseFuture {
+    let mut body = denylist.digests().join("\n");
+    body.push('\n');
+    Box::new(future::ok(Response::new(Body::from(body))))
+}
+
+// Open a cache entry to send it, None if it's gone, e.g. evicted since we found it. Eviction
+// only unlinks entries, so once we have it open we can send all of it.
+pub fn open_cached(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn open_error(path: &Path, e: io::Error) -> ResponseFuture {
+    match e.kind() {
+        IoErrorKind::PermissionDenied => {
+            Box::new(empty_with_status_code_fut(StatusCode::FORBIDDEN).map_err(From::from))
+        }
+        _ => {
+            warn!("Failed to open {:?}: {}", path, e);
+            Box::new(future::err(e).map_err(From::from))
+        }
+    }
+}
+
 pub fn send_file(path: String) -> ResponseFuture {
-    let metadata = match fs::metadata(&path) {
-        Ok(meta) => meta,
-        Err(e) => {
-            return match e.kind() {
-                IoErrorKind::NotFound => panic!(
-                    "Should never reach here, file not found looking for {:?}",
-                    path
-                ),
-                IoErrorKind::PermissionDenied => {
-                    Box::new(empty_with_status_code_fut(StatusCode::FORBIDDEN).map_err(From::from))
-                }
-                _ => Box::new(future::err(e).map_err(From::from)),
-            };
+    match open_cached(Path::new(&path)) {
+        Ok(Some(file)) => send_open_file(path, file),
+        Ok(None) => {
+            debug!("{:?} went missing before we could send it", path);
+            Box::new(empty_with_status_code_fut(StatusCode::NOT_FOUND))
         }
+        Err(e) => open_error(Path::new(&path), e),
+    }
+}
+
+// Send a cache entry from a handle opened by `open_cached`
+pub fn send_open_file(path: String, file: File) -> ResponseFuture {
+    let metadata = match file.metadata() {
+        Ok(meta) => meta,
+        Err(e) => return open_error(Path::new(&path), e),
     };
     // Build response headers.
     let size: String = metadata.len().to_string();
@@ -58,9 +130,26 @@ pub fn send_file(path: String) -> ResponseFuture {
 
     res.header(header::CONTENT_LENGTH, header_size);
 
-    let body = match buffered_send_stream::send_file(&path) {
-        Ok(body) => body,
-        Err(err) => return Box::new(future::err(err)),
-    };
+    // Large blobs are sent straight from a mapping of the file, otherwise we stream them
+    if metadata.len() >= MIN_MAPPED_SIZE {
+        match MappedFile::from_file(&file) {
+            Ok(mapped) => {
+                res.extension(mapped);
+                return Box::new(future::result(res.body(Body::empty())).map_err(From::from));
+            }
+            Err(e) => debug!("Unable to map {}, streaming it instead: {}", path, e),
+        }
+    }
+
+    let body = buffered_send_stream::send_open_file(&path, file);
     Box::new(future::result(res.body(body)).map_err(From::from))
 }
+
+// Handlers deal in plain hyper bodies, so a file to send from its mapping rides along in the
+// response's extensions until we're about to hand it to hyper.
+pub fn into_response_body(mut res: Response<Body>) -> Response<ResponseBody> {
+    match res.extensions_mut().remove::<MappedFile>() {
+        Some(file) => res.map(|_| ResponseBody::Mapped(MappedBody::new(file))),
+        None => res.map(ResponseBody::Streamed),
+    }
+}
@@ -3,14 +3,34 @@ use net::server_error::ServerError;
 use hyper;
 use hyper::body::Payload;
 
-use futures::Future;
+use futures::{Future, Stream};
 use hyper::service::NewService;
 use hyper::Body;
 use hyper::Server;
+use libc;
+use tokio::net::TcpListener as TokioTcpListener;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_reactor::Handle;
+use unix_socket::unix_listener::UnixListener;
 
+use std::env;
 use std::io;
+use std::mem;
 use std::net::IpAddr;
+use std::net::TcpListener;
 use std::net::ToSocketAddrs;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::process;
+
+// First fd handed to us by systemd style socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// An already bound socket we were started with, rather than one we bind ourselves.
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(StdUnixListener),
+}
 
 fn resolve(host: &str) -> io::Result<Vec<IpAddr>> {
     (host, 0).to_socket_addrs().map(|iter| {
@@ -20,25 +40,118 @@ fn resolve(host: &str) -> io::Result<Vec<IpAddr>> {
     })
 }
 
-pub fn start_unix_server_impl<S, Bd>(
-    _bind_target: &hyper::Uri,
-    _s: S,
+// The listening socket passed via `LISTEN_FDS`/`LISTEN_PID`, if we were socket activated.
+pub fn activated_listener() -> io::Result<Option<ActivatedListener>> {
+    let for_us = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|e| e.parse::<u32>().ok()) == Some(process::id());
+    let fd_count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|e| e.parse::<i32>().ok())
+        .unwrap_or(0);
+    if !for_us || fd_count < 1 {
+        return Ok(None);
+    }
+
+    // These shouldn't leak into anything we spawn
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if fd_count > 1 {
+        warn!(
+            "Socket activated with {} sockets, only serving on the first",
+            fd_count
+        );
+    }
+
+    let fd = SD_LISTEN_FDS_START;
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockname(
+            fd,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match addr.ss_family as libc::c_int {
+        libc::AF_UNIX => Ok(Some(ActivatedListener::Unix(unsafe {
+            StdUnixListener::from_raw_fd(fd)
+        }))),
+        libc::AF_INET | libc::AF_INET6 => Ok(Some(ActivatedListener::Tcp(unsafe {
+            TcpListener::from_raw_fd(fd)
+        }))),
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Socket activated with unsupported address family {}", family),
+        )),
+    }
+}
+
+fn serve<I, S, Bd>(incoming: I, s: S) -> Box<Future<Item = (), Error = ()> + Send>
+where
+    I: Stream + Send + 'static,
+    I::Item: AsyncRead + AsyncWrite + Send + 'static,
+    I::Error: Into<Box<::std::error::Error + Send + Sync>>,
+    S: NewService<ReqBody = Body, ResBody = Bd> + Send + 'static,
+    S::Error: Into<Box<::std::error::Error + Send + Sync>>,
+    S::Service: Send,
+    <S as ::hyper::service::NewService>::Future: Send,
+    <S::Service as ::hyper::service::Service>::Future: Send + 'static,
+    Bd: Payload,
+{
+    Box::new(
+        Server::builder(incoming)
+            .serve(s)
+            .map_err(|e| eprintln!("server error: {}", e)),
+    )
+}
+
+pub fn start_activated_server_impl<S, Bd>(
+    listener: ActivatedListener,
+    s: S,
 ) -> Result<Box<Future<Item = (), Error = ()> + Send>, ServerError>
 where
-    S: Sync,
     S: NewService<ReqBody = Body, ResBody = Bd> + Send + 'static,
     S::Error: Into<Box<::std::error::Error + Send + Sync>>,
     S::Service: Send,
+    <S as ::hyper::service::NewService>::Future: Send,
+    <S::Service as ::hyper::service::Service>::Future: Send + 'static,
+    Bd: Payload,
+{
+    match listener {
+        ActivatedListener::Tcp(listener) => {
+            info!("Serving on socket activated {:?}", listener.local_addr()?);
+            let listener = TokioTcpListener::from_std(listener, &Handle::default())?;
+            Ok(serve(listener.incoming(), s))
+        }
+        ActivatedListener::Unix(listener) => {
+            info!("Serving on socket activated {:?}", listener.local_addr()?);
+            let listener = UnixListener::from_std(listener)?;
+            Ok(serve(listener.incoming(), s))
+        }
+    }
+}
+
+pub fn start_unix_server_impl<S, Bd>(
+    bind_target: &hyper::Uri,
+    s: S,
+) -> Result<Box<Future<Item = (), Error = ()> + Send>, ServerError>
+where
+    S: NewService<ReqBody = Body, ResBody = Bd> + Send + 'static,
     S::Error: Into<Box<::std::error::Error + Send + Sync>>,
+    S::Service: Send,
     <S as ::hyper::service::NewService>::Future: Send,
     <S::Service as ::hyper::service::Service>::Future: Send + 'static,
     Bd: Payload,
 {
-    unimplemented!()
-    // let bind_path = bind_target.authority().unwrap();
-    // let svr = hyperlocal::server::Http::new().bind(bind_path, s)?;
-    // svr.run()?;
-    // Ok(())
+    let bind_path = bind_target.authority().unwrap();
+    let listener = UnixListener::bind(bind_path)?;
+    Ok(serve(listener.incoming(), s))
 }
 
 pub fn start_http_server_impl<S, Bd>(
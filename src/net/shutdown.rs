@@ -0,0 +1,102 @@
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use libc;
+use net::downloader::Downloader;
+use net::State;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+static SHUTDOWN_REQUESTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    // A second signal means the user is done waiting on us
+    if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(1) };
+    }
+}
+
+fn poll_every<F>(interval: Duration, done: F) -> Box<Future<Item = (), Error = ()> + Send>
+where
+    F: Fn() -> bool + Send + 'static,
+{
+    Box::new(future::loop_fn((), move |_| {
+        if done() {
+            Either::A(future::ok(Loop::Break(())))
+        } else {
+            Either::B(
+                Delay::new(Instant::now() + interval)
+                    .map(|_| Loop::Continue(()))
+                    .map_err(|e| warn!("Shutdown timer failed: {:?}", e)),
+            )
+        }
+    }))
+}
+
+// Resolves once we get a SIGTERM or SIGINT.
+pub(super) fn shutdown_signal() -> Box<Future<Item = (), Error = ()> + Send> {
+    unsafe {
+        libc::signal(libc::SIGTERM, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGINT, on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    Box::new(
+        poll_every(Duration::from_millis(250), || {
+            SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+        }).map(|_| info!("Received shutdown signal")),
+    )
+}
+
+// Run once we've stopped accepting connections. Waits for in-flight requests to finish and
+// the uploader to drain its queue, the uploader persists whatever is left at the deadline.
+// The cache is checkpointed last, so the next start has no journal to check.
+pub(super) fn graceful_shutdown(
+    state: Arc<Mutex<State>>,
+    downloader: Downloader,
+    grace_period: Duration,
+    uploads_done: Box<Future<Item = (), Error = ()> + Send>,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+    let deadline = Instant::now() + grace_period;
+    {
+        let mut locked = state.lock().unwrap();
+        locked.shutdown_deadline = Some(deadline);
+        locked.notify_uploader();
+    }
+    let upload_state = Arc::clone(&state);
+    info!(
+        "Shutting down, waiting up to {} seconds for requests and uploads",
+        grace_period.as_secs()
+    );
+
+    let request_state = Arc::clone(&state);
+    let requests_done = poll_every(Duration::from_millis(100), move || {
+        request_state.lock().unwrap().in_flight_requests == 0 || Instant::now() >= deadline
+    });
+
+    Box::new(
+        requests_done
+            .and_then(move |_| {
+                {
+                    let mut locked = upload_state.lock().unwrap();
+                    locked.drain_uploads = true;
+                    locked.notify_uploader();
+                }
+                uploads_done
+                    .select2(Delay::new(deadline))
+                    .then(move |res| match res {
+                        Ok(Either::B((_, uploads_done))) => {
+                            // Out of time, have the uploader persist what's left
+                            state.lock().unwrap().notify_uploader();
+                            Either::A(uploads_done.then(|_| Ok(())))
+                        }
+                        _ => Either::B(future::ok(())),
+                    })
+            })
+            .map(move |_| {
+                downloader.checkpoint();
+                info!("Shutdown complete");
+                process::exit(0);
+            }),
+    )
+}
@@ -1,6 +1,55 @@
+use futures::task::Task;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 pub struct State {
     pub last_user_facing_request: Instant,
     pub last_background_upload: Instant,
+    // Requests we're still serving, shutdown waits on these
+    pub in_flight_requests: usize,
+    // Set once we start shutting down, everything must be wrapped up by then
+    pub shutdown_deadline: Option<Instant>,
+    // Set once requests have finished, so no more uploads will be queued
+    pub drain_uploads: bool,
+    // So the background uploader can be woken to drain its queue on shutdown
+    pub uploader_task: Option<Task>,
+}
+
+impl State {
+    pub fn new() -> State {
+        State {
+            last_user_facing_request: Instant::now(),
+            last_background_upload: Instant::now(),
+            in_flight_requests: 0,
+            shutdown_deadline: None,
+            drain_uploads: false,
+            uploader_task: None,
+        }
+    }
+
+    pub fn notify_uploader(&self) {
+        if let Some(ref task) = self.uploader_task {
+            task.notify();
+        }
+    }
+}
+
+// Counts a request as in flight until dropped, for a response that's once its body is done with
+pub struct InFlight {
+    state: Arc<Mutex<State>>,
+}
+
+impl InFlight {
+    pub fn new(state: &Arc<Mutex<State>>) -> InFlight {
+        state.lock().unwrap().in_flight_requests += 1;
+        InFlight {
+            state: Arc::clone(state),
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().in_flight_requests -= 1;
+    }
 }
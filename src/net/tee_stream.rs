@@ -0,0 +1,100 @@
+use bytes::Bytes;
+use futures::{Async, Poll, Stream};
+use hyper::body::Sender;
+use hyper::Chunk;
+
+/// Passes a download's chunks on to whoever asked for it, as it's written to the cache.
+/// Anything short of the whole download aborts their response, so they never see partial data.
+pub struct TeeStream<S> {
+    inner: S,
+    sender: Option<Sender>,
+    pending: Option<Bytes>,
+    expected_len: u64,
+    received: u64,
+    complete: bool,
+}
+
+impl<S> TeeStream<S> {
+    pub fn new(inner: S, sender: Sender, expected_len: u64) -> TeeStream<S> {
+        TeeStream {
+            inner: inner,
+            sender: Some(sender),
+            pending: None,
+            expected_len: expected_len,
+            received: 0,
+            complete: false,
+        }
+    }
+
+    // The requester going away shouldn't stop us caching the download
+    fn poll_send(&mut self) -> Async<()> {
+        let bytes = match self.pending {
+            Some(ref e) => e.clone(),
+            None => return Async::Ready(()),
+        };
+        let gone = match self.sender {
+            Some(ref mut sender) => match sender.poll_ready() {
+                Ok(Async::NotReady) => return Async::NotReady,
+                Ok(Async::Ready(_)) => sender.send_data(Chunk::from(bytes)).is_err(),
+                Err(_) => true,
+            },
+            None => false,
+        };
+        if gone {
+            debug!("Requester went away, still caching the download");
+            self.sender = None;
+        }
+        Async::Ready(())
+    }
+}
+
+impl<S> Stream for TeeStream<S>
+where
+    S: Stream<Item = Chunk>,
+    S::Error: ToString,
+{
+    type Item = Bytes;
+    type Error = String;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, String> {
+        if self.pending.is_some() {
+            if let Async::NotReady = self.poll_send() {
+                return Ok(Async::NotReady);
+            }
+            return Ok(Async::Ready(self.pending.take()));
+        }
+        match try_ready!(self.inner.poll().map_err(|e| e.to_string())) {
+            Some(chunk) => {
+                self.received += chunk.len() as u64;
+                if self.received > self.expected_len {
+                    return Err(format!(
+                        "Got more than the {} bytes we were promised",
+                        self.expected_len
+                    ));
+                }
+                self.pending = Some(chunk.into_bytes());
+                self.poll()
+            }
+            None if self.received < self.expected_len => Err(format!(
+                "Download ended after {} of {} bytes",
+                self.received, self.expected_len
+            )),
+            None => {
+                // Ends the requester's response, it doesn't need to wait for us to cache it
+                self.complete = true;
+                self.sender = None;
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+impl<S> Drop for TeeStream<S> {
+    fn drop(&mut self) {
+        if !self.complete {
+            if let Some(sender) = self.sender.take() {
+                sender.abort();
+            }
+        }
+    }
+}
@@ -2,7 +2,6 @@ use config::AppConfig;
 use futures::Future;
 use futures::Poll;
 use net::State;
-use std::process;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::time::Duration;
@@ -75,6 +74,7 @@ impl Future for Terminator {
             // the future behavior
             return self.poll();
         }
-        process::exit(0);
+        info!("Idle for {:?}, shutting down", idle_duration);
+        Ok(Async::Ready(()))
     }
 }
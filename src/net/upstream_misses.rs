@@ -0,0 +1,107 @@
+use config::AppConfig;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Misses {
+    // file name -> repo -> when the upstream told us it didn't have it
+    by_file_name: HashMap<String, HashMap<String, Instant>>,
+    // Oldest first, so we can stay within our capacity
+    order: VecDeque<(String, String, Instant)>,
+}
+
+/// Recent upstream 404s, so asking again for a blob the upstream doesn't have is answered at once.
+#[derive(Clone)]
+pub struct UpstreamMisses {
+    ttl: Duration,
+    capacity: usize,
+    misses: Arc<Mutex<Misses>>,
+}
+
+impl UpstreamMisses {
+    pub fn new(config: &AppConfig) -> UpstreamMisses {
+        UpstreamMisses {
+            ttl: config.negative_cache_ttl,
+            capacity: config.negative_cache_size,
+            misses: Arc::new(Mutex::new(Misses {
+                by_file_name: HashMap::new(),
+                order: VecDeque::new(),
+            })),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.capacity > 0 && self.ttl > Duration::from_millis(0)
+    }
+
+    // Repos can be routed to different upstreams, so a miss only counts for the repo that saw it
+    pub fn contains(&self, repo: &str, file_name: &str) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+        let mut misses = self.misses.lock().unwrap();
+        let seen = misses
+            .by_file_name
+            .get(file_name)
+            .and_then(|repos| repos.get(repo))
+            .cloned();
+        match seen {
+            Some(seen) if seen.elapsed() < self.ttl => true,
+            Some(_) => {
+                remove(&mut misses, file_name, repo);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn insert(&self, repo: &str, file_name: &str) {
+        if !self.enabled() {
+            return;
+        }
+        let now = Instant::now();
+        let mut misses = self.misses.lock().unwrap();
+        misses
+            .by_file_name
+            .entry(file_name.to_string())
+            .or_insert_with(HashMap::new)
+            .insert(repo.to_string(), now);
+        misses
+            .order
+            .push_back((file_name.to_string(), repo.to_string(), now));
+
+        while misses.order.len() > self.capacity {
+            let (file_name, repo, seen) = misses.order.pop_front().unwrap();
+            // Only if it hasn't been seen again since
+            let current = misses
+                .by_file_name
+                .get(&file_name)
+                .and_then(|repos| repos.get(&repo))
+                .cloned();
+            if current == Some(seen) {
+                remove(&mut misses, &file_name, &repo);
+            }
+        }
+    }
+
+    // Once we have a blob ourselves, or have uploaded it, it's no longer missing for anyone
+    pub fn forget(&self, file_name: &str) {
+        if !self.enabled() {
+            return;
+        }
+        self.misses.lock().unwrap().by_file_name.remove(file_name);
+    }
+}
+
+fn remove(misses: &mut Misses, file_name: &str, repo: &str) {
+    let now_empty = match misses.by_file_name.get_mut(file_name) {
+        Some(repos) => {
+            repos.remove(repo);
+            repos.is_empty()
+        }
+        None => false,
+    };
+    if now_empty {
+        misses.by_file_name.remove(file_name);
+    }
+}
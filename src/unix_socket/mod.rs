@@ -1,4 +1,5 @@
 pub mod unix_connector;
+pub mod unix_listener;
 pub mod unix_stream;
 pub mod uri;
 pub use self::uri::Uri;
@@ -0,0 +1,100 @@
+use futures::{Async, Poll, Stream};
+use mio::Ready;
+use mio_uds;
+use tokio_reactor::PollEvented;
+use unix_socket::unix_stream::UnixStream;
+
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{self, SocketAddr};
+use std::path::Path;
+
+/// A Unix socket which can accept connections from other Unix sockets.
+pub struct UnixListener {
+    io: PollEvented<mio_uds::UnixListener>,
+}
+
+impl UnixListener {
+    /// Creates a new `UnixListener` bound to the specified path.
+    pub fn bind<P>(path: P) -> io::Result<UnixListener>
+    where
+        P: AsRef<Path>,
+    {
+        let listener = mio_uds::UnixListener::bind(path)?;
+        Ok(UnixListener {
+            io: PollEvented::new(listener),
+        })
+    }
+
+    /// Consumes a `UnixListener` in the standard library and returns a
+    /// nonblocking `UnixListener` from this crate.
+    ///
+    /// Used for sockets handed to us already bound, e.g. via socket activation.
+    pub fn from_std(listener: net::UnixListener) -> io::Result<UnixListener> {
+        let listener = mio_uds::UnixListener::from_listener(listener)?;
+        Ok(UnixListener {
+            io: PollEvented::new(listener),
+        })
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.get_ref().local_addr()
+    }
+
+    /// Attempt to accept a connection, registering interest in the listener
+    /// becoming readable if none is pending.
+    pub fn poll_accept(&self) -> Poll<(UnixStream, SocketAddr), io::Error> {
+        try_ready!(self.io.poll_read_ready(Ready::readable()));
+
+        match self.io.get_ref().accept_std() {
+            Ok(None) => {
+                self.io.clear_read_ready(Ready::readable())?;
+                Ok(Async::NotReady)
+            }
+            Ok(Some((sock, addr))) => {
+                let sock = mio_uds::UnixStream::from_stream(sock)?;
+                Ok(Async::Ready((UnixStream::new(sock), addr)))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                self.io.clear_read_ready(Ready::readable())?;
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Consumes this listener, returning a stream of the sockets this listener
+    /// accepts.
+    pub fn incoming(self) -> Incoming {
+        Incoming { inner: self }
+    }
+}
+
+impl fmt::Debug for UnixListener {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.io.get_ref().fmt(f)
+    }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.get_ref().as_raw_fd()
+    }
+}
+
+/// Stream of listeners
+#[derive(Debug)]
+pub struct Incoming {
+    inner: UnixListener,
+}
+
+impl Stream for Incoming {
+    type Item = UnixStream;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<UnixStream>, io::Error> {
+        Ok(Some(try_ready!(self.inner.poll_accept()).0).into())
+    }
+}